@@ -0,0 +1,41 @@
+//! Instructions per second through the memory bus, with the page table
+//! and with every access decoded the slow way. Run with `cargo bench`;
+//! results on record are in the README.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use gbemu::cpu::Cpu;
+use gbemu::hw::controller::MBC1;
+use gbemu::hw::memory::Memory;
+
+const INSTRUCTIONS: u64 = 100_000;
+
+/// A loop of WRAM reads and writes and ROM fetches:
+/// `ld hl, 0xC000; loop: ld a, (hl); add (hl); ld (hl), a; inc l; jr loop`
+fn bus_loop(pages_enabled: bool) -> Cpu {
+    let mut rom = vec![0u8; 0x100];
+    rom.extend_from_slice(&[0x21, 0x00, 0xC0, 0x7E, 0x86, 0x77, 0x2C, 0x18, 0xFA]);
+    rom.resize(0x8000, 0x00);
+    let mut memory = Memory::new(MBC1::new(rom));
+    memory.set_pages_enabled(pages_enabled);
+    Cpu::new(memory)
+}
+
+fn memory_bus(c: &mut Criterion) {
+    let mut group = c.benchmark_group("memory_bus");
+    group.throughput(Throughput::Elements(INSTRUCTIONS));
+    for &(name, pages_enabled) in &[("page_table", true), ("slow_path", false)] {
+        let mut cpu = bus_loop(pages_enabled);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for _ in 0..INSTRUCTIONS {
+                    cpu.execute_instr().unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, memory_bus);
+criterion_main!(benches);
@@ -0,0 +1,61 @@
+//! A RAM search scripted against the library, with no window or prompt.
+//! Runs a ROM and narrows down the bytes that go up by one every frame,
+//! like a frame counter would.
+//!
+//!     cargo run --example ram_search -- game.gb [frames]
+
+use std::env;
+use std::path::Path;
+use std::process;
+
+use gbemu::cpu::Cpu;
+use gbemu::hw::controller::{self, CgbSupport};
+use gbemu::hw::memory::Memory;
+use gbemu::ram_search::{Filter, RamSearch, ValueType};
+use gbemu::rom_file;
+
+fn run_frame(cpu: &mut Cpu) -> Result<(), String> {
+    loop {
+        let pc = cpu.regs.get_pc();
+        cpu.execute_instr().map_err(|_| format!("Unsupported instruction at {:04x}", pc))?;
+        cpu.memory.tick_lcd();
+        if cpu.memory.io.lcd.vblank_interrupt_should_trigger() {
+            return Ok(());
+        }
+    }
+}
+
+fn search(rom_path: &Path, frames: u32) -> Result<(), String> {
+    let rom = rom_file::load(rom_path, None).map_err(|e| e.to_string())?.data;
+    let (header, cartridge) = controller::load_cartridge(rom).map_err(|e| e.to_string())?;
+    let memory = match header.cgb {
+        CgbSupport::None => Memory::new(cartridge),
+        CgbSupport::Compatible | CgbSupport::Only => Memory::new_cgb(cartridge),
+    };
+    let mut cpu = Cpu::new(memory);
+
+    run_frame(&mut cpu)?;
+    let mut search = RamSearch::new(&cpu.memory, ValueType::U8);
+    for _ in 0..frames {
+        run_frame(&mut cpu)?;
+        search.filter(&cpu.memory, Filter::IncreasedBy(1));
+    }
+    for candidate in search.candidates() {
+        println!("{}", candidate);
+    }
+    println!("{} candidates", search.candidates().len());
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        println!("Usage: {} <rom> [frames]", args[0]);
+        process::exit(2);
+    }
+    let frames = args.get(2).and_then(|arg| arg.parse().ok()).unwrap_or(10);
+    if let Err(e) = search(Path::new(&args[1]), frames) {
+        println!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,89 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+use crate::hw::controller::camera::{ImageSensor, SENSOR_HEIGHT, SENSOR_WIDTH};
+
+/// Pictures for the Pocket Camera to see, from image files. Each capture
+/// takes the next picture, going back to the first after the last, so a
+/// single file is a still scene and several make a sequence.
+pub struct CameraImages {
+    frames: Vec<Vec<u8>>,
+    next: usize,
+}
+
+impl CameraImages {
+    /// Load every path in order. A directory stands for the images in it,
+    /// sorted by name.
+    pub fn load(paths: &[PathBuf]) -> Result<CameraImages, String> {
+        let mut files = Vec::new();
+        for path in paths {
+            if path.is_dir() {
+                let mut entries = fs::read_dir(path)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|entry| entry.is_file())
+                    .collect::<Vec<_>>();
+                entries.sort();
+                files.extend(entries);
+            } else {
+                files.push(path.clone());
+            }
+        }
+        let frames = files
+            .iter()
+            .map(|file| load_image(file))
+            .collect::<Result<Vec<_>, _>>()?;
+        CameraImages::from_frames(frames)
+    }
+
+    pub fn from_images(images: &[DynamicImage]) -> Result<CameraImages, String> {
+        CameraImages::from_frames(images.iter().map(sensor_frame).collect())
+    }
+
+    fn from_frames(frames: Vec<Vec<u8>>) -> Result<CameraImages, String> {
+        if frames.is_empty() {
+            return Err("No camera images given".to_string());
+        }
+        Ok(CameraImages { frames, next: 0 })
+    }
+}
+
+impl ImageSensor for CameraImages {
+    fn capture(&mut self) -> Vec<u8> {
+        let frame = self.frames[self.next].clone();
+        self.next = (self.next + 1) % self.frames.len();
+        frame
+    }
+}
+
+fn load_image(path: &Path) -> Result<Vec<u8>, String> {
+    let image = image::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(sensor_frame(&image))
+}
+
+/// Scale and crop to the sensor's size, keeping the aspect ratio, and drop
+/// the colour.
+fn sensor_frame(image: &DynamicImage) -> Vec<u8> {
+    image
+        .resize_to_fill(SENSOR_WIDTH as u32, SENSOR_HEIGHT as u32, FilterType::Triangle)
+        .to_luma8()
+        .into_raw()
+}
+
+#[test]
+fn camera_images_cycle() {
+    use image::{GrayImage, Luma};
+
+    let dark = DynamicImage::ImageLuma8(GrayImage::from_pixel(640, 480, Luma([0x10])));
+    let bright = DynamicImage::ImageLuma8(GrayImage::from_pixel(32, 32, Luma([0xF0])));
+    let mut images = CameraImages::from_images(&[dark, bright]).unwrap();
+    let first = images.capture();
+    assert_eq!(first.len(), SENSOR_WIDTH * SENSOR_HEIGHT);
+    assert!(first.iter().all(|&p| p == 0x10));
+    assert!(images.capture().iter().all(|&p| p == 0xF0));
+    assert_eq!(images.capture(), first);
+    assert!(CameraImages::from_images(&[]).is_err());
+}
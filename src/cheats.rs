@@ -0,0 +1,264 @@
+mod error;
+
+use std::fmt;
+
+use crate::hw::memory::BusWidth;
+
+pub use self::error::{CheatError, CheatResult};
+
+/// What a cheat code does.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CheatKind {
+    /// Replace the byte read from ROM at `addr` with `value`. With a
+    /// `compare` byte only when the ROM holds that, which is how codes
+    /// pick one bank out of the several that appear at a 4000-7FFF
+    /// address.
+    GameGenie {
+        addr: BusWidth,
+        value: u8,
+        compare: Option<u8>,
+    },
+    /// Write `value` to RAM at `addr` every frame. `bank` is the code's
+    /// first byte: 01 writes to whatever is switched in, 8x to cartridge
+    /// RAM bank x and 9x to WRAM bank x.
+    GameShark { bank: u8, value: u8, addr: BusWidth },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cheat {
+    pub code: String,
+    pub name: String,
+    pub kind: CheatKind,
+    pub enabled: bool,
+}
+
+fn hex_digits(code: &str) -> Option<Vec<u8>> {
+    code.chars()
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect()
+}
+
+impl Cheat {
+    /// Parse `ABC-DEF` or `ABC-DEF-GHI` as a Game Genie code, or
+    /// `TTVVLLHH` as a GameShark code. The cheat starts out enabled.
+    pub fn parse(code: &str, name: &str) -> CheatResult<Cheat> {
+        let bad_code = || CheatError::BadCode(code.to_string());
+        let kind = if code.contains('-') {
+            let parts: Vec<&str> = code.split('-').collect();
+            if parts.len() < 2 || parts.len() > 3 || parts.iter().any(|part| part.len() != 3) {
+                return Err(bad_code());
+            }
+            let d = hex_digits(&parts.concat()).ok_or_else(bad_code)?;
+            // The address is scrambled and its top nibble inverted; the
+            // compare byte is rotated and XORed, with H only a check digit
+            let addr = ((d[5] ^ 0xF) as BusWidth) << 12
+                | (d[2] as BusWidth) << 8
+                | (d[3] as BusWidth) << 4
+                | d[4] as BusWidth;
+            // The Game Genie sits on the ROM lines only
+            if addr > 0x7FFF {
+                return Err(bad_code());
+            }
+            let compare = if d.len() == 9 {
+                Some((d[6] << 4 | d[8]).rotate_right(2) ^ 0xBA)
+            } else {
+                None
+            };
+            CheatKind::GameGenie {
+                addr,
+                value: d[0] << 4 | d[1],
+                compare,
+            }
+        } else {
+            if code.len() != 8 {
+                return Err(bad_code());
+            }
+            let d = hex_digits(code).ok_or_else(bad_code)?;
+            let byte = |i: usize| d[i] << 4 | d[i + 1];
+            CheatKind::GameShark {
+                bank: byte(0),
+                value: byte(2),
+                addr: (byte(6) as BusWidth) << 8 | byte(4) as BusWidth,
+            }
+        };
+        Ok(Cheat {
+            code: code.to_uppercase(),
+            name: name.to_string(),
+            kind,
+            enabled: true,
+        })
+    }
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = if self.enabled { "on" } else { "off" };
+        write!(f, "{:<11} {:<3} {}", self.code, state, self.name)
+    }
+}
+
+/// The cheats in effect. Memory consults this for every ROM read it can't
+/// serve from the page table, and applies the RAM writes once a frame.
+#[derive(Default)]
+pub struct Cheats {
+    list: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new() -> Cheats {
+        Cheats { list: Vec::new() }
+    }
+
+    /// A cheat file has one code per line, optionally followed by a
+    /// description. Lines starting with `#` are comments, and a code
+    /// starting with `-` is loaded disabled.
+    pub fn parse(text: &str) -> CheatResult<Cheats> {
+        let mut cheats = Cheats::new();
+        for (num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.splitn(2, char::is_whitespace);
+            let code = fields.next().unwrap_or("");
+            let name = fields.next().unwrap_or("").trim();
+            let (code, enabled) = match code.strip_prefix('-') {
+                Some(code) => (code, false),
+                None => (code, true),
+            };
+            let mut cheat = Cheat::parse(code, name).map_err(|_| CheatError::BadLine {
+                line: num + 1,
+                code: code.to_string(),
+            })?;
+            cheat.enabled = enabled;
+            cheats.list.push(cheat);
+        }
+        Ok(cheats)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cheat> {
+        self.list.iter()
+    }
+
+    pub fn push(&mut self, cheat: Cheat) {
+        self.list.push(cheat);
+    }
+
+    /// Returns false if there's no cheat `index`.
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.list.get_mut(index) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn game_genie(&self) -> impl Iterator<Item = (BusWidth, u8, Option<u8>)> + '_ {
+        self.list
+            .iter()
+            .filter(|cheat| cheat.enabled)
+            .filter_map(|cheat| match cheat.kind {
+                CheatKind::GameGenie {
+                    addr,
+                    value,
+                    compare,
+                } => Some((addr, value, compare)),
+                _ => None,
+            })
+    }
+
+    /// Whether any enabled Game Genie code patches the 256-byte page
+    /// holding `addr`.
+    pub fn patches_page(&self, addr: BusWidth) -> bool {
+        self.game_genie()
+            .any(|(patch_addr, _, _)| patch_addr >> 8 == addr >> 8)
+    }
+
+    /// The byte a ROM read at `addr` returns, given what the cartridge
+    /// has there.
+    pub fn read_rom(&self, addr: BusWidth, data: u8) -> u8 {
+        self.game_genie()
+            .find(|&(patch_addr, _, compare)| {
+                patch_addr == addr && compare.is_none_or(|compare| compare == data)
+            })
+            .map_or(data, |(_, value, _)| value)
+    }
+
+    /// Enabled GameShark codes as (bank, value, address).
+    pub fn ram_writes(&self) -> impl Iterator<Item = (u8, u8, BusWidth)> + '_ {
+        self.list
+            .iter()
+            .filter(|cheat| cheat.enabled)
+            .filter_map(|cheat| match cheat.kind {
+                CheatKind::GameShark { bank, value, addr } => Some((bank, value, addr)),
+                _ => None,
+            })
+    }
+}
+
+#[test]
+fn parse_codes() {
+    // With a compare byte
+    let cheat = Cheat::parse("00A-17B-C49", "").unwrap();
+    assert_eq!(
+        cheat.kind,
+        CheatKind::GameGenie {
+            addr: 0x4A17,
+            value: 0x00,
+            compare: Some(0xC8),
+        }
+    );
+    assert_eq!(
+        Cheat::parse("3E1-23F", "").unwrap().kind,
+        CheatKind::GameGenie {
+            addr: 0x0123,
+            value: 0x3E,
+            compare: None,
+        }
+    );
+    assert_eq!(
+        Cheat::parse("3E1-237", "").err(),
+        Some(CheatError::BadCode("3E1-237".to_string()))
+    );
+    assert_eq!(
+        Cheat::parse("010F12C3", "").unwrap().kind,
+        CheatKind::GameShark {
+            bank: 0x01,
+            value: 0x0F,
+            addr: 0xC312,
+        }
+    );
+    assert!(Cheat::parse("12-345", "").is_err());
+    assert!(Cheat::parse("0102C3", "").is_err());
+    assert!(Cheat::parse("XX0F12C3", "").is_err());
+}
+
+#[test]
+fn cheat_file_and_rom_reads() {
+    let mut cheats = Cheats::parse("# lives\n00A-17B-C49 Infinite lives\n-010F12C3\n").unwrap();
+    let list: Vec<&Cheat> = cheats.iter().collect();
+    assert_eq!(list[0].name, "Infinite lives");
+    assert!(list[0].enabled);
+    assert!(!list[1].enabled);
+    assert_eq!(cheats.ram_writes().count(), 0);
+
+    assert!(cheats.patches_page(0x4A00));
+    assert!(!cheats.patches_page(0x4B00));
+    assert_eq!(cheats.read_rom(0x4A17, 0xC8), 0x00);
+    // Other banks at the same address don't match the compare byte
+    assert_eq!(cheats.read_rom(0x4A17, 0x12), 0x12);
+
+    assert!(cheats.set_enabled(0, false));
+    assert_eq!(cheats.read_rom(0x4A17, 0xC8), 0xC8);
+    assert!(!cheats.set_enabled(5, true));
+
+    assert_eq!(
+        Cheats::parse("\nBADCODE\n").err(),
+        Some(CheatError::BadLine {
+            line: 2,
+            code: "BADCODE".to_string(),
+        })
+    );
+}
@@ -0,0 +1,25 @@
+use std::error;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheatError {
+    /// Neither a Game Genie nor a GameShark code.
+    BadCode(String),
+    /// A cheat file line that doesn't parse, numbered from 1.
+    BadLine { line: usize, code: String },
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatError::BadCode(code) => write!(f, "Not a Game Genie or GameShark code: {}", code),
+            CheatError::BadLine { line, code } => {
+                write!(f, "Line {}: not a Game Genie or GameShark code: {}", line, code)
+            }
+        }
+    }
+}
+
+impl error::Error for CheatError {}
+
+pub type CheatResult<T> = Result<T, CheatError>;
@@ -2,9 +2,12 @@
 
 mod instr_arrays;
 
+use std::cell::Cell;
+
 use crate::emu_log;
 use crate::hw::memory::{Bus, BusWidth, Memory};
 use crate::registers::Registers;
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
 
 use self::instr_arrays::*;
 
@@ -22,40 +25,42 @@ pub struct Cpu {
     pub regs: Registers,
     pub memory: Memory,
     pub global_interrupt_flag: bool,
-    pub interrupt_enable_reg: u8,
-    pub interrupt_flag_reg: u8,
-}
-
-enum InterruptType {
-    VBlank = 0x1,
-    LCDC = 0x2,
-    Timer = 0x4,
-    SerialTransferDone = 0x8,
-    PinFallingEdge = 0x10,
+    /// EI has run, and interrupts are enabled after the next instruction.
+    ei_pending: bool,
+    /// HALT has run, and the CPU is waiting for an interrupt to be pending.
+    halted: bool,
+    /// The last byte fetched at PC. Instructions read their opcode again,
+    /// and that mustn't count as another M-cycle.
+    fetched: Cell<Option<(BusWidth, u8)>>,
 }
 
 impl Cpu {
     pub fn new(memory: Memory) -> Cpu {
+        let mut regs = Registers::new();
+        if memory.is_cgb() {
+            // CGB games check for A = 0x11 at boot to detect the hardware
+            regs.put_a(0x11);
+        }
         Cpu {
-            regs: Registers::new(),
+            regs,
             memory: memory,
             global_interrupt_flag: false,
-            interrupt_enable_reg: 0,
-            interrupt_flag_reg: 0,
+            ei_pending: false,
+            halted: false,
+            fetched: Cell::new(None),
         }
     }
 
-    fn is_interrupt_enabled(&self, interrupt_type: InterruptType) -> bool {
-        self.interrupt_enable_reg & (interrupt_type as u8) == 1
-    }
-
-    fn is_interrupt_triggered(&self, interrupt_type: InterruptType) -> bool {
-        self.interrupt_flag_reg & (interrupt_type as u8) == 1
-    }
-
     pub fn get_opcode(&self) -> u8 {
         let pc = self.regs.get_pc();
-        self.read8(pc)
+        match self.fetched.get() {
+            Some((addr, byte)) if addr == pc => byte,
+            _ => {
+                let byte = self.read8(pc);
+                self.fetched.set(Some((pc, byte)));
+                byte
+            }
+        }
     }
 
     pub fn incr_pc(&mut self) {
@@ -91,24 +96,22 @@ impl Cpu {
         self.regs.put_pc(addr.wrapping_sub(1));
     }
 
-    fn poll_interrupts(&mut self) {
-        if self.memory.io.lcd.vblank_interrupt_should_trigger() {
-            self.interrupt_flag_reg |= InterruptType::VBlank as u8;
-        }
-    }
-
     fn check_and_run_interrupts(&mut self) {
-        if self.global_interrupt_flag == false {
+        if !self.global_interrupt_flag {
             return;
         }
 
-        if self.is_interrupt_enabled(InterruptType::VBlank)
-            && self.is_interrupt_triggered(InterruptType::VBlank)
-        {
+        if let Some(interrupt) = self.memory.interrupts.pending() {
+            self.memory.interrupts.acknowledge(interrupt);
+            self.global_interrupt_flag = false;
+            self.halted = false;
+            // Two idle M-cycles, the push, and one to load PC
+            self.memory.internal_cycle();
+            self.memory.internal_cycle();
             self.push_u16(self.regs.get_pc());
-            self.regs.put_pc(0x0040);
-            self.interrupt_flag_reg &= !(InterruptType::VBlank as u8);
-            return;
+            self.memory.internal_cycle();
+            self.regs.put_pc(interrupt.vector());
+            self.memory.tick(20);
         }
     }
 
@@ -116,15 +119,48 @@ impl Cpu {
         // Get instruction (XXX expand)
         // Look up instruction in instruction table
         // Execute instruction
+        let pc = self.regs.get_pc();
+        self.memory.begin_instruction(pc);
+        if self.halted {
+            if self.memory.interrupts.pending().is_none() {
+                // Nothing to wake up for, so just let an M-cycle pass
+                self.memory.tick(4);
+                self.check_and_run_interrupts();
+                return Ok(NoBranch);
+            }
+            // With interrupts disabled, a pending one only ends the HALT
+            self.halted = false;
+        }
+        let enable_interrupts = self.ei_pending;
+        self.fetched.set(None);
         let opcode = self.get_opcode();
+        self.memory.notify_execute(pc, opcode);
+        let cycles = match opcode {
+            0xCB => CB_INSTR[self.peek8(pc.wrapping_add(1)) as usize].cycles,
+            _ => INSTR[opcode as usize].cycles,
+        };
         let result = (INSTR[opcode as usize].func)(self);
-        // wait
+        // The table has the cycles for a taken branch
+        let cycles = match result {
+            Ok(BranchNotTaken) => (self.memory.instr_mcycles() * 4) as u8,
+            _ => cycles,
+        };
+        self.memory.tick(cycles);
         self.incr_pc();
-        self.poll_interrupts();
+        // DI straight after EI cancels it
+        if enable_interrupts && self.ei_pending {
+            self.ei_pending = false;
+            self.global_interrupt_flag = true;
+        }
         self.check_and_run_interrupts();
         result
     }
 
+    /// Read memory without side effects, for the debugger.
+    pub fn peek8(&self, addr: BusWidth) -> u8 {
+        self.memory.peek8(addr)
+    }
+
     pub fn get_debug_str(&self) -> String {
         use std::fmt::Write;
         let mut s = String::new();
@@ -167,7 +203,15 @@ impl Cpu {
             self.regs.get_sp()
         )
         .unwrap();
-        writeln!(s, "ROM: ??  RAM: ??").unwrap();
+        let banks = self.memory.cartridge().bank_state();
+        writeln!(
+            s,
+            "ROM: {:02X}  RAM: {:02X}{}",
+            banks.romx,
+            banks.ram,
+            if banks.ram_enabled { "" } else { " (disabled)" }
+        )
+        .unwrap();
 
         write!(s, "F: [").unwrap();
         write!(s, "{}", if self.regs.get_flag_z() { "Z" } else { "-" }).unwrap();
@@ -181,17 +225,11 @@ impl Cpu {
 
 impl Bus for Cpu {
     fn write8(&mut self, addr: BusWidth, data: u8) {
-        match addr {
-            0xFFFF => self.interrupt_enable_reg = 0x1F & data,
-            ______ => self.memory.write8(addr, data),
-        };
+        self.memory.write8(addr, data);
     }
 
     fn read8(&self, addr: BusWidth) -> u8 {
-        match addr {
-            0xFFFF => self.interrupt_enable_reg,
-            ______ => self.memory.read8(addr),
-        }
+        self.memory.read8(addr)
     }
 
     fn write16(&mut self, addr: BusWidth, data: u16) {
@@ -203,6 +241,25 @@ impl Bus for Cpu {
     }
 }
 
+impl SaveState for Cpu {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.regs.save_state(w);
+        w.write_bool(self.global_interrupt_flag);
+        w.write_bool(self.ei_pending);
+        w.write_bool(self.halted);
+        self.memory.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.regs.load_state(r)?;
+        self.global_interrupt_flag = r.read_bool()?;
+        self.ei_pending = r.read_bool()?;
+        self.halted = r.read_bool()?;
+        self.fetched.set(None);
+        self.memory.load_state(r)
+    }
+}
+
 pub fn noop_instr(_: &mut Cpu) -> InstructionRetType {
     Ok(NoBranch)
 }
@@ -212,17 +269,20 @@ pub fn stop_instr(_: &mut Cpu) -> InstructionRetType {
     Err(())
 }
 
-// TODO
-pub fn halt_instr(_: &mut Cpu) -> InstructionRetType {
-    Err(())
+// TODO the HALT bug, where HALT with interrupts disabled and one already
+// pending reads the next opcode twice
+pub fn halt_instr(cpu: &mut Cpu) -> InstructionRetType {
+    cpu.halted = true;
+    Ok(NoBranch)
 }
 
 pub fn ei_instr(cpu: &mut Cpu) -> InstructionRetType {
-    cpu.global_interrupt_flag = true;
+    cpu.ei_pending = true;
     Ok(NoBranch)
 }
 
 pub fn di_instr(cpu: &mut Cpu) -> InstructionRetType {
+    cpu.ei_pending = false;
     cpu.global_interrupt_flag = false;
     Ok(NoBranch)
 }
@@ -304,6 +364,7 @@ pub fn add_hl_instr(cpu: &mut Cpu) -> InstructionRetType {
     } as u32;
 
     let new_val = hl_val + arg_val;
+    cpu.memory.internal_cycle();
 
     let h_flag = {
         let mask = (1 << 12) - 1;
@@ -327,6 +388,8 @@ pub fn add_sp_instr(cpu: &mut Cpu) -> InstructionRetType {
     let imm_val = cpu.get_opcode() as i8;
     let old_sp = cpu.regs.get_sp();
     let new_sp = u16_plus_i8(old_sp, imm_val);
+    cpu.memory.internal_cycle();
+    cpu.memory.internal_cycle();
 
     let h_flag = (old_sp >> 4) & 1 != (new_sp >> 4) & 1;
     let c_flag = (old_sp >> 7) != (new_sp >> 7);
@@ -543,6 +606,7 @@ pub fn inc_u16_instr(cpu: &mut Cpu) -> InstructionRetType {
     let old_val = u16_inc_dec_get_val(cpu, opcode);
     let new_val = old_val + 1;
     u16_inc_dec_put_val(cpu, opcode, new_val);
+    cpu.memory.internal_cycle();
     Ok(NoBranch)
 }
 
@@ -551,6 +615,7 @@ pub fn dec_u16_instr(cpu: &mut Cpu) -> InstructionRetType {
     let old_val = u16_inc_dec_get_val(cpu, opcode);
     let new_val = old_val - 1;
     u16_inc_dec_put_val(cpu, opcode, new_val);
+    cpu.memory.internal_cycle();
     Ok(NoBranch)
 }
 
@@ -968,6 +1033,7 @@ pub fn ld_from_a_to_mem_instr(cpu: &mut Cpu) -> InstructionRetType {
 pub fn ld_hl_to_sp_instr(cpu: &mut Cpu) -> InstructionRetType {
     let hl_val = cpu.regs.get_hl();
     cpu.regs.put_sp(hl_val);
+    cpu.memory.internal_cycle();
     Ok(NoBranch)
 }
 
@@ -988,6 +1054,7 @@ pub fn ld_sp_plus_signed_imm_to_hl_instr(cpu: &mut Cpu) -> InstructionRetType {
     let signed_imm = cpu.get_opcode() as i8;
     let new_sp_val = u16_plus_i8(sp_val, signed_imm);
     cpu.regs.put_hl(new_sp_val);
+    cpu.memory.internal_cycle();
     Ok(NoBranch)
 }
 
@@ -1014,6 +1081,7 @@ pub fn jr_imm8_instr(cpu: &mut Cpu) -> InstructionRetType {
     }
 
     let new_pc = u16_plus_i8(orig_pc.wrapping_add(2), imm_val);
+    cpu.memory.internal_cycle();
     cpu.jump(new_pc);
     Ok(BranchTaken)
 }
@@ -1040,6 +1108,7 @@ pub fn jp_imm16_instr(cpu: &mut Cpu) -> InstructionRetType {
     }
 
     let new_pc = (upper_imm_val << 8) | lower_imm_val;
+    cpu.memory.internal_cycle();
     cpu.jump(new_pc);
     Ok(BranchTaken)
 }
@@ -1066,6 +1135,7 @@ pub fn restart_instr(cpu: &mut Cpu) -> InstructionRetType {
     } as u16;
 
     let pc = cpu.regs.get_pc();
+    cpu.memory.internal_cycle();
     cpu.push_u16(pc.wrapping_add(1));
 
     cpu.jump(restart_addr);
@@ -1084,13 +1154,15 @@ pub fn call_instr(cpu: &mut Cpu) -> InstructionRetType {
         ____ => panic!("Unrecognized call opcode {}", opcode),
     };
 
+    // The address is read either way
+    let pc = cpu.regs.get_pc();
+    let jump_addr = cpu.read16(pc.wrapping_add(1));
+
     if !should_call {
         return Ok(BranchNotTaken);
     }
 
-    let pc = cpu.regs.get_pc();
-    let jump_addr = cpu.read16(pc.wrapping_add(1));
-
+    cpu.memory.internal_cycle();
     cpu.push_u16(pc.wrapping_add(3));
 
     cpu.jump(jump_addr);
@@ -1113,12 +1185,17 @@ pub fn ret_instr(cpu: &mut Cpu) -> InstructionRetType {
         }
         ____ => panic!("Unrecognized ret opcode {}", opcode),
     };
+    // Conditional returns take a cycle to check the condition
+    if !matches!(opcode, 0xc9 | 0xd9) {
+        cpu.memory.internal_cycle();
+    }
 
     if !should_return {
         return Ok(BranchNotTaken);
     }
 
     let ret_addr = cpu.pop_u16();
+    cpu.memory.internal_cycle();
 
     cpu.jump(ret_addr);
 
@@ -1138,6 +1215,7 @@ pub fn push_instr(cpu: &mut Cpu) -> InstructionRetType {
         ____ => panic!("Unrecognized push instruction {}", opcode),
     };
 
+    cpu.memory.internal_cycle();
     cpu.push_u16(val);
 
     Ok(NoBranch)
@@ -1219,7 +1297,7 @@ macro_rules! setup_test {
             )*
             rom.push(0xFD);
             rom.resize(0xFFFF, 0x00);
-            let new_cartridge: Box<dyn Bus> = MBC1::new(rom);
+            let new_cartridge = MBC1::new(rom);
             let new_memory = Memory::new(new_cartridge);
             Cpu::new(new_memory)
         }
@@ -1299,3 +1377,101 @@ fn daa() {
     }
     assert_eq!(cpu.regs.get_a(), 0x16);
 }
+
+#[test]
+fn instructions_count_internal_mcycles() {
+    use crate::hw::controller::MBC1;
+
+    let run = |code: &[u8], flag_z: bool| {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+        let mut cpu = Cpu::new(Memory::new(MBC1::new(rom)));
+        cpu.regs.put_sp(0xDFF0);
+        cpu.regs.put_hl(0xC000);
+        cpu.regs.put_flag_z(flag_z);
+        cpu
+    };
+    // Code, Z flag, then M-cycles: the table's for taken branches, fewer
+    // for ones not taken
+    let cases: &[(&[u8], bool, u32)] = &[
+        (&[0xC5], false, 4),
+        (&[0xCD, 0x00, 0x02], false, 6),
+        (&[0xC4, 0x00, 0x02], true, 3),
+        (&[0xC7], false, 4),
+        (&[0xC9], false, 4),
+        (&[0xC0], false, 5),
+        (&[0xC0], true, 2),
+        (&[0xC2, 0x00, 0x02], false, 4),
+        (&[0xC2, 0x00, 0x02], true, 3),
+        (&[0x20, 0x05], false, 3),
+        (&[0x20, 0x05], true, 2),
+        (&[0xE8, 0x01], false, 4),
+        (&[0xF8, 0x01], false, 3),
+        (&[0xF9], false, 2),
+        (&[0x03], false, 2),
+        (&[0x09], false, 2),
+        (&[0x34], false, 3),
+        (&[0xCB, 0x06], false, 4),
+    ];
+    for &(code, flag_z, mcycles) in cases {
+        let mut cpu = run(code, flag_z);
+        cpu.memory.begin_instruction(0x100);
+        let opcode = cpu.get_opcode();
+        (INSTR[opcode as usize].func)(&mut cpu).unwrap();
+        assert_eq!(cpu.memory.instr_mcycles(), mcycles, "{:02x?}", code);
+
+        let mut cpu = run(code, flag_z);
+        cpu.execute_instr().unwrap();
+        assert_eq!(cpu.memory.cycles(), mcycles as u64 * 4, "{:02x?}", code);
+    }
+}
+
+#[test]
+fn halt_waits_for_interrupt() {
+    use crate::hw::interrupt::InterruptType;
+
+    // halt; inc a; undefined
+    let mut cpu = setup_test![0x76, 0x3C];
+    cpu.regs.put_a(0);
+    cpu.execute_instr().unwrap();
+    for _ in 0..10 {
+        cpu.execute_instr().unwrap();
+    }
+    assert_eq!(cpu.regs.get_pc(), 0x101);
+    assert_eq!(cpu.regs.get_a(), 0);
+
+    // With interrupts disabled, HALT ends without a call to the handler
+    cpu.memory.interrupts.write_ie(InterruptType::Timer as u8);
+    cpu.memory.interrupts.request(InterruptType::Timer);
+    cpu.execute_instr().unwrap();
+    assert_eq!(cpu.regs.get_a(), 1);
+    assert!(cpu.execute_instr().is_err());
+}
+
+#[test]
+fn ei_enables_interrupts_after_next_instruction() {
+    use crate::hw::interrupt::InterruptType;
+
+    let pending = |code: &[u8]| {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+        let mut cpu = Cpu::new(Memory::new(crate::hw::controller::MBC1::new(rom)));
+        cpu.regs.put_sp(0xDFF0);
+        cpu.memory.interrupts.write_ie(InterruptType::Timer as u8);
+        cpu.memory.interrupts.request(InterruptType::Timer);
+        cpu
+    };
+    // ei; nop
+    let mut cpu = pending(&[0xFB, 0x00]);
+    cpu.execute_instr().unwrap();
+    assert_eq!(cpu.regs.get_pc(), 0x101);
+    cpu.execute_instr().unwrap();
+    assert_eq!(cpu.regs.get_pc(), InterruptType::Timer.vector());
+
+    // ei; di
+    let mut cpu = pending(&[0xFB, 0xF3]);
+    cpu.execute_instr().unwrap();
+    cpu.execute_instr().unwrap();
+    assert_eq!(cpu.regs.get_pc(), 0x102);
+    assert!(!cpu.global_interrupt_flag);
+}
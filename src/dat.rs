@@ -0,0 +1,191 @@
+mod error;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use xml::attribute::OwnedAttribute;
+use xml::reader::{EventReader, XmlEvent};
+
+pub use self::error::{DatError, DatResult};
+
+/// CRC32 and SHA-1 of a ROM image. The SHA-1 is what per-game settings
+/// and compatibility notes should be keyed on.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RomDigest {
+    pub crc32: u32,
+    /// Lowercase hex, as DAT files write it.
+    pub sha1: String,
+}
+
+impl RomDigest {
+    pub fn of(rom: &[u8]) -> RomDigest {
+        RomDigest {
+            crc32: crc32fast::hash(rom),
+            sha1: sha1_smol::Sha1::from(rom).digest().to_string(),
+        }
+    }
+}
+
+/// One known dump from a DAT file.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DatRom {
+    /// The canonical name, from the `<game>` the ROM belongs to.
+    pub game: String,
+    pub size: usize,
+    pub crc32: u32,
+    pub sha1: Option<String>,
+    /// Set for dumps the DAT marks as bad.
+    pub bad_dump: bool,
+}
+
+/// How a ROM compares with the DAT.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Identity<'a> {
+    Good(&'a DatRom),
+    /// Matches an entry the DAT marks as a bad dump.
+    BadDump(&'a DatRom),
+    /// A known dump with extra data after the end.
+    Overdump(&'a DatRom),
+    Unknown,
+}
+
+/// A No-Intro or Redump style XML DAT: `<game name="...">` elements (or
+/// `<machine>`) each holding `<rom size="..." crc="..." sha1="..."/>`.
+pub struct Dat {
+    roms: Vec<DatRom>,
+}
+
+fn attribute<'a>(attributes: &'a [OwnedAttribute], name: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|attr| attr.name.local_name == name)
+        .map(|attr| attr.value.as_str())
+}
+
+impl Dat {
+    pub fn load(path: &Path) -> DatResult<Dat> {
+        Dat::parse(BufReader::new(File::open(path)?))
+    }
+
+    pub fn parse<R: Read>(source: R) -> DatResult<Dat> {
+        let mut roms = Vec::new();
+        let mut game = String::new();
+        for event in EventReader::new(source) {
+            if let XmlEvent::StartElement {
+                name, attributes, ..
+            } = event?
+            {
+                match name.local_name.as_str() {
+                    "game" | "machine" => {
+                        game = attribute(&attributes, "name").unwrap_or("").to_string();
+                    }
+                    "rom" => {
+                        let bad_rom = || DatError::BadRom(game.clone());
+                        let size = attribute(&attributes, "size")
+                            .and_then(|size| size.parse().ok())
+                            .ok_or_else(bad_rom)?;
+                        let crc32 = attribute(&attributes, "crc")
+                            .and_then(|crc| u32::from_str_radix(crc, 16).ok())
+                            .ok_or_else(bad_rom)?;
+                        roms.push(DatRom {
+                            game: game.clone(),
+                            size,
+                            crc32,
+                            sha1: attribute(&attributes, "sha1").map(|sha1| sha1.to_lowercase()),
+                            bad_dump: attribute(&attributes, "status") == Some("baddump"),
+                        });
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(Dat { roms })
+    }
+
+    pub fn roms(&self) -> &[DatRom] {
+        &self.roms
+    }
+
+    pub fn identify(&self, rom: &[u8]) -> Identity<'_> {
+        let digest = RomDigest::of(rom);
+        let exact = self.roms.iter().find(|entry| {
+            entry.size == rom.len()
+                && entry.crc32 == digest.crc32
+                && entry.sha1.as_ref().is_none_or(|sha1| *sha1 == digest.sha1)
+        });
+        match exact {
+            Some(entry) if entry.bad_dump => return Identity::BadDump(entry),
+            Some(entry) => return Identity::Good(entry),
+            None => (),
+        }
+
+        // An overdump starts with a known good image
+        let mut prefix_crcs = HashMap::new();
+        self.roms
+            .iter()
+            .filter(|entry| !entry.bad_dump && entry.size < rom.len())
+            .find(|entry| {
+                let crc = *prefix_crcs
+                    .entry(entry.size)
+                    .or_insert_with(|| crc32fast::hash(&rom[..entry.size]));
+                crc == entry.crc32
+            })
+            .map_or(Identity::Unknown, Identity::Overdump)
+    }
+}
+
+#[cfg(test)]
+fn test_dat(good: &[u8], bad: &[u8]) -> String {
+    let good_digest = RomDigest::of(good);
+    format!(
+        r#"<?xml version="1.0"?>
+<datafile>
+  <header><name>Test</name></header>
+  <game name="Good Game (World)">
+    <description>Good Game (World)</description>
+    <rom name="Good Game (World).gb" size="{}" crc="{:08X}" sha1="{}" status="verified"/>
+  </game>
+  <game name="Bad Game (Japan) [b]">
+    <rom name="Bad Game (Japan) [b].gb" size="{}" crc="{:08x}" status="baddump"/>
+  </game>
+</datafile>"#,
+        good.len(),
+        good_digest.crc32,
+        good_digest.sha1.to_uppercase(),
+        bad.len(),
+        crc32fast::hash(bad)
+    )
+}
+
+#[test]
+fn dat_identifies_dumps() {
+    let good = vec![0x11u8; 0x8000];
+    let bad = vec![0x22u8; 0x8000];
+    let dat = Dat::parse(test_dat(&good, &bad).as_bytes()).unwrap();
+    assert_eq!(dat.roms().len(), 2);
+
+    match dat.identify(&good) {
+        Identity::Good(entry) => assert_eq!(entry.game, "Good Game (World)"),
+        other => panic!("{:?}", other),
+    }
+    assert!(matches!(dat.identify(&bad), Identity::BadDump(_)));
+
+    let mut overdump = good.clone();
+    overdump.extend_from_slice(&[0xFF; 0x8000]);
+    assert!(matches!(dat.identify(&overdump), Identity::Overdump(_)));
+
+    let mut modified = good;
+    modified[0x100] = 0;
+    assert_eq!(dat.identify(&modified), Identity::Unknown);
+}
+
+#[test]
+fn rom_digest() {
+    let digest = RomDigest::of(b"abc");
+    assert_eq!(digest.crc32, 0x352441C2);
+    assert_eq!(digest.sha1, "a9993e364706816aba3e25717850c26c9cd0d89d");
+    let no_crc = r#"<datafile><game name="x"><rom size="1"/></game></datafile>"#;
+    assert!(Dat::parse(no_crc.as_bytes()).is_err());
+}
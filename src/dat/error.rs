@@ -0,0 +1,38 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum DatError {
+    Io(io::Error),
+    Xml(xml::reader::Error),
+    /// A `<rom>` entry missing its size or CRC, or with one that doesn't
+    /// parse.
+    BadRom(String),
+}
+
+impl fmt::Display for DatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatError::Io(e) => write!(f, "DAT I/O error: {}", e),
+            DatError::Xml(e) => write!(f, "Bad DAT file: {}", e),
+            DatError::BadRom(name) => write!(f, "Bad DAT entry for {}", name),
+        }
+    }
+}
+
+impl error::Error for DatError {}
+
+impl From<io::Error> for DatError {
+    fn from(e: io::Error) -> DatError {
+        DatError::Io(e)
+    }
+}
+
+impl From<xml::reader::Error> for DatError {
+    fn from(e: xml::reader::Error) -> DatError {
+        DatError::Xml(e)
+    }
+}
+
+pub type DatResult<T> = Result<T, DatError>;
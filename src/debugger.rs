@@ -1,13 +1,21 @@
 mod error;
 
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::io::{self, prelude::*};
+use std::rc::Rc;
 
 use itertools::Itertools;
 
+use crate::cheats::Cheat;
 use crate::cpu::Cpu;
 use crate::emu_log;
-use crate::hw::memory::{Bus, BusWidth};
+use crate::hw::io_regs::{self, IO_REGISTERS};
+use crate::hw::memory::BusWidth;
+use crate::hw::observer::{AccessKind, AccessMask, MemoryEvent, ObserverId};
+use crate::ram_search::{Filter, RamSearch, ValueType};
+use crate::savestate;
 
 use self::error::{DebugError, DebugResult};
 
@@ -36,7 +44,12 @@ fn registers_cmd(
 
 fn parse_val<S: Into<String>>(string: S) -> DebugResult<BusWidth> {
     let addr_str = string.into();
-    if addr_str.starts_with("0x") {
+    let upper = addr_str.to_uppercase();
+    if let Some(reg) = IO_REGISTERS.iter().find(|reg| reg.name == upper) {
+        Ok(reg.addr)
+    } else if upper == "IE" {
+        Ok(0xFFFF)
+    } else if addr_str.starts_with("0x") {
         let trimmed_str = addr_str.trim_start_matches("0x");
         u16::from_str_radix(trimmed_str, 16).map_err(|_| DebugError)
     } else {
@@ -49,8 +62,23 @@ fn print_cmd(
     cpu: &mut Cpu,
     args: &mut Iterator<Item = &str>,
 ) -> DebugResult<()> {
-    let start_addr = parse_val(args.next().ok_or(DebugError)?)?;
+    // Either `addr` or `bank:addr` to look at a bank that isn't switched in
+    let start = args.next().ok_or(DebugError)?;
+    let (bank, start_addr) = match start.find(':') {
+        Some(idx) => (
+            Some(parse_val(&start[..idx])? as usize),
+            parse_val(&start[idx + 1..])?,
+        ),
+        None => (None, parse_val(start)?),
+    };
     let len = parse_val(args.next().unwrap_or("1"))?;
+    let peek = |addr| match bank {
+        Some(bank) => cpu
+            .memory
+            .peek8_banked(addr, bank)
+            .map_or_else(|| "??".to_string(), |val| format!("{:02x}", val)),
+        None => format!("{:02x}", cpu.peek8(addr)),
+    };
 
     let mut line_output = Vec::<String>::new();
     for chunk_iter in &(start_addr..=start_addr + len).chunks(0x10) {
@@ -58,7 +86,7 @@ fn print_cmd(
         let line_start_addr = peekable_iter.peek().ok_or(DebugError)?;
         print!("{:04x}: ", line_start_addr);
         peekable_iter
-            .map(|addr| format!("{:02x}", cpu.read8(addr)))
+            .map(peek)
             .for_each(|s| line_output.push(s));
         println!("{}", line_output.join(" "));
         line_output.clear();
@@ -87,6 +115,163 @@ fn break_cmd(
     Ok(())
 }
 
+fn io_cmd(
+    _: &mut Debugger,
+    cpu: &mut Cpu,
+    _: &mut dyn Iterator<Item = &str>,
+) -> DebugResult<()> {
+    for reg in IO_REGISTERS {
+        if cpu.memory.io.register(reg.addr).is_some() {
+            println!("{:04x} {:<6} {:02x}", reg.addr, reg.name, cpu.peek8(reg.addr));
+        }
+    }
+    println!("{:04x} {:<6} {:02x}", 0xFFFF, "IE", cpu.peek8(0xFFFF));
+    Ok(())
+}
+
+/// `r`, `w` or `rw` for watchpoints, writes if not given.
+fn parse_access_kinds(arg: Option<&str>) -> DebugResult<AccessMask> {
+    match arg.unwrap_or("w") {
+        "r" => Ok(AccessMask::READ),
+        "w" => Ok(AccessMask::WRITE),
+        "rw" => Ok(AccessMask::READ.union(AccessMask::WRITE)),
+        _ => Err(DebugError),
+    }
+}
+
+fn watch_cmd(
+    dbgr: &mut Debugger,
+    cpu: &mut Cpu,
+    args: &mut dyn Iterator<Item = &str>,
+) -> DebugResult<()> {
+    let addr = parse_val(args.next().ok_or(DebugError)?)?;
+    let kinds = parse_access_kinds(args.next())?;
+    dbgr.watch(cpu, addr, None, kinds);
+    Ok(())
+}
+
+fn unwatch_cmd(
+    dbgr: &mut Debugger,
+    cpu: &mut Cpu,
+    args: &mut dyn Iterator<Item = &str>,
+) -> DebugResult<()> {
+    let addr = parse_val(args.next().ok_or(DebugError)?)?;
+    let id = dbgr.watchpoints.remove(&addr).ok_or(DebugError)?;
+    cpu.memory.unsubscribe(id);
+    println!("Removed watchpoint from 0x{:04x}", addr);
+    Ok(())
+}
+
+fn save_cmd(
+    _: &mut Debugger,
+    cpu: &mut Cpu,
+    args: &mut dyn Iterator<Item = &str>,
+) -> DebugResult<()> {
+    let path = args.next().ok_or(DebugError)?;
+    fs::write(path, savestate::save_to_vec(cpu)).map_err(|_| DebugError)?;
+    println!("Saved state to {}", path);
+    Ok(())
+}
+
+/// Candidates are listed after each step once there are this few.
+const SEARCH_LIST_MAX: usize = 10;
+
+fn print_candidates(search: &RamSearch, max: usize) {
+    for (idx, candidate) in search.candidates().iter().take(max).enumerate() {
+        println!("{:>3} {}", idx, candidate);
+    }
+    if search.candidates().len() > max {
+        println!("... {} more", search.candidates().len() - max);
+    }
+}
+
+/// RAM search: `search new [u8|u16|bcd8|bcd16]` takes a snapshot, then
+/// `search eq <n>`, `same`, `changed`, `inc [n]` or `dec [n]` keeps the
+/// candidates that match compared with the last step. `search list [max]`
+/// shows them and `search watch <n> [r|w|rw]` sets a watchpoint on one.
+fn search_cmd(
+    dbgr: &mut Debugger,
+    cpu: &mut Cpu,
+    args: &mut dyn Iterator<Item = &str>,
+) -> DebugResult<()> {
+    let step = args.next().ok_or(DebugError)?;
+    if step == "new" {
+        let value_type = ValueType::parse(args.next().unwrap_or("u8")).ok_or(DebugError)?;
+        let search = RamSearch::new(&cpu.memory, value_type);
+        println!("{} candidates", search.candidates().len());
+        dbgr.search = Some(search);
+        return Ok(());
+    }
+
+    let search = dbgr.search.as_mut().ok_or(DebugError)?;
+    let mut number = || -> DebugResult<Option<u32>> {
+        args.next()
+            .map(|arg| parse_val(arg).map(|val| val as u32))
+            .transpose()
+    };
+    let filter = match step {
+        "eq" => Filter::Equal(number()?.ok_or(DebugError)?),
+        "same" => Filter::Unchanged,
+        "changed" => Filter::Changed,
+        "inc" => number()?.map_or(Filter::Increased, Filter::IncreasedBy),
+        "dec" => number()?.map_or(Filter::Decreased, Filter::DecreasedBy),
+        "list" => {
+            let max = number()?.map_or(usize::MAX, |max| max as usize);
+            print_candidates(search, max);
+            return Ok(());
+        }
+        "watch" => {
+            let idx = number()?.ok_or(DebugError)? as usize;
+            let candidate = *search.candidates().get(idx).ok_or(DebugError)?;
+            let kinds = parse_access_kinds(args.next())?;
+            dbgr.watch(cpu, candidate.addr(), Some(candidate.bank() as u16), kinds);
+            return Ok(());
+        }
+        _ => return Err(DebugError),
+    };
+    let left = search.filter(&cpu.memory, filter);
+    println!("{} candidates", left);
+    if left <= SEARCH_LIST_MAX {
+        print_candidates(search, SEARCH_LIST_MAX);
+    }
+    Ok(())
+}
+
+/// `cheat` lists the cheats, `cheat <n>` turns cheat n on or off, and
+/// `cheat add <code> [name]` adds one.
+fn cheat_cmd(
+    dbgr: &mut Debugger,
+    cpu: &mut Cpu,
+    args: &mut dyn Iterator<Item = &str>,
+) -> DebugResult<()> {
+    match args.next() {
+        None => {
+            for (idx, cheat) in cpu.memory.cheats().iter().enumerate() {
+                println!("{:>3} {}", idx, cheat);
+            }
+        }
+        Some(_) if dbgr.cheats_locked => {
+            println!("Cheats can't be changed while a movie is running");
+            return Err(DebugError);
+        }
+        Some("add") => {
+            let code = args.next().ok_or(DebugError)?;
+            let name = args.join(" ");
+            let cheat = Cheat::parse(code, &name).map_err(|_| DebugError)?;
+            println!("Added cheat {}", cheat);
+            cpu.memory.add_cheat(cheat);
+        }
+        Some(idx) => {
+            let idx = idx.parse::<usize>().map_err(|_| DebugError)?;
+            let cheat = cpu.memory.cheats().iter().nth(idx).ok_or(DebugError)?;
+            let enabled = !cheat.enabled;
+            cpu.memory.set_cheat_enabled(idx, enabled);
+            println!("Cheat {} {}", idx, if enabled { "on" } else { "off" });
+        }
+    }
+    Ok(())
+}
+
 struct Cmd {
     command: &'static str,
     func: fn(&mut Debugger, &mut Cpu, &mut Iterator<Item = &str>) -> DebugResult<()>,
@@ -99,6 +284,12 @@ enum Cmds {
     ContinueCmd,
     BreakCmd,
     RegistersCmd,
+    IoCmd,
+    WatchCmd,
+    UnwatchCmd,
+    SaveCmd,
+    CheatCmd,
+    SearchCmd,
 }
 
 const CMD_LIST: &'static [Cmd] = &[
@@ -152,11 +343,51 @@ const CMD_LIST: &'static [Cmd] = &[
         func: registers_cmd,
         goto_next_cmd: false,
     },
+    Cmd {
+        command: "io",
+        func: io_cmd,
+        goto_next_cmd: false,
+    },
+    Cmd {
+        command: "w",
+        func: watch_cmd,
+        goto_next_cmd: false,
+    },
+    Cmd {
+        command: "watch",
+        func: watch_cmd,
+        goto_next_cmd: false,
+    },
+    Cmd {
+        command: "unwatch",
+        func: unwatch_cmd,
+        goto_next_cmd: false,
+    },
+    Cmd {
+        command: "save",
+        func: save_cmd,
+        goto_next_cmd: false,
+    },
+    Cmd {
+        command: "cheat",
+        func: cheat_cmd,
+        goto_next_cmd: false,
+    },
+    Cmd {
+        command: "search",
+        func: search_cmd,
+        goto_next_cmd: false,
+    },
 ];
 
 pub struct Debugger {
     state: DebuggerState,
     breakpoints: HashSet<BusWidth>,
+    watchpoints: HashMap<BusWidth, ObserverId>,
+    watch_hits: Rc<RefCell<Vec<MemoryEvent>>>,
+    search: Option<RamSearch>,
+    /// Set while a movie runs, since it has no record of cheat changes.
+    cheats_locked: bool,
     commands: HashMap<&'static str, &'static Cmd>,
     last_cmd: &'static Cmd,
 }
@@ -171,11 +402,42 @@ impl Debugger {
         Debugger {
             state: DebuggerState::Paused,
             breakpoints: HashSet::new(),
+            watchpoints: HashMap::new(),
+            watch_hits: Rc::new(RefCell::new(Vec::new())),
+            search: None,
+            cheats_locked: false,
             commands: commands,
             last_cmd: last_cmd,
         }
     }
 
+    pub fn set_cheats_locked(&mut self, locked: bool) {
+        self.cheats_locked = locked;
+    }
+
+    /// Pause on accesses of `kinds` to `addr`, replacing any watchpoint
+    /// already there. With a `bank`, only while that bank is mapped there.
+    fn watch(&mut self, cpu: &mut Cpu, addr: BusWidth, bank: Option<u16>, kinds: AccessMask) {
+        if let Some(old_id) = self.watchpoints.remove(&addr) {
+            cpu.memory.unsubscribe(old_id);
+        }
+        let hits = self.watch_hits.clone();
+        let id = cpu.memory.subscribe(
+            kinds,
+            addr..=addr,
+            Box::new(move |event| {
+                if bank.is_none_or(|bank| event.bank == bank) {
+                    hits.borrow_mut().push(*event);
+                }
+            }),
+        );
+        self.watchpoints.insert(addr, id);
+        match bank {
+            Some(bank) => println!("Added watchpoint to {:02x}:{:04x}", bank, addr),
+            None => println!("Added watchpoint to 0x{:04x}", addr),
+        }
+    }
+
     fn parse_input<'a, I>(&self, cmd_iter: I) -> Option<&'static Cmd>
     where
         I: IntoIterator<Item = &'a str>,
@@ -184,7 +446,30 @@ impl Debugger {
         Some(self.commands.get(&cmd)?)
     }
 
+    fn report_watch_hits(&mut self) -> bool {
+        let hits: Vec<MemoryEvent> = self.watch_hits.borrow_mut().drain(..).collect();
+        for hit in &hits {
+            let kind = match hit.kind {
+                AccessKind::Read => "read",
+                AccessKind::Write => "write",
+                AccessKind::Execute => "execute",
+            };
+            let name = io_regs::name(hit.addr)
+                .map(|name| format!(" ({})", name))
+                .unwrap_or_default();
+            println!(
+                "Watchpoint 0x{:04x}{}: {} 0x{:02x} by pc 0x{:04x} (bank {}, cycle {})",
+                hit.addr, name, kind, hit.value, hit.pc, hit.bank, hit.cycle
+            );
+        }
+        !hits.is_empty()
+    }
+
     pub fn tick(&mut self, cpu: &mut Cpu) {
+        if self.report_watch_hits() {
+            self.state = DebuggerState::Paused;
+        }
+
         if self.state == DebuggerState::Running {
             // XXX Add ability to break out of running state
             if self.breakpoints.contains(&cpu.regs.get_pc()) {
@@ -226,3 +511,27 @@ impl Debugger {
         }
     }
 }
+
+#[test]
+fn search_watch_matches_bank() {
+    use crate::hw::controller::MBC1;
+    use crate::hw::memory::{Bus, Memory};
+
+    let mut cpu = Cpu::new(Memory::new_cgb(MBC1::new(vec![0u8; 0x8000])));
+    let mut dbgr = Debugger::new();
+    cpu.memory.write8(0xFF70, 3);
+    cpu.memory.write8(0xD010, 7);
+    dbgr.search = Some(RamSearch::new(&cpu.memory, ValueType::U8));
+    let mut args = "eq 7".split_whitespace();
+    search_cmd(&mut dbgr, &mut cpu, &mut args).unwrap();
+    let mut args = "watch 0 w".split_whitespace();
+    search_cmd(&mut dbgr, &mut cpu, &mut args).unwrap();
+
+    // The same address in another bank doesn't count
+    cpu.memory.write8(0xFF70, 1);
+    cpu.memory.write8(0xD010, 8);
+    assert!(!dbgr.report_watch_hits());
+    cpu.memory.write8(0xFF70, 3);
+    cpu.memory.write8(0xD010, 8);
+    assert!(dbgr.report_watch_hits());
+}
@@ -0,0 +1,381 @@
+use crate::hw::controller::{header, BankState, Cartridge, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::hw::memory::{Bus, BusWidth};
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
+
+/// Size of the picture the camera hands to software, in pixels.
+pub const SENSOR_WIDTH: usize = 128;
+pub const SENSOR_HEIGHT: usize = 112;
+
+/// Where the finished picture goes in RAM bank 0: 16x14 tiles, 2bpp.
+const IMAGE_OFFSET: usize = 0x100;
+const IMAGE_SIZE: usize = SENSOR_WIDTH * SENSOR_HEIGHT / 4;
+
+const REG_COUNT: usize = 0x36;
+const REG_CONTROL: usize = 0x00;
+const REG_GAIN: usize = 0x01;
+const REG_EXPOSURE_HIGH: usize = 0x02;
+const REG_EXPOSURE_LOW: usize = 0x03;
+const REG_EDGE: usize = 0x04;
+const REG_MATRIX: usize = 0x06;
+
+/// Exposure time at which the sensor passes the light it sees straight
+/// through. Games adjust exposure themselves until the picture looks
+/// right, so this only needs to be in the right range.
+const EXPOSURE_NEUTRAL: f32 = 0x400 as f32;
+
+/// Edge enhancement strength for the ratio in bits 4-6 of register 4.
+const EDGE_RATIOS: [f32; 8] = [0.5, 0.75, 1.0, 1.25, 2.0, 3.0, 4.0, 5.0];
+
+/// The light falling on the camera sensor.
+pub trait ImageSensor {
+    /// A `SENSOR_WIDTH` x `SENSOR_HEIGHT` picture, row by row, one byte per
+    /// pixel from 0 (dark) to 255 (bright).
+    fn capture(&mut self) -> Vec<u8>;
+}
+
+/// A lens cap: the same flat grey every time.
+pub struct BlankSensor;
+
+impl ImageSensor for BlankSensor {
+    fn capture(&mut self) -> Vec<u8> {
+        vec![0x80; SENSOR_WIDTH * SENSOR_HEIGHT]
+    }
+}
+
+/// Nintendo's Pocket Camera, with a Mitsubishi M64282FP sensor. It banks
+/// like an MBC3 with 16 RAM banks, and selecting RAM bank 10 or above puts
+/// the sensor registers at A000 instead.
+///
+/// Software sets up exposure, gain and edge enhancement in registers 1-5
+/// and a 4x4 dithering matrix in registers 6-35, which turns the analog
+/// picture into four shades (and with it sets the contrast), then starts a
+/// capture with bit 0 of register 0. That bit reads back set until the
+/// picture has been written to RAM bank 0.
+///
+/// The sensor's output and zero point voltages aren't modelled; the
+/// picture is only shaped by the exposure, gain, edge and matrix settings.
+pub struct PocketCamera {
+    rom: Vec<u8>,
+    rom_bank_num: u8,
+    ram: Vec<u8>,
+    ram_enable: bool,
+    ram_bank_num: u8,
+    regs: [u8; REG_COUNT],
+    sensor: Box<dyn ImageSensor>,
+    /// The picture being captured, written to RAM when the capture ends.
+    image: Vec<u8>,
+    capture_end: Option<u64>,
+    cycles: u64,
+}
+
+impl PocketCamera {
+    pub fn new(rom: Vec<u8>) -> Box<dyn Cartridge> {
+        let ramsize = rom
+            .get(0x149)
+            .and_then(|code| header::ram_size(*code).ok())
+            .unwrap_or(0);
+        Box::new(PocketCamera {
+            rom,
+            rom_bank_num: 1,
+            ram: vec![0u8; ramsize],
+            ram_enable: false,
+            ram_bank_num: 0,
+            regs: [0u8; REG_COUNT],
+            sensor: Box::new(BlankSensor),
+            image: vec![0u8; IMAGE_SIZE],
+            capture_end: None,
+            cycles: 0,
+        })
+    }
+
+    fn rom_bank(&self) -> usize {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        self.rom_bank_num as usize % banks
+    }
+
+    fn registers_selected(&self) -> bool {
+        self.ram_bank_num & 0x10 != 0
+    }
+
+    /// RAM can't be reached while the sensor is writing to it.
+    fn ram_index(&self, addr: BusWidth) -> Option<usize> {
+        if self.registers_selected() || self.capture_end.is_some() || self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_bank_num as usize * RAM_BANK_SIZE + (addr - 0xA000) as usize;
+        Some(offset % self.ram.len())
+    }
+
+    fn exposure(&self) -> u16 {
+        (self.regs[REG_EXPOSURE_HIGH] as u16) << 8 | self.regs[REG_EXPOSURE_LOW] as u16
+    }
+
+    /// How long a capture takes with the current settings, in CPU cycles.
+    fn capture_cycles(&self) -> u64 {
+        let n_bit = self.regs[REG_GAIN] & 0x80 != 0;
+        129_792 + 64 * self.exposure() as u64 + if n_bit { 0 } else { 2048 }
+    }
+
+    fn start_capture(&mut self) {
+        let light = self.sensor.capture();
+        self.image = self.process(&light);
+        self.capture_end = Some(self.cycles + self.capture_cycles());
+    }
+
+    fn finish_capture(&mut self) {
+        self.capture_end = None;
+        self.regs[REG_CONTROL] &= !0x01;
+        if self.ram.len() >= IMAGE_OFFSET + IMAGE_SIZE {
+            self.ram[IMAGE_OFFSET..IMAGE_OFFSET + IMAGE_SIZE].copy_from_slice(&self.image);
+        }
+    }
+
+    /// Run the sensor's analog processing and the dithering matrix over
+    /// `light`, giving the picture as tile data.
+    fn process(&self, light: &[u8]) -> Vec<u8> {
+        let pixel = |x: isize, y: isize| -> f32 {
+            let x = x.clamp(0, SENSOR_WIDTH as isize - 1) as usize;
+            let y = y.clamp(0, SENSOR_HEIGHT as isize - 1) as usize;
+            light.get(y * SENSOR_WIDTH + x).cloned().unwrap_or(0) as f32
+        };
+        let gain = 0.88 + (self.regs[REG_GAIN] & 0x1F) as f32 * 0.0127;
+        let level = gain * self.exposure() as f32 / EXPOSURE_NEUTRAL;
+        let ratio = EDGE_RATIOS[(self.regs[REG_EDGE] >> 4 & 0x7) as usize];
+        let (horizontal, vertical) = match self.regs[REG_GAIN] >> 5 & 0x3 {
+            1 => (true, false),
+            2 => (false, true),
+            3 => (true, true),
+            _ => (false, false),
+        };
+        let invert = self.regs[REG_EDGE] & 0x08 != 0;
+
+        let mut tiles = vec![0u8; IMAGE_SIZE];
+        for y in 0..SENSOR_HEIGHT {
+            for x in 0..SENSOR_WIDTH {
+                let (xi, yi) = (x as isize, y as isize);
+                let mut value = pixel(xi, yi);
+                if horizontal {
+                    value += ratio * (2.0 * pixel(xi, yi) - pixel(xi - 1, yi) - pixel(xi + 1, yi));
+                }
+                if vertical {
+                    value += ratio * (2.0 * pixel(xi, yi) - pixel(xi, yi - 1) - pixel(xi, yi + 1));
+                }
+                let mut value = (value * level).clamp(0.0, 255.0) as u8;
+                if invert {
+                    value = 255 - value;
+                }
+
+                let matrix = REG_MATRIX + ((y & 3) * 4 + (x & 3)) * 3;
+                let shade = match self.regs[matrix..matrix + 3].iter().position(|&t| value < t) {
+                    Some(darker) => 3 - darker as u8,
+                    None => 0,
+                };
+
+                let row = (y / 8 * 16 + x / 8) * 16 + (y & 7) * 2;
+                let bit = 0x80 >> (x & 7);
+                if shade & 0x1 != 0 {
+                    tiles[row] |= bit;
+                }
+                if shade & 0x2 != 0 {
+                    tiles[row + 1] |= bit;
+                }
+            }
+        }
+        tiles
+    }
+}
+
+impl Bus for PocketCamera {
+    fn write8(&mut self, addr: BusWidth, data: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enable = data & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank_num = data & 0x3F,
+            0x4000..=0x5FFF => self.ram_bank_num = data & 0x1F,
+            0xA000..=0xBFFF if self.registers_selected() => {
+                let reg = (addr & 0x7F) as usize;
+                match reg {
+                    REG_CONTROL => {
+                        let start = data & 0x01 != 0 && self.capture_end.is_none();
+                        // Software can't stop a capture once it's going
+                        self.regs[REG_CONTROL] = data & 0x06 | self.regs[REG_CONTROL] & 0x01;
+                        if start {
+                            self.regs[REG_CONTROL] |= 0x01;
+                            self.start_capture();
+                        }
+                    }
+                    reg if reg < REG_COUNT => self.regs[reg] = data,
+                    _ => (),
+                }
+            }
+            0xA000..=0xBFFF => {
+                if !self.ram_enable {
+                    return;
+                }
+                if let Some(idx) = self.ram_index(addr) {
+                    self.ram[idx] = data;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn read8(&self, addr: BusWidth) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom.get(addr as usize).cloned().unwrap_or(0xFF),
+            0x4000..=0x7FFF => {
+                let offset = self.rom_bank() * ROM_BANK_SIZE + (addr - 0x4000) as usize;
+                self.rom.get(offset).cloned().unwrap_or(0xFF)
+            }
+            // Only the control register can be read back
+            0xA000..=0xBFFF if self.registers_selected() => match addr & 0x7F {
+                0 => self.regs[REG_CONTROL] & 0x07,
+                _ => 0x00,
+            },
+            0xA000..=0xBFFF if self.capture_end.is_some() => 0x00,
+            0xA000..=0xBFFF => self.ram_index(addr).map_or(0xFF, |idx| self.ram[idx]),
+            _ => 0xFF,
+        }
+    }
+
+    fn direct_page(&self, addr: BusWidth) -> Option<&[u8]> {
+        let page_start = self.rom_offset(addr)? & !0xFF;
+        self.rom.get(page_start..page_start + 0x100)
+    }
+
+    fn write16(&mut self, addr: BusWidth, data: u16) {
+        self._write16_using_write8(addr, data);
+    }
+
+    fn read16(&self, addr: BusWidth) -> u16 {
+        self._read16_using_read8(addr)
+    }
+}
+
+impl Cartridge for PocketCamera {
+    fn bank_state(&self) -> BankState {
+        BankState {
+            rom0: 0,
+            romx: self.rom_bank() as u16,
+            ram: self.ram_bank_num as u16,
+            ram_enabled: self.ram_enable,
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_offset(&self, addr: BusWidth) -> Option<usize> {
+        match addr {
+            0xA000..=0xBFFF if self.ram_enable => self.ram_index(addr),
+            _ => None,
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        true
+    }
+
+    fn connect_camera(&mut self, sensor: Box<dyn ImageSensor>) -> bool {
+        self.sensor = sensor;
+        true
+    }
+
+    fn needs_tick(&self) -> bool {
+        true
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.cycles = cycles;
+        if self.capture_end.is_some_and(|end| cycles >= end) {
+            self.finish_capture();
+        }
+    }
+}
+
+impl SaveState for PocketCamera {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rom_bank_num);
+        w.write_bytes(&self.ram);
+        w.write_bool(self.ram_enable);
+        w.write_u8(self.ram_bank_num);
+        w.write_bytes(&self.regs);
+        w.write_bytes(&self.image);
+        w.write_bool(self.capture_end.is_some());
+        w.write_u64(self.capture_end.unwrap_or(0));
+        w.write_u64(self.cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.rom_bank_num = r.read_u8()? & 0x3F;
+        r.read_into(&mut self.ram)?;
+        self.ram_enable = r.read_bool()?;
+        self.ram_bank_num = r.read_u8()? & 0x1F;
+        r.read_into(&mut self.regs)?;
+        r.read_into(&mut self.image)?;
+        let capturing = r.read_bool()?;
+        let capture_end = r.read_u64()?;
+        self.capture_end = if capturing { Some(capture_end) } else { None };
+        self.cycles = r.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+struct StripesSensor;
+
+#[cfg(test)]
+impl ImageSensor for StripesSensor {
+    fn capture(&mut self) -> Vec<u8> {
+        (0..SENSOR_WIDTH * SENSOR_HEIGHT)
+            .map(|i| if i % 2 == 0 { 0x00 } else { 0xFF })
+            .collect()
+    }
+}
+
+#[test]
+fn pocket_camera_capture() {
+    let mut rom = vec![0u8; ROM_BANK_SIZE * 64];
+    rom[0x149] = 0x04;
+    let mut camera = PocketCamera::new(rom);
+    assert!(camera.connect_camera(Box::new(StripesSensor)));
+
+    camera.write8(0x0000, 0x0A);
+    camera.write8(0x4000, 0x10);
+    camera.write8(0xA002, 0x04);
+    camera.write8(0xA003, 0x00);
+    for cell in 0..16 {
+        camera.write8(0xA006 + cell * 3, 0x40);
+        camera.write8(0xA007 + cell * 3, 0x80);
+        camera.write8(0xA008 + cell * 3, 0xC0);
+    }
+    camera.write8(0xA000, 0x03);
+    assert_eq!(camera.read8(0xA000), 0x03);
+    camera.write8(0x4000, 0x00);
+    assert_eq!(camera.read8(0xA100), 0x00);
+
+    camera.tick(129_792 + 64 * 0x400 + 2048);
+    camera.write8(0x4000, 0x10);
+    assert_eq!(camera.read8(0xA000), 0x02);
+    // Dark pixels come out black, bright ones white
+    camera.write8(0x4000, 0x00);
+    assert_eq!(camera.read8(0xA100), 0xAA);
+    assert_eq!(camera.read8(0xA101), 0xAA);
+    assert_eq!(camera.read8(0xAEFF), 0xAA);
+
+    // Inverted
+    camera.write8(0x4000, 0x10);
+    camera.write8(0xA004, 0x08);
+    camera.write8(0xA000, 0x01);
+    camera.tick(1_000_000);
+    camera.write8(0x4000, 0x00);
+    assert_eq!(camera.read8(0xA100), 0x55);
+}
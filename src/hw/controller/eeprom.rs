@@ -0,0 +1,303 @@
+use crate::savestate::{SaveState, StateError, StateReader, StateResult, StateWriter};
+
+/// 93LC56 in 16-bit mode: 128 words.
+pub const EEPROM_SIZE: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum EepromState {
+    /// Waiting for a start bit.
+    Idle,
+    /// Shifting in the opcode and address.
+    Command,
+    /// Shifting out `data`, then the following words.
+    Read { addr: u8, data: u16, bits: u8 },
+    /// Shifting in a word for `addr`, or for every word if None.
+    Write { addr: Option<u8> },
+}
+
+/// A 93LC56 serial EEPROM, driven one pin at a time.
+///
+/// Commands are a start bit, a two bit opcode and eight address bits, of
+/// which the top one is ignored, clocked in on rising edges of CLK while
+/// CS is high. Words are kept low byte first.
+pub struct Eeprom {
+    data: Vec<u8>,
+    cs: bool,
+    clk: bool,
+    di: bool,
+    do_: bool,
+    shift: u16,
+    bits: u8,
+    write_enabled: bool,
+    state: EepromState,
+}
+
+impl Eeprom {
+    pub fn new() -> Eeprom {
+        Eeprom {
+            data: vec![0xFF; EEPROM_SIZE],
+            cs: false,
+            clk: false,
+            di: false,
+            do_: true,
+            shift: 0,
+            bits: 0,
+            write_enabled: false,
+            state: EepromState::Idle,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    fn word(&self, addr: u8) -> u16 {
+        let idx = (addr & 0x7F) as usize * 2;
+        self.data[idx] as u16 | (self.data[idx + 1] as u16) << 8
+    }
+
+    fn set_word(&mut self, addr: u8, word: u16) {
+        if !self.write_enabled {
+            return;
+        }
+        let idx = (addr & 0x7F) as usize * 2;
+        self.data[idx] = word as u8;
+        self.data[idx + 1] = (word >> 8) as u8;
+    }
+
+    /// The pins as the MBC7 maps them: CS bit 7, CLK bit 6, DI bit 1 and
+    /// DO bit 0.
+    pub fn read_pins(&self) -> u8 {
+        (self.cs as u8) << 7 | (self.clk as u8) << 6 | (self.di as u8) << 1 | self.do_ as u8
+    }
+
+    pub fn write_pins(&mut self, data: u8) {
+        let cs = data & 0x80 != 0;
+        let clk = data & 0x40 != 0;
+        self.di = data & 0x02 != 0;
+        if !cs {
+            self.state = EepromState::Idle;
+        } else if !self.clk && clk {
+            self.clock_in();
+        }
+        self.cs = cs;
+        self.clk = clk;
+    }
+
+    fn shift_in(&mut self) {
+        self.shift = self.shift << 1 | self.di as u16;
+        self.bits += 1;
+    }
+
+    fn clock_in(&mut self) {
+        match self.state {
+            EepromState::Idle => {
+                if self.di {
+                    self.state = EepromState::Command;
+                    self.shift = 0;
+                    self.bits = 0;
+                }
+            }
+            EepromState::Command => {
+                self.shift_in();
+                if self.bits == 10 {
+                    self.run_command();
+                }
+            }
+            EepromState::Read { addr, data, bits } => {
+                self.do_ = data & 0x8000 != 0;
+                self.state = if bits > 1 {
+                    EepromState::Read {
+                        addr,
+                        data: data << 1,
+                        bits: bits - 1,
+                    }
+                } else {
+                    // Reads carry on into the next word
+                    let next = addr.wrapping_add(1) & 0x7F;
+                    EepromState::Read {
+                        addr: next,
+                        data: self.word(next),
+                        bits: 16,
+                    }
+                };
+            }
+            EepromState::Write { addr } => {
+                self.shift_in();
+                if self.bits == 16 {
+                    let word = self.shift;
+                    match addr {
+                        Some(addr) => self.set_word(addr, word),
+                        None => (0..0x80).for_each(|addr| self.set_word(addr, word)),
+                    }
+                    self.do_ = true;
+                    self.state = EepromState::Idle;
+                }
+            }
+        }
+    }
+
+    fn run_command(&mut self) {
+        let opcode = self.shift >> 8 & 0x3;
+        let operand = self.shift as u8;
+        let addr = operand & 0x7F;
+        self.shift = 0;
+        self.bits = 0;
+        self.state = EepromState::Idle;
+        match opcode {
+            // READ: a dummy 0 bit, then the word
+            0b10 => {
+                self.do_ = false;
+                self.state = EepromState::Read {
+                    addr,
+                    data: self.word(addr),
+                    bits: 16,
+                };
+            }
+            // WRITE
+            0b01 => self.state = EepromState::Write { addr: Some(addr) },
+            // ERASE
+            0b11 => {
+                self.set_word(addr, 0xFFFF);
+                self.do_ = true;
+            }
+            // The rest share opcode 00 and pick one with the top two
+            // address bits
+            _ => match operand >> 6 {
+                0b00 => self.write_enabled = false,
+                0b01 => self.state = EepromState::Write { addr: None },
+                0b10 => {
+                    (0..0x80).for_each(|addr| self.set_word(addr, 0xFFFF));
+                    self.do_ = true;
+                }
+                _ => self.write_enabled = true,
+            },
+        }
+    }
+}
+
+impl SaveState for Eeprom {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.data);
+        w.write_u8(self.read_pins());
+        w.write_u16(self.shift);
+        w.write_u8(self.bits);
+        w.write_bool(self.write_enabled);
+        match self.state {
+            EepromState::Idle => w.write_u8(0),
+            EepromState::Command => w.write_u8(1),
+            EepromState::Read { addr, data, bits } => {
+                w.write_u8(2);
+                w.write_u8(addr);
+                w.write_u16(data);
+                w.write_u8(bits);
+            }
+            EepromState::Write { addr } => {
+                w.write_u8(3);
+                w.write_u8(addr.unwrap_or(0xFF));
+            }
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        r.read_into(&mut self.data)?;
+        let pins = r.read_u8()?;
+        self.cs = pins & 0x80 != 0;
+        self.clk = pins & 0x40 != 0;
+        self.di = pins & 0x02 != 0;
+        self.do_ = pins & 0x01 != 0;
+        self.shift = r.read_u16()?;
+        self.bits = r.read_u8()?;
+        self.write_enabled = r.read_bool()?;
+        self.state = match r.read_u8()? {
+            0 => EepromState::Idle,
+            1 => EepromState::Command,
+            2 => EepromState::Read {
+                addr: r.read_u8()? & 0x7F,
+                data: r.read_u16()?,
+                bits: r.read_u8()?,
+            },
+            3 => EepromState::Write {
+                addr: match r.read_u8()? {
+                    0xFF => None,
+                    addr => Some(addr & 0x7F),
+                },
+            },
+            _ => return Err(StateError::Invalid("EEPROM state")),
+        };
+        Ok(())
+    }
+}
+
+/// Clock `bits` (MSB first) into the EEPROM with CS held high.
+#[cfg(test)]
+fn send(eeprom: &mut Eeprom, bits: u32, count: u32) {
+    for bit in (0..count).rev() {
+        let di = if bits >> bit & 1 != 0 { 0x02 } else { 0x00 };
+        eeprom.write_pins(0x80 | di);
+        eeprom.write_pins(0xC0 | di);
+    }
+}
+
+/// Start bit, opcode and the eight address bits.
+#[cfg(test)]
+fn command(eeprom: &mut Eeprom, opcode: u32, operand: u32) {
+    send(eeprom, 0x400 | opcode << 8 | operand, 11);
+}
+
+#[cfg(test)]
+fn receive(eeprom: &mut Eeprom, count: u32) -> u32 {
+    (0..count).fold(0, |word, _| {
+        eeprom.write_pins(0x80);
+        eeprom.write_pins(0xC0);
+        word << 1 | (eeprom.read_pins() & 0x1) as u32
+    })
+}
+
+#[test]
+fn eeprom_commands() {
+    let mut eeprom = Eeprom::new();
+    // Writes are ignored until EWEN
+    command(&mut eeprom, 0b01, 0x05);
+    send(&mut eeprom, 0x1234, 16);
+    eeprom.write_pins(0x00);
+    assert_eq!(eeprom.word(5), 0xFFFF);
+
+    command(&mut eeprom, 0b00, 0xC0);
+    eeprom.write_pins(0x00);
+    command(&mut eeprom, 0b01, 0x05);
+    send(&mut eeprom, 0x1234, 16);
+    eeprom.write_pins(0x00);
+    assert_eq!(eeprom.word(5), 0x1234);
+    assert_eq!(&eeprom.data()[10..12], &[0x34, 0x12]);
+
+    // READ: dummy bit, the word, then the next word
+    command(&mut eeprom, 0b10, 0x05);
+    assert_eq!(eeprom.read_pins() & 0x1, 0);
+    assert_eq!(receive(&mut eeprom, 16), 0x1234);
+    assert_eq!(receive(&mut eeprom, 16), 0xFFFF);
+    eeprom.write_pins(0x00);
+
+    // ERAL, then WRAL
+    command(&mut eeprom, 0b00, 0x80);
+    eeprom.write_pins(0x00);
+    assert_eq!(eeprom.word(5), 0xFFFF);
+    command(&mut eeprom, 0b00, 0x40);
+    send(&mut eeprom, 0xABCD, 16);
+    eeprom.write_pins(0x00);
+    assert!((0..0x80).all(|addr| eeprom.word(addr) == 0xABCD));
+
+    // ERASE, then EWDS locks it again
+    command(&mut eeprom, 0b11, 0x01);
+    eeprom.write_pins(0x00);
+    assert_eq!(eeprom.word(1), 0xFFFF);
+    command(&mut eeprom, 0b00, 0x00);
+    eeprom.write_pins(0x00);
+    command(&mut eeprom, 0b11, 0x02);
+    eeprom.write_pins(0x00);
+    assert_eq!(eeprom.word(2), 0xABCD);
+}
@@ -0,0 +1,318 @@
+mod error;
+
+pub use self::error::{HeaderError, HeaderResult};
+
+/// Offset just past the end of the header.
+pub const HEADER_END: usize = 0x150;
+
+/// The logo at 0x104 the boot ROM checks before starting a game.
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mapper {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1,
+}
+
+/// The cartridge type byte (0x147), split into the mapper and the extra
+/// hardware on the board.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub mapper: Mapper,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+    pub sensor: bool,
+}
+
+impl CartridgeType {
+    pub fn from_code(code: u8) -> HeaderResult<CartridgeType> {
+        use self::Mapper::*;
+        let (mapper, ram, battery, timer, rumble) = match code {
+            0x00 => (RomOnly, false, false, false, false),
+            0x01 => (Mbc1, false, false, false, false),
+            0x02 => (Mbc1, true, false, false, false),
+            0x03 => (Mbc1, true, true, false, false),
+            0x05 => (Mbc2, false, false, false, false),
+            0x06 => (Mbc2, false, true, false, false),
+            0x08 => (RomOnly, true, false, false, false),
+            0x09 => (RomOnly, true, true, false, false),
+            0x0B => (Mmm01, false, false, false, false),
+            0x0C => (Mmm01, true, false, false, false),
+            0x0D => (Mmm01, true, true, false, false),
+            0x0F => (Mbc3, false, true, true, false),
+            0x10 => (Mbc3, true, true, true, false),
+            0x11 => (Mbc3, false, false, false, false),
+            0x12 => (Mbc3, true, false, false, false),
+            0x13 => (Mbc3, true, true, false, false),
+            0x19 => (Mbc5, false, false, false, false),
+            0x1A => (Mbc5, true, false, false, false),
+            0x1B => (Mbc5, true, true, false, false),
+            0x1C => (Mbc5, false, false, false, true),
+            0x1D => (Mbc5, true, false, false, true),
+            0x1E => (Mbc5, true, true, false, true),
+            0x20 => (Mbc6, true, true, false, false),
+            0x22 => (Mbc7, true, true, false, true),
+            0xFC => (PocketCamera, true, true, false, false),
+            0xFD => (Tama5, true, true, true, false),
+            0xFE => (HuC3, true, true, true, false),
+            0xFF => (HuC1, true, true, false, false),
+            _ => return Err(HeaderError::UnknownCartridgeType(code)),
+        };
+        Ok(CartridgeType {
+            code,
+            mapper,
+            ram,
+            battery,
+            timer,
+            rumble,
+            // MBC7 has an accelerometer
+            sensor: mapper == Mbc7,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CgbSupport {
+    /// DMG only game.
+    None,
+    /// Runs on both, with CGB enhancements.
+    Compatible,
+    Only,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Licensee {
+    /// Old licensee code at 0x14B.
+    Old(u8),
+    /// Two ASCII characters at 0x144-0x145, used when 0x14B is 0x33.
+    New([u8; 2]),
+}
+
+/// The cartridge header at 0x0100-0x014F.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CartridgeHeader {
+    pub title: String,
+    /// Four character code on later games; None on older ones where those
+    /// bytes are part of the title.
+    pub manufacturer: Option<String>,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub cartridge_type: CartridgeType,
+    /// ROM size in bytes.
+    pub rom_size: usize,
+    /// External RAM size in bytes, not counting RAM built into the mapper.
+    pub ram_size: usize,
+    pub licensee: Licensee,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+/// ROM size from the 0x148 code.
+pub fn rom_size(code: u8) -> HeaderResult<usize> {
+    match code {
+        0x00..=0x08 => Ok(0x8000 << code),
+        // Only ever listed in unofficial docs; no known game uses them
+        0x52 => Ok(72 * 0x4000),
+        0x53 => Ok(80 * 0x4000),
+        0x54 => Ok(96 * 0x4000),
+        _ => Err(HeaderError::UnknownRomSize(code)),
+    }
+}
+
+/// External RAM size from the 0x149 code.
+pub fn ram_size(code: u8) -> HeaderResult<usize> {
+    match code {
+        0x00 => Ok(0),
+        // Unofficial, listed by some old docs and used by a few homebrew
+        0x01 => Ok(0x800),
+        0x02 => Ok(0x2000),
+        0x03 => Ok(0x8000),
+        0x04 => Ok(0x20000),
+        0x05 => Ok(0x10000),
+        _ => Err(HeaderError::UnknownRamSize(code)),
+    }
+}
+
+/// The checksum the boot ROM verifies over 0x134-0x14C.
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x134..=0x14C]
+        .iter()
+        .fold(0u8, |sum, b| sum.wrapping_sub(*b).wrapping_sub(1))
+}
+
+/// Sum of every byte in the ROM except the checksum itself.
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|(idx, _)| *idx != 0x14E && *idx != 0x14F)
+        .fold(0u16, |sum, (_, b)| sum.wrapping_add(*b as u16))
+}
+
+fn ascii(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|b| **b != 0)
+        .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '?' })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> HeaderResult<CartridgeHeader> {
+        if rom.len() < HEADER_END {
+            return Err(HeaderError::TooShort(rom.len()));
+        }
+
+        let expected = header_checksum(rom);
+        if rom[0x14D] != expected {
+            return Err(HeaderError::BadHeaderChecksum {
+                expected,
+                actual: rom[0x14D],
+            });
+        }
+
+        let cgb = match rom[0x143] {
+            0xC0 => CgbSupport::Only,
+            flag if flag & 0x80 != 0 => CgbSupport::Compatible,
+            _ => CgbSupport::None,
+        };
+        // The title shrank to make room for the manufacturer code and CGB
+        // flag. A manufacturer code is only there if it's four uppercase
+        // letters or digits on a CGB game.
+        let code = &rom[0x13F..0x143];
+        let has_manufacturer = cgb != CgbSupport::None
+            && code.iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit());
+        let (title, manufacturer) = match (cgb, has_manufacturer) {
+            (CgbSupport::None, _) => (ascii(&rom[0x134..0x144]), None),
+            (_, false) => (ascii(&rom[0x134..0x143]), None),
+            (_, true) => (ascii(&rom[0x134..0x13F]), Some(ascii(code))),
+        };
+
+        let licensee = match rom[0x14B] {
+            0x33 => Licensee::New([rom[0x144], rom[0x145]]),
+            code => Licensee::Old(code),
+        };
+
+        let header = CartridgeHeader {
+            title,
+            manufacturer,
+            cgb,
+            // The SGB functions are ignored unless the old licensee is 0x33
+            sgb: rom[0x146] == 0x03 && rom[0x14B] == 0x33,
+            cartridge_type: CartridgeType::from_code(rom[0x147])?,
+            rom_size: rom_size(rom[0x148])?,
+            ram_size: ram_size(rom[0x149])?,
+            licensee,
+            version: rom[0x14C],
+            header_checksum: rom[0x14D],
+            global_checksum: (rom[0x14E] as u16) << 8 | rom[0x14F] as u16,
+        };
+
+        if rom.len() < header.rom_size {
+            return Err(HeaderError::RomSizeMismatch {
+                expected: header.rom_size,
+                actual: rom.len(),
+            });
+        }
+        Ok(header)
+    }
+
+    pub fn mapper(&self) -> Mapper {
+        self.cartridge_type.mapper
+    }
+
+    /// The global checksum isn't checked by hardware, so a mismatch is
+    /// only worth a warning.
+    pub fn global_checksum_ok(&self, rom: &[u8]) -> bool {
+        global_checksum(rom) == self.global_checksum
+    }
+}
+
+/// Builds a minimal ROM with a valid header, for tests.
+#[cfg(test)]
+pub fn test_rom(cartridge_type: u8, rom_code: u8, ram_code: u8) -> Vec<u8> {
+    let mut rom = vec![0u8; rom_size(rom_code).unwrap()];
+    rom[0x134..0x134 + 4].copy_from_slice(b"TEST");
+    rom[0x147] = cartridge_type;
+    rom[0x148] = rom_code;
+    rom[0x149] = ram_code;
+    rom[0x14D] = header_checksum(&rom);
+    rom
+}
+
+#[test]
+fn header_parse() {
+    let mut rom = test_rom(0x13, 0x02, 0x03);
+    rom[0x134..0x143].copy_from_slice(b"POKEMON_SLVAAXE");
+    rom[0x13F..0x143].copy_from_slice(b"AAXE");
+    rom[0x143] = 0x80;
+    rom[0x144..0x146].copy_from_slice(b"01");
+    rom[0x146] = 0x03;
+    rom[0x14B] = 0x33;
+    rom[0x14C] = 0x01;
+    rom[0x14D] = header_checksum(&rom);
+    let sum = global_checksum(&rom);
+    rom[0x14E] = (sum >> 8) as u8;
+    rom[0x14F] = sum as u8;
+
+    let header = CartridgeHeader::parse(&rom).unwrap();
+    assert_eq!(header.title, "POKEMON_SLV");
+    assert_eq!(header.manufacturer.as_deref(), Some("AAXE"));
+    assert_eq!(header.cgb, CgbSupport::Compatible);
+    assert!(header.sgb);
+    assert_eq!(header.mapper(), Mapper::Mbc3);
+    assert!(header.cartridge_type.battery && !header.cartridge_type.timer);
+    assert_eq!(header.rom_size, 128 * 1024);
+    assert_eq!(header.ram_size, 32 * 1024);
+    assert_eq!(header.licensee, Licensee::New(*b"01"));
+    assert_eq!(header.version, 1);
+    assert!(header.global_checksum_ok(&rom));
+}
+
+#[test]
+fn header_errors() {
+    assert_eq!(CartridgeHeader::parse(&[0u8; 0x100]), Err(HeaderError::TooShort(0x100)));
+
+    let mut rom = test_rom(0x00, 0x00, 0x00);
+    rom[0x14D] ^= 1;
+    assert!(matches!(
+        CartridgeHeader::parse(&rom),
+        Err(HeaderError::BadHeaderChecksum { .. })
+    ));
+
+    let rom = test_rom(0x04, 0x00, 0x00);
+    assert_eq!(CartridgeHeader::parse(&rom), Err(HeaderError::UnknownCartridgeType(0x04)));
+
+    let rom = test_rom(0x00, 0x00, 0x07);
+    assert_eq!(CartridgeHeader::parse(&rom), Err(HeaderError::UnknownRamSize(0x07)));
+
+    let mut rom = test_rom(0x01, 0x00, 0x00);
+    rom[0x148] = 0x01;
+    rom[0x14D] = header_checksum(&rom);
+    assert_eq!(
+        CartridgeHeader::parse(&rom),
+        Err(HeaderError::RomSizeMismatch {
+            expected: 0x10000,
+            actual: 0x8000
+        })
+    );
+}
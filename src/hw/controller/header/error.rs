@@ -0,0 +1,48 @@
+use std::error;
+use std::fmt;
+
+use crate::hw::controller::header::Mapper;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The ROM is too small to hold a header at all.
+    TooShort(usize),
+    BadHeaderChecksum { expected: u8, actual: u8 },
+    UnknownCartridgeType(u8),
+    UnknownRomSize(u8),
+    UnknownRamSize(u8),
+    /// The file holds less ROM than the header declares.
+    RomSizeMismatch { expected: usize, actual: usize },
+    /// A valid cartridge type that has no mapper implementation yet.
+    UnsupportedMapper(Mapper),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderError::TooShort(len) => {
+                write!(f, "ROM is only {} bytes, too short for a cartridge header", len)
+            }
+            HeaderError::BadHeaderChecksum { expected, actual } => write!(
+                f,
+                "Header checksum is {:02x}, expected {:02x}",
+                actual, expected
+            ),
+            HeaderError::UnknownCartridgeType(t) => write!(f, "Unknown cartridge type {:02x}", t),
+            HeaderError::UnknownRomSize(s) => write!(f, "Unknown ROM size code {:02x}", s),
+            HeaderError::UnknownRamSize(s) => write!(f, "Unknown RAM size code {:02x}", s),
+            HeaderError::RomSizeMismatch { expected, actual } => write!(
+                f,
+                "Header declares {} bytes of ROM but the file has {}",
+                expected, actual
+            ),
+            HeaderError::UnsupportedMapper(mapper) => {
+                write!(f, "Cartridges using {:?} aren't supported", mapper)
+            }
+        }
+    }
+}
+
+impl error::Error for HeaderError {}
+
+pub type HeaderResult<T> = Result<T, HeaderError>;
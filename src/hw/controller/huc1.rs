@@ -0,0 +1,184 @@
+use crate::hw::controller::infrared::{IrPort, NoIrPort};
+use crate::hw::controller::{header, BankState, Cartridge, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::hw::memory::{Bus, BusWidth};
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
+
+/// Hudson HuC1: MBC1 style banking, with an infrared LED and receiver that
+/// can be switched in over the RAM area.
+pub struct HuC1 {
+    rom: Vec<u8>,
+    rom_bank_num: u8,
+    ram: Vec<u8>,
+    ram_bank_num: u8,
+    /// Set by writing 0E to 0000-1FFF; anything else selects RAM.
+    ir_mode: bool,
+    led: bool,
+    ir: Box<dyn IrPort>,
+}
+
+impl HuC1 {
+    pub fn new(rom: Vec<u8>) -> Box<dyn Cartridge> {
+        let ramsize = rom
+            .get(0x149)
+            .and_then(|code| header::ram_size(*code).ok())
+            .map_or(0, |size| size.min(RAM_BANK_SIZE * 4));
+        Box::new(HuC1 {
+            rom,
+            rom_bank_num: 1,
+            ram: vec![0u8; ramsize],
+            ram_bank_num: 0,
+            ir_mode: false,
+            led: false,
+            ir: Box::new(NoIrPort),
+        })
+    }
+
+    fn rom_bank(&self) -> usize {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        self.rom_bank_num as usize % banks
+    }
+
+    fn ram_index(&self, addr: BusWidth) -> Option<usize> {
+        if self.ir_mode || self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_bank_num as usize * RAM_BANK_SIZE + (addr - 0xA000) as usize;
+        Some(offset % self.ram.len())
+    }
+}
+
+impl Bus for HuC1 {
+    fn write8(&mut self, addr: BusWidth, data: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ir_mode = data == 0x0E,
+            0x2000..=0x3FFF => {
+                self.rom_bank_num = match data & 0x3F {
+                    0 => 1,
+                    bank => bank,
+                };
+            }
+            0x4000..=0x5FFF => self.ram_bank_num = data & 0x3,
+            0xA000..=0xBFFF if self.ir_mode => {
+                self.led = data & 0x1 != 0;
+                self.ir.set_led(self.led);
+            }
+            0xA000..=0xBFFF => {
+                if let Some(idx) = self.ram_index(addr) {
+                    self.ram[idx] = data;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn read8(&self, addr: BusWidth) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom.get(addr as usize).cloned().unwrap_or(0xFF),
+            0x4000..=0x7FFF => {
+                let offset = self.rom_bank() * ROM_BANK_SIZE + (addr - 0x4000) as usize;
+                self.rom.get(offset).cloned().unwrap_or(0xFF)
+            }
+            0xA000..=0xBFFF if self.ir_mode => 0xC0 | self.ir.receiving() as u8,
+            0xA000..=0xBFFF => self.ram_index(addr).map_or(0xFF, |idx| self.ram[idx]),
+            _ => 0xFF,
+        }
+    }
+
+    fn direct_page(&self, addr: BusWidth) -> Option<&[u8]> {
+        let page_start = self.rom_offset(addr)? & !0xFF;
+        self.rom.get(page_start..page_start + 0x100)
+    }
+
+    fn write16(&mut self, addr: BusWidth, data: u16) {
+        self._write16_using_write8(addr, data);
+    }
+
+    fn read16(&self, addr: BusWidth) -> u16 {
+        self._read16_using_read8(addr)
+    }
+}
+
+impl Cartridge for HuC1 {
+    fn bank_state(&self) -> BankState {
+        BankState {
+            rom0: 0,
+            romx: self.rom_bank() as u16,
+            ram: self.ram_bank_num as u16,
+            ram_enabled: !self.ir_mode,
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_offset(&self, addr: BusWidth) -> Option<usize> {
+        match addr {
+            0xA000..=0xBFFF => self.ram_index(addr),
+            _ => None,
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        true
+    }
+
+    fn connect_ir(&mut self, mut port: Box<dyn IrPort>) -> bool {
+        port.set_led(self.led);
+        self.ir = port;
+        true
+    }
+}
+
+impl SaveState for HuC1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rom_bank_num);
+        w.write_bytes(&self.ram);
+        w.write_u8(self.ram_bank_num);
+        w.write_bool(self.ir_mode);
+        w.write_bool(self.led);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.rom_bank_num = r.read_u8()? & 0x3F;
+        r.read_into(&mut self.ram)?;
+        self.ram_bank_num = r.read_u8()? & 0x3;
+        self.ir_mode = r.read_bool()?;
+        self.led = r.read_bool()?;
+        self.ir.set_led(self.led);
+        Ok(())
+    }
+}
+
+#[test]
+fn huc1_ir_and_ram() {
+    use crate::hw::controller::infrared::ir_link;
+
+    let mut rom = vec![0u8; ROM_BANK_SIZE * 4];
+    rom[0x149] = 0x03;
+    let mut huc1 = HuC1::new(rom);
+    let (port, mut other) = ir_link();
+    assert!(huc1.connect_ir(Box::new(port)));
+
+    huc1.write8(0x4000, 0x01);
+    huc1.write8(0xA000, 0x12);
+    assert_eq!(huc1.ram()[RAM_BANK_SIZE], 0x12);
+
+    huc1.write8(0x0000, 0x0E);
+    assert_eq!(huc1.read8(0xA000), 0xC0);
+    other.set_led(true);
+    assert_eq!(huc1.read8(0xA000), 0xC1);
+    huc1.write8(0xA000, 0x01);
+    assert!(other.receiving());
+
+    huc1.write8(0x0000, 0x0A);
+    assert_eq!(huc1.read8(0xA000), 0x12);
+}
@@ -0,0 +1,477 @@
+use crate::hw::controller::infrared::{IrPort, NoIrPort};
+use crate::hw::controller::rtc::{footer_last_sync, footer_timestamp, RtcClock, WallClock};
+use crate::hw::controller::{header, BankState, Cartridge, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::hw::memory::{Bus, BusWidth};
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
+
+/// Size of the clock block appended to battery RAM in save files: a u64
+/// UNIX timestamp, then minutes, days, alarm minutes and alarm days as
+/// little endian u16s, then the alarm enable.
+pub const HUC3_FOOTER_SIZE: usize = 17;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// The HuC3 clock. It counts minutes of the day and days, and is read and
+/// set a nibble at a time through the command interface.
+struct HuC3Clock {
+    minutes: u16,
+    days: u16,
+    /// Seconds into the current minute. Not visible to software.
+    seconds: u8,
+    alarm_minutes: u16,
+    alarm_days: u16,
+    alarm_enabled: bool,
+    clock: Box<dyn RtcClock>,
+    last_sync: u64,
+    cycles: u64,
+}
+
+impl HuC3Clock {
+    fn new(clock: Box<dyn RtcClock>) -> HuC3Clock {
+        let last_sync = clock.now(0);
+        HuC3Clock {
+            minutes: 0,
+            days: 0,
+            seconds: 0,
+            alarm_minutes: 0,
+            alarm_days: 0,
+            alarm_enabled: false,
+            clock,
+            last_sync,
+            cycles: 0,
+        }
+    }
+
+    fn sync(&mut self) {
+        let now = self.clock.now(self.cycles);
+        let elapsed = now.saturating_sub(self.last_sync);
+        self.last_sync = now;
+        self.advance(elapsed);
+    }
+
+    fn advance(&mut self, elapsed: u64) {
+        let seconds = self.seconds as u64 + elapsed;
+        self.seconds = (seconds % 60) as u8;
+        // Minutes written out of range count up to the register width
+        // before the day rolls over
+        let minutes = self.minutes as u64 + seconds / 60;
+        let (days, minutes) = if self.minutes < MINUTES_PER_DAY {
+            (minutes / MINUTES_PER_DAY as u64, minutes % MINUTES_PER_DAY as u64)
+        } else {
+            (0, minutes & 0xFFF)
+        };
+        self.minutes = minutes as u16;
+        self.days = self.days.wrapping_add(days as u16);
+    }
+
+    /// Nibble `index` of the clock registers, as addressed by commands 1-3.
+    fn read(&self, index: u8) -> u8 {
+        match index {
+            0x00..=0x02 => (self.minutes >> (index * 4)) as u8 & 0xF,
+            0x03..=0x06 => (self.days >> ((index - 3) * 4)) as u8 & 0xF,
+            0x58..=0x5A => (self.alarm_minutes >> ((index - 0x58) * 4)) as u8 & 0xF,
+            0x5B..=0x5E => (self.alarm_days >> ((index - 0x5B) * 4)) as u8 & 0xF,
+            0x5F => self.alarm_enabled as u8,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, index: u8, data: u8) {
+        fn set_nibble(reg: &mut u16, nibble: u8, data: u8) {
+            let shift = nibble * 4;
+            *reg = (*reg & !(0xF << shift)) | ((data & 0xF) as u16) << shift;
+        }
+        match index {
+            0x00..=0x02 => set_nibble(&mut self.minutes, index, data),
+            0x03..=0x06 => set_nibble(&mut self.days, index - 3, data),
+            0x58..=0x5A => set_nibble(&mut self.alarm_minutes, index - 0x58, data),
+            0x5B..=0x5E => set_nibble(&mut self.alarm_days, index - 0x5B, data),
+            0x5F => self.alarm_enabled = data & 0x1 != 0,
+            _ => (),
+        }
+    }
+
+    fn footer(&mut self) -> [u8; HUC3_FOOTER_SIZE] {
+        self.sync();
+        let mut footer = [0u8; HUC3_FOOTER_SIZE];
+        let timestamp = footer_timestamp(&*self.clock, self.cycles);
+        footer[0..8].copy_from_slice(&timestamp.to_le_bytes());
+        footer[8..10].copy_from_slice(&self.minutes.to_le_bytes());
+        footer[10..12].copy_from_slice(&self.days.to_le_bytes());
+        footer[12..14].copy_from_slice(&self.alarm_minutes.to_le_bytes());
+        footer[14..16].copy_from_slice(&self.alarm_days.to_le_bytes());
+        footer[16] = self.alarm_enabled as u8;
+        footer
+    }
+
+    fn load_footer(&mut self, data: &[u8]) -> bool {
+        if data.len() != HUC3_FOOTER_SIZE {
+            return false;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        self.minutes = u16_at(8) & 0xFFF;
+        self.days = u16_at(10);
+        self.alarm_minutes = u16_at(12) & 0xFFF;
+        self.alarm_days = u16_at(14);
+        self.alarm_enabled = data[16] & 0x1 != 0;
+        self.seconds = 0;
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&data[0..8]);
+        self.last_sync = footer_last_sync(&*self.clock, self.cycles, u64::from_le_bytes(timestamp));
+        self.sync();
+        true
+    }
+}
+
+/// Hudson HuC3: ROM/RAM banking, a clock and a speaker behind a nibble
+/// wide command interface, and the same infrared port as the HuC1.
+///
+/// The register at 0000-1FFF picks what A000-BFFF is connected to.
+pub struct HuC3 {
+    rom: Vec<u8>,
+    rom_bank_num: u8,
+    ram: Vec<u8>,
+    ram_bank_num: u8,
+    mode: u8,
+    clock: HuC3Clock,
+    /// Nibble of the clock registers commands 1-3 work on.
+    access_index: u8,
+    /// Last command and its result, read back in mode 0C.
+    command: u8,
+    result: u8,
+    /// Set by command 6. The speaker sounds while it's 0E.
+    speaker: u8,
+    led: bool,
+    ir: Box<dyn IrPort>,
+}
+
+impl HuC3 {
+    pub fn new(rom: Vec<u8>) -> Box<dyn Cartridge> {
+        let ramsize = rom
+            .get(0x149)
+            .and_then(|code| header::ram_size(*code).ok())
+            .unwrap_or(0);
+        Box::new(HuC3 {
+            rom,
+            rom_bank_num: 1,
+            ram: vec![0u8; ramsize],
+            ram_bank_num: 0,
+            mode: 0,
+            clock: HuC3Clock::new(Box::new(WallClock)),
+            access_index: 0,
+            command: 0,
+            result: 0,
+            speaker: 0,
+            led: false,
+            ir: Box::new(NoIrPort),
+        })
+    }
+
+    fn rom_bank(&self) -> usize {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        self.rom_bank_num as usize % banks
+    }
+
+    fn ram_index(&self, addr: BusWidth) -> Option<usize> {
+        if !matches!(self.mode, 0x0 | 0xA) || self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_bank_num as usize * RAM_BANK_SIZE + (addr - 0xA000) as usize;
+        Some(offset % self.ram.len())
+    }
+
+    /// Run a command written in mode 0B: the command in the upper nibble,
+    /// its argument in the lower.
+    fn execute(&mut self, data: u8) {
+        let arg = data & 0xF;
+        self.command = (data >> 4) & 0x7;
+        match self.command {
+            // Read the addressed nibble and move on
+            0x1 => {
+                self.clock.sync();
+                self.result = self.clock.read(self.access_index);
+                self.access_index = self.access_index.wrapping_add(1);
+            }
+            // Write the addressed nibble, and move on for 3
+            0x2 | 0x3 => {
+                self.clock.sync();
+                self.clock.write(self.access_index, arg);
+                if self.command == 0x3 {
+                    self.access_index = self.access_index.wrapping_add(1);
+                }
+            }
+            0x4 => self.access_index = (self.access_index & 0xF0) | arg,
+            0x5 => self.access_index = (self.access_index & 0x0F) | arg << 4,
+            0x6 => self.speaker = arg,
+            _ => (),
+        }
+    }
+}
+
+impl Bus for HuC3 {
+    fn write8(&mut self, addr: BusWidth, data: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.mode = data & 0xF,
+            0x2000..=0x3FFF => {
+                self.rom_bank_num = match data & 0x7F {
+                    0 => 1,
+                    bank => bank,
+                };
+            }
+            0x4000..=0x5FFF => self.ram_bank_num = data & 0xF,
+            0xA000..=0xBFFF => match self.mode {
+                0xA => {
+                    if let Some(idx) = self.ram_index(addr) {
+                        self.ram[idx] = data;
+                    }
+                }
+                0xB => self.execute(data),
+                0xE => {
+                    self.led = data & 0x1 != 0;
+                    self.ir.set_led(self.led);
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    fn read8(&self, addr: BusWidth) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom.get(addr as usize).cloned().unwrap_or(0xFF),
+            0x4000..=0x7FFF => {
+                let offset = self.rom_bank() * ROM_BANK_SIZE + (addr - 0x4000) as usize;
+                self.rom.get(offset).cloned().unwrap_or(0xFF)
+            }
+            0xA000..=0xBFFF => match self.mode {
+                0x0 | 0xA => self.ram_index(addr).map_or(0xFF, |idx| self.ram[idx]),
+                0xC => self.command << 4 | self.result,
+                // Commands finish instantly, so the clock is always ready
+                0xD => 0x01,
+                0xE => 0xC0 | self.ir.receiving() as u8,
+                _ => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    fn direct_page(&self, addr: BusWidth) -> Option<&[u8]> {
+        let page_start = self.rom_offset(addr)? & !0xFF;
+        self.rom.get(page_start..page_start + 0x100)
+    }
+
+    fn write16(&mut self, addr: BusWidth, data: u16) {
+        self._write16_using_write8(addr, data);
+    }
+
+    fn read16(&self, addr: BusWidth) -> u16 {
+        self._read16_using_read8(addr)
+    }
+}
+
+impl Cartridge for HuC3 {
+    fn bank_state(&self) -> BankState {
+        BankState {
+            rom0: 0,
+            romx: self.rom_bank() as u16,
+            ram: self.ram_bank_num as u16,
+            ram_enabled: matches!(self.mode, 0x0 | 0xA),
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_offset(&self, addr: BusWidth) -> Option<usize> {
+        match addr {
+            0xA000..=0xBFFF => self.ram_index(addr),
+            _ => None,
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        true
+    }
+
+    fn save_data(&mut self) -> Option<Vec<u8>> {
+        let mut data = self.ram.clone();
+        data.extend_from_slice(&self.clock.footer());
+        Some(data)
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) -> bool {
+        if data.len() < self.ram.len() {
+            return false;
+        }
+        let (ram, footer) = data.split_at(self.ram.len());
+        self.ram.copy_from_slice(ram);
+        self.clock.load_footer(footer);
+        true
+    }
+
+    fn needs_tick(&self) -> bool {
+        true
+    }
+
+    fn set_clock(&mut self, clock: Box<dyn RtcClock>) {
+        self.clock.clock = clock;
+        self.clock.last_sync = self.clock.clock.now(self.clock.cycles);
+    }
+
+    fn tone(&self) -> Option<bool> {
+        Some(self.speaker == 0xE)
+    }
+
+    fn connect_ir(&mut self, mut port: Box<dyn IrPort>) -> bool {
+        port.set_led(self.led);
+        self.ir = port;
+        true
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.clock.cycles = cycles;
+    }
+}
+
+// The clock is saved with its seconds not yet counted, like the MBC3 RTC's
+impl SaveState for HuC3 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rom_bank_num);
+        w.write_bytes(&self.ram);
+        w.write_u8(self.ram_bank_num);
+        w.write_u8(self.mode);
+        w.write_u16(self.clock.minutes);
+        w.write_u16(self.clock.days);
+        w.write_u8(self.clock.seconds);
+        w.write_u16(self.clock.alarm_minutes);
+        w.write_u16(self.clock.alarm_days);
+        w.write_bool(self.clock.alarm_enabled);
+        w.write_u64(self.clock.clock.now(self.clock.cycles).saturating_sub(self.clock.last_sync));
+        w.write_u64(self.clock.cycles);
+        w.write_u8(self.access_index);
+        w.write_u8(self.command);
+        w.write_u8(self.result);
+        w.write_u8(self.speaker);
+        w.write_bool(self.led);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.rom_bank_num = r.read_u8()? & 0x7F;
+        r.read_into(&mut self.ram)?;
+        self.ram_bank_num = r.read_u8()? & 0xF;
+        self.mode = r.read_u8()? & 0xF;
+        self.clock.minutes = r.read_u16()? & 0xFFF;
+        self.clock.days = r.read_u16()?;
+        self.clock.seconds = r.read_u8()? % 60;
+        self.clock.alarm_minutes = r.read_u16()? & 0xFFF;
+        self.clock.alarm_days = r.read_u16()?;
+        self.clock.alarm_enabled = r.read_bool()?;
+        let pending = r.read_u64()?;
+        self.clock.cycles = r.read_u64()?;
+        self.clock.last_sync = self.clock.clock.now(self.clock.cycles);
+        self.clock.advance(pending);
+        self.access_index = r.read_u8()?;
+        self.command = r.read_u8()? & 0x7;
+        self.result = r.read_u8()? & 0xF;
+        self.speaker = r.read_u8()? & 0xF;
+        self.led = r.read_bool()?;
+        self.ir.set_led(self.led);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn huc3_command(huc3: &mut Box<dyn Cartridge>, command: u8) -> u8 {
+    huc3.write8(0x0000, 0x0B);
+    huc3.write8(0xA000, command);
+    huc3.write8(0x0000, 0x0D);
+    assert_eq!(huc3.read8(0xA000) & 0x1, 0x1);
+    huc3.write8(0x0000, 0x0C);
+    huc3.read8(0xA000)
+}
+
+#[test]
+fn huc3_clock_commands() {
+    use crate::hw::controller::rtc::{CycleClock, CPU_CLOCK_HZ};
+
+    let mut rom = vec![0u8; ROM_BANK_SIZE * 4];
+    rom[0x149] = 0x03;
+    let mut huc3 = HuC3::new(rom);
+    huc3.set_clock(Box::new(CycleClock));
+
+    // Set the time to 23:59 on day 0x0102
+    huc3_command(&mut huc3, 0x40);
+    huc3_command(&mut huc3, 0x50);
+    for nibble in &[0xF, 0x9, 0x5, 0x2, 0x0, 0x1, 0x0] {
+        huc3_command(&mut huc3, 0x30 | nibble);
+    }
+    huc3.tick(CPU_CLOCK_HZ * 61);
+
+    huc3_command(&mut huc3, 0x40);
+    let nibbles: Vec<u8> = (0..7).map(|_| huc3_command(&mut huc3, 0x10) & 0xF).collect();
+    assert_eq!(nibbles, vec![0x0, 0x0, 0x0, 0x3, 0x0, 0x1, 0x0]);
+    assert_eq!(huc3_command(&mut huc3, 0x10) >> 4, 0x1);
+
+    assert_eq!(huc3.tone(), Some(false));
+    huc3_command(&mut huc3, 0x6E);
+    assert_eq!(huc3.tone(), Some(true));
+
+    // RAM is read only outside mode 0A
+    huc3.write8(0x0000, 0x0A);
+    huc3.write8(0xA000, 0x42);
+    huc3.write8(0x0000, 0x00);
+    huc3.write8(0xA000, 0x17);
+    assert_eq!(huc3.read8(0xA000), 0x42);
+
+    let save = huc3.save_data().unwrap();
+    assert_eq!(save.len(), RAM_BANK_SIZE * 4 + HUC3_FOOTER_SIZE);
+    let mut rom = vec![0u8; ROM_BANK_SIZE * 4];
+    rom[0x149] = 0x03;
+    let mut restored = HuC3::new(rom);
+    restored.set_clock(Box::new(CycleClock));
+    assert!(restored.load_save_data(&save));
+    huc3_command(&mut restored, 0x43);
+    assert_eq!(huc3_command(&mut restored, 0x10) & 0xF, 0x3);
+}
+
+#[test]
+fn huc3_state_across_clocks() {
+    use crate::hw::controller::rtc::{CycleClock, CPU_CLOCK_HZ};
+
+    let mut rom = vec![0u8; ROM_BANK_SIZE * 4];
+    rom[0x149] = 0x03;
+    let mut huc3 = HuC3::new(rom.clone());
+    huc3.tick(CPU_CLOCK_HZ * 1_000_000);
+    huc3.set_clock(Box::new(CycleClock));
+    let mut w = StateWriter::new();
+    huc3.save_state(&mut w);
+    let state = w.into_bytes();
+
+    // A cycle clock state deep into a run doesn't jump ahead under the wall
+    // clock
+    let mut wall = HuC3::new(rom.clone());
+    wall.load_state(&mut StateReader::new(&state).unwrap()).unwrap();
+    huc3_command(&mut wall, 0x40);
+    let nibbles: Vec<u8> = (0..7).map(|_| huc3_command(&mut wall, 0x10) & 0xF).collect();
+    assert_eq!(nibbles, vec![0x0; 7]);
+
+    // And a wall clock state keeps running under the cycle clock
+    let mut w = StateWriter::new();
+    wall.save_state(&mut w);
+    let state = w.into_bytes();
+    let mut cycles = HuC3::new(rom);
+    cycles.set_clock(Box::new(CycleClock));
+    cycles.load_state(&mut StateReader::new(&state).unwrap()).unwrap();
+    cycles.tick(CPU_CLOCK_HZ * 1_000_000 + CPU_CLOCK_HZ * 120);
+    huc3_command(&mut cycles, 0x40);
+    assert_eq!(huc3_command(&mut cycles, 0x10) & 0xF, 0x2);
+}
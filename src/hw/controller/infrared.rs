@@ -0,0 +1,151 @@
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+
+/// One end of an infrared link: an LED the cartridge drives and a
+/// receiver it reads.
+pub trait IrPort {
+    fn set_led(&mut self, on: bool);
+
+    /// True while light from the other end is being received.
+    fn receiving(&self) -> bool;
+}
+
+/// Nothing on the other end.
+pub struct NoIrPort;
+
+impl IrPort for NoIrPort {
+    fn set_led(&mut self, _on: bool) {}
+
+    fn receiving(&self) -> bool {
+        false
+    }
+}
+
+/// One end of a pair made by `ir_link`. Each end receives what the other
+/// one sends.
+pub struct LinkedIrPort {
+    send: Rc<Cell<bool>>,
+    receive: Rc<Cell<bool>>,
+}
+
+/// Two connected ports, for two cartridges in one thread or talking to a
+/// cartridge from a test. `TcpIrPort` links separate emulators.
+pub fn ir_link() -> (LinkedIrPort, LinkedIrPort) {
+    let a = Rc::new(Cell::new(false));
+    let b = Rc::new(Cell::new(false));
+    (
+        LinkedIrPort {
+            send: a.clone(),
+            receive: b.clone(),
+        },
+        LinkedIrPort {
+            send: b,
+            receive: a,
+        },
+    )
+}
+
+impl IrPort for LinkedIrPort {
+    fn set_led(&mut self, on: bool) {
+        self.send.set(on);
+    }
+
+    fn receiving(&self) -> bool {
+        self.receive.get()
+    }
+}
+
+/// One end of a link to another emulator over TCP, sent a byte whenever
+/// the LED changes. The two emulators don't run in lockstep, so games see
+/// the link's latency as a slow partner.
+pub struct TcpIrPort {
+    stream: TcpStream,
+    led: bool,
+    receiving: Cell<bool>,
+}
+
+impl TcpIrPort {
+    /// Wait for the other emulator to connect to `addr`.
+    pub fn listen(addr: &str) -> io::Result<TcpIrPort> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        TcpIrPort::new(stream)
+    }
+
+    pub fn connect(addr: &str) -> io::Result<TcpIrPort> {
+        TcpIrPort::new(TcpStream::connect(addr)?)
+    }
+
+    fn new(stream: TcpStream) -> io::Result<TcpIrPort> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        Ok(TcpIrPort {
+            stream,
+            led: false,
+            receiving: Cell::new(false),
+        })
+    }
+}
+
+impl IrPort for TcpIrPort {
+    fn set_led(&mut self, on: bool) {
+        if on != self.led {
+            self.led = on;
+            // A dropped link just goes dark on the other end
+            let _ = (&self.stream).write_all(&[on as u8]);
+        }
+    }
+
+    fn receiving(&self) -> bool {
+        let mut buf = [0u8; 64];
+        loop {
+            match (&self.stream).read(&mut buf) {
+                Ok(0) => {
+                    self.receiving.set(false);
+                    break;
+                }
+                Ok(len) => self.receiving.set(buf[len - 1] != 0),
+                // Nothing new, or the link is gone
+                Err(_) => break,
+            }
+        }
+        self.receiving.get()
+    }
+}
+
+#[test]
+fn tcp_ir_port_sends_led_changes() {
+    use std::time::{Duration, Instant};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut a = TcpIrPort::connect(&listener.local_addr().unwrap().to_string()).unwrap();
+    let mut b = TcpIrPort::new(listener.accept().unwrap().0).unwrap();
+    let wait_for = |port: &TcpIrPort, on: bool| {
+        let start = Instant::now();
+        while port.receiving() != on {
+            assert!(start.elapsed() < Duration::from_secs(5));
+        }
+    };
+    a.set_led(true);
+    wait_for(&b, true);
+    assert!(!a.receiving());
+    b.set_led(true);
+    a.set_led(false);
+    wait_for(&a, true);
+    wait_for(&b, false);
+    drop(b);
+    wait_for(&a, false);
+}
+
+#[test]
+fn ir_link_connects_both_ends() {
+    let (mut a, mut b) = ir_link();
+    a.set_led(true);
+    assert!(b.receiving());
+    assert!(!a.receiving());
+    b.set_led(true);
+    a.set_led(false);
+    assert!(a.receiving());
+    assert!(!b.receiving());
+}
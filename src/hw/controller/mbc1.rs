@@ -1,99 +1,159 @@
-use crate::hw::memory::{BusWidth, Bus};
+use crate::hw::controller::header::{self, NINTENDO_LOGO};
+use crate::hw::controller::{BankState, Cartridge, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::hw::memory::{Bus, BusWidth};
+use crate::savestate::{SaveState, StateError, StateReader, StateResult, StateWriter};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum BankingMode {
+    /// The upper bank bits only apply to 4000-7FFF.
+    Simple,
+    /// The upper bank bits also switch 0000-3FFF and the RAM bank.
+    Advanced,
+}
 
 pub struct MBC1 {
     rom: Vec<u8>,
+    /// BANK1 register, five bits, never 0.
     rom_bank_num: u8,
     ram: Vec<u8>,
     ram_enable: bool,
-    ram_bank_num: u8, // also the upper bits of rom bank num 
-                      // if mode select is 0
-    mode_select: u8, // TODO make enum?
+    /// BANK2 register, two bits. Upper ROM bank bits, or the RAM bank in
+    /// advanced mode.
+    ram_bank_num: u8,
+    mode: BankingMode,
+    /// MBC1M multicarts only wire up four bits of BANK1, so BANK2 picks
+    /// one of four 256KiB games.
+    multicart: bool,
+    battery: bool,
+}
+
+/// MBC1M carts are 1MiB and have a game with its own header, logo
+/// included, every 16 banks. Regular 1MiB MBC1 games don't repeat the logo.
+fn is_multicart(rom: &[u8]) -> bool {
+    rom.len() == 64 * ROM_BANK_SIZE
+        && (1..4).all(|game| {
+            let logo = game * 16 * ROM_BANK_SIZE + 0x104;
+            rom[logo..logo + NINTENDO_LOGO.len()] == NINTENDO_LOGO
+        })
 }
 
 impl MBC1 {
-    pub fn new(rom: Vec<u8>) -> Box<dyn Bus> {
-        let ramsize = match rom[0x149] {
-            0x0 => 0,
-            0x1 => 2048,
-            0x2 => 8192,
-            0x3 => 32768,
-            _ => panic!("invalid ram size!"),
-        };
+    pub fn new(rom: Vec<u8>) -> Box<dyn Cartridge> {
+        // MBC1 boards carry at most 32KiB of RAM
+        let ramsize = rom
+            .get(0x149)
+            .and_then(|code| header::ram_size(*code).ok())
+            .map_or(0, |size| size.min(RAM_BANK_SIZE * 4));
 
         let ram_vec = vec![0u8; ramsize];
+        let battery = rom.get(0x147) == Some(&0x03);
+        let multicart = is_multicart(&rom);
 
         Box::new(MBC1 {
-            rom: rom,
+            rom,
             rom_bank_num: 1,
             ram: ram_vec,
             ram_enable: false,
             ram_bank_num: 0,
-            mode_select: 0,
+            mode: BankingMode::Simple,
+            multicart,
+            battery,
         })
     }
+
+    fn upper_bits(&self) -> usize {
+        let shift = if self.multicart { 4 } else { 5 };
+        (self.ram_bank_num as usize) << shift
+    }
+
+    fn lower_bits(&self) -> usize {
+        if self.multicart {
+            (self.rom_bank_num & 0xF) as usize
+        } else {
+            self.rom_bank_num as usize
+        }
+    }
+
+    /// Bank numbers past the end of the ROM wrap, since the unused bank
+    /// lines aren't connected.
+    fn mask_rom_bank(&self, bank: usize) -> usize {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        bank % banks
+    }
+
+    fn rom0_bank(&self) -> usize {
+        match self.mode {
+            BankingMode::Simple => 0,
+            BankingMode::Advanced => self.mask_rom_bank(self.upper_bits()),
+        }
+    }
+
+    fn romx_bank(&self) -> usize {
+        self.mask_rom_bank(self.upper_bits() | self.lower_bits())
+    }
+
+    fn ram_bank(&self) -> usize {
+        match self.mode {
+            BankingMode::Simple => 0,
+            BankingMode::Advanced => self.ram_bank_num as usize,
+        }
+    }
+
+    fn ram_index(&self, addr: BusWidth) -> Option<usize> {
+        if !self.ram_enable || self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_bank() * RAM_BANK_SIZE + (addr - 0xA000) as usize;
+        Some(offset % self.ram.len())
+    }
 }
 
 impl Bus for MBC1 {
     fn write8(&mut self, addr: BusWidth, data: u8) {
         match addr {
-            0x0..=0x1FFF => {
-                if data & 0xF == 0xA {
-                    self.ram_enable = true;
-                } else {
-                    self.ram_enable = false;
-                }
-            },
+            0x0000..=0x1FFF => self.ram_enable = data & 0xF == 0xA,
             0x2000..=0x3FFF => {
-                println!("Setting MBC ram bank num to {}", data);
-                if data == 0x0 {
-                    self.rom_bank_num = 0x1;
-                } else {
-                    self.rom_bank_num = data;
-                }
-            },
-            0x4000..=0x5FFF => {
-                println!("Setting MBC ram bank num to {}", data);
-                self.ram_bank_num = data;
-            },
+                // Only the five register bits are checked for 0, so 20, 40
+                // and 60 can't be reached in simple mode; they become
+                // 21, 41 and 61
+                self.rom_bank_num = match data & 0x1F {
+                    0 => 1,
+                    bank => bank,
+                };
+            }
+            0x4000..=0x5FFF => self.ram_bank_num = data & 0x3,
             0x6000..=0x7FFF => {
-                self.mode_select = data;
-            },
-            0xA000..=0xBFFF => {
-                let translated_addr = if self.mode_select == 0 {
-                    (self.ram_bank_num as u16) << 10 & addr - 0xA000
+                self.mode = if data & 0x1 == 0 {
+                    BankingMode::Simple
                 } else {
-                    addr - 0xA000
-                } as usize;
-
-                match self.ram.get_mut(translated_addr) {
-                    Some(ptr) => *ptr = data,
-                    _ => (),
+                    BankingMode::Advanced
+                };
+            }
+            0xA000..=0xBFFF => {
+                if let Some(idx) = self.ram_index(addr) {
+                    self.ram[idx] = data;
                 }
-            },
-            _ => panic!("Illegal write to {}", addr),
+            }
+            _ => (),
         };
     }
 
     fn read8(&self, addr: BusWidth) -> u8 {
         match addr {
-            0x0..=0x3FFF => {
-                self.rom[addr as usize]
-            },
-            0x4000..=0x7FFF => {
-                let bank_num = match self.mode_select {
-                    0 => ((self.ram_bank_num as u16) << 5) | (self.rom_bank_num as u16),
-                    _ => self.rom_bank_num as u16,
-                };
-                self.rom[((addr-0x4000) + 0x4000 * bank_num) as usize]
-            },
-            0xA000..=0xBFFF => {
-                let ram_idx = ((addr-0xA000) + 0x2000 * self.ram_bank_num as u16) as usize;
-                self.ram.get(ram_idx).cloned().unwrap_or(0)
-            },
-            _ => panic!("Illegal read from {}", addr),
+            0x0000..=0x7FFF => {
+                let offset = self.rom_offset(addr).unwrap_or(0);
+                self.rom.get(offset).cloned().unwrap_or(0xFF)
+            }
+            0xA000..=0xBFFF => self.ram_index(addr).map_or(0xFF, |idx| self.ram[idx]),
+            _ => 0xFF,
         }
     }
 
+    fn direct_page(&self, addr: BusWidth) -> Option<&[u8]> {
+        let page_start = self.rom_offset(addr)? & !0xFF;
+        self.rom.get(page_start..page_start + 0x100)
+    }
+
     fn write16(&mut self, addr: BusWidth, data: u16) {
         self._write16_using_write8(addr, data);
     }
@@ -103,6 +163,79 @@ impl Bus for MBC1 {
     }
 }
 
+impl Cartridge for MBC1 {
+    fn bank_state(&self) -> BankState {
+        BankState {
+            rom0: self.rom0_bank() as u16,
+            romx: self.romx_bank() as u16,
+            ram: self.ram_bank() as u16,
+            ram_enabled: self.ram_enable,
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_offset(&self, addr: BusWidth) -> Option<usize> {
+        match addr {
+            0xA000..=0xBFFF => self.ram_index(addr),
+            _ => None,
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+}
+
+impl SaveState for MBC1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rom_bank_num);
+        w.write_bytes(&self.ram);
+        w.write_bool(self.ram_enable);
+        w.write_u8(self.ram_bank_num);
+        w.write_bool(self.mode == BankingMode::Advanced);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.rom_bank_num = r.read_u8()?;
+        if self.rom_bank_num == 0 || self.rom_bank_num > 0x1F {
+            return Err(StateError::Invalid("MBC1 ROM bank"));
+        }
+        r.read_into(&mut self.ram)?;
+        self.ram_enable = r.read_bool()?;
+        self.ram_bank_num = r.read_u8()? & 0x3;
+        self.mode = if r.read_bool()? {
+            BankingMode::Advanced
+        } else {
+            BankingMode::Simple
+        };
+        Ok(())
+    }
+}
+
+/// ROM where the first two bytes of every bank hold the bank number.
+#[cfg(test)]
+fn numbered_rom(banks: usize, ram_code: u8) -> Vec<u8> {
+    let mut rom = vec![0u8; banks * ROM_BANK_SIZE];
+    for bank in 0..banks {
+        rom[bank * ROM_BANK_SIZE] = bank as u8;
+        rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+    }
+    rom[0x147] = 0x03;
+    rom[0x149] = ram_code;
+    rom
+}
+
 #[test]
 fn mbc1_rom_bank_test() {
     let mut rom_vec = vec![0u8; 1024 * 64];
@@ -115,3 +248,138 @@ fn mbc1_rom_bank_test() {
         assert!(mbc1.read8(0x4000) == i);
     }
 }
+
+// Mirrors Mooneye's mbc1/bits_bank1 and bits_bank2
+#[test]
+fn mbc1_bank_register_bits() {
+    let mut mbc1 = MBC1::new(numbered_rom(128, 0x00));
+    // Only the low five bits are kept, and 0 reads as 1
+    mbc1.write8(0x2000, 0xE3);
+    assert_eq!(mbc1.read8(0x4000), 0x03);
+    mbc1.write8(0x3FFF, 0x00);
+    assert_eq!(mbc1.read8(0x4000), 0x01);
+
+    mbc1.write8(0x5FFF, 0xFE);
+    mbc1.write8(0x2000, 0x05);
+    assert_eq!(mbc1.read8(0x4000), 0x45);
+    // The 20/40/60 quirk
+    mbc1.write8(0x2000, 0x20);
+    assert_eq!(mbc1.read8(0x4000), 0x41);
+    // Simple mode leaves 0000-3FFF alone
+    assert_eq!(mbc1.read8(0x0000), 0x00);
+}
+
+// Mirrors Mooneye's mbc1/bits_mode, rom_512kb and rom_1Mb
+#[test]
+fn mbc1_advanced_mode_and_masking() {
+    let mut mbc1 = MBC1::new(numbered_rom(128, 0x00));
+    mbc1.write8(0x4000, 0x03);
+    mbc1.write8(0x6000, 0x01);
+    assert_eq!(mbc1.read8(0x0000), 0x60);
+    assert_eq!(mbc1.bank_state().rom0, 0x60);
+    assert_eq!(mbc1.rom_offset(0x0010), Some(0x60 * ROM_BANK_SIZE + 0x10));
+    mbc1.write8(0x6000, 0x00);
+    assert_eq!(mbc1.read8(0x0000), 0x00);
+
+    // 512KiB: BANK2 is out of range and masked away entirely
+    let mut mbc1 = MBC1::new(numbered_rom(32, 0x00));
+    mbc1.write8(0x4000, 0x01);
+    mbc1.write8(0x2000, 0x04);
+    assert_eq!(mbc1.read8(0x4000), 0x04);
+    mbc1.write8(0x6000, 0x01);
+    assert_eq!(mbc1.read8(0x0000), 0x00);
+
+    // 1MiB: only the low BANK2 bit is connected
+    let mut mbc1 = MBC1::new(numbered_rom(64, 0x00));
+    mbc1.write8(0x4000, 0x03);
+    mbc1.write8(0x2000, 0x02);
+    assert_eq!(mbc1.read8(0x4000), 0x22);
+}
+
+// Mirrors Mooneye's mbc1/ram_64kb and ram_256kb, and bits_ramg
+#[test]
+fn mbc1_ram_banking() {
+    let mut mbc1 = MBC1::new(numbered_rom(4, 0x03));
+    assert_eq!(mbc1.read8(0xA000), 0xFF);
+    // Only the low nibble of the enable write matters
+    mbc1.write8(0x1FFF, 0xFA);
+    mbc1.write8(0xA000, 0x11);
+    assert_eq!(mbc1.read8(0xA000), 0x11);
+
+    // Simple mode always uses RAM bank 0
+    mbc1.write8(0x4000, 0x02);
+    assert_eq!(mbc1.read8(0xA000), 0x11);
+    mbc1.write8(0x6000, 0x01);
+    assert_eq!(mbc1.read8(0xA000), 0x00);
+    mbc1.write8(0xA000, 0x22);
+    assert_eq!(mbc1.ram()[2 * RAM_BANK_SIZE], 0x22);
+    assert_eq!(mbc1.ram()[0], 0x11);
+
+    mbc1.write8(0x0000, 0x0B);
+    assert_eq!(mbc1.read8(0xA000), 0xFF);
+    mbc1.write8(0xA000, 0x33);
+    assert_eq!(mbc1.ram()[2 * RAM_BANK_SIZE], 0x22);
+
+    // 8KiB RAM is mirrored across every bank
+    let mut mbc1 = MBC1::new(numbered_rom(4, 0x02));
+    mbc1.write8(0x0000, 0x0A);
+    mbc1.write8(0x6000, 0x01);
+    mbc1.write8(0x4000, 0x03);
+    mbc1.write8(0xA123, 0x44);
+    assert_eq!(mbc1.ram()[0x123], 0x44);
+}
+
+// Mirrors Mooneye's mbc1/multicart_rom_8Mb
+#[test]
+fn mbc1_multicart() {
+    let mut rom = numbered_rom(64, 0x00);
+    for game in 0..4 {
+        let logo = game * 16 * ROM_BANK_SIZE + 0x104;
+        rom[logo..logo + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+    }
+    let mut mbc1 = MBC1::new(rom.clone());
+    mbc1.write8(0x4000, 0x01);
+    mbc1.write8(0x2000, 0x12);
+    // Bit 4 of BANK1 isn't connected
+    assert_eq!(mbc1.read8(0x4000), 0x12);
+    mbc1.write8(0x6000, 0x01);
+    assert_eq!(mbc1.read8(0x0000), 0x10);
+    mbc1.write8(0x4000, 0x03);
+    assert_eq!(mbc1.read8(0x0000), 0x30);
+    assert_eq!(mbc1.read8(0x4000), 0x32);
+
+    // Without the repeated logos it's a plain 1MiB game
+    rom[16 * ROM_BANK_SIZE + 0x104] = 0;
+    let mut mbc1 = MBC1::new(rom);
+    mbc1.write8(0x4000, 0x01);
+    mbc1.write8(0x2000, 0x12);
+    assert_eq!(mbc1.read8(0x4000), 0x32);
+}
+
+#[test]
+fn mbc1_cartridge_queries() {
+    let mut rom = vec![0u8; ROM_BANK_SIZE * 8];
+    rom[0x147] = 0x03;
+    rom[0x149] = 0x02;
+    let mut mbc1 = MBC1::new(rom);
+    assert!(mbc1.has_battery());
+    assert_eq!(mbc1.battery_ram().map(|ram| ram.len()), Some(0x2000));
+
+    mbc1.write8(0x2000, 5);
+    assert_eq!(mbc1.bank_state().romx, 5);
+    assert_eq!(mbc1.rom_offset(0x4123), Some(5 * ROM_BANK_SIZE + 0x123));
+    assert_eq!(mbc1.rom_offset(0x0123), Some(0x123));
+    assert_eq!(mbc1.ram_offset(0xA000), None);
+    mbc1.write8(0x0000, 0x0A);
+    assert_eq!(mbc1.ram_offset(0xA010), Some(0x10));
+
+    assert!(!mbc1.load_battery_ram(&[0u8; 16]));
+    assert!(mbc1.load_battery_ram(&[0x42u8; 0x2000]));
+    assert_eq!(mbc1.read8(0xA000), 0x42);
+}
+
+#[test]
+#[ignore = "needs the Mooneye ROMs"]
+fn mooneye_mbc1() {
+    crate::mooneye::run_all("emulator-only/mbc1");
+}
@@ -0,0 +1,168 @@
+use crate::hw::controller::{BankState, Cartridge, ROM_BANK_SIZE};
+use crate::hw::memory::{Bus, BusWidth};
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
+
+const MBC2_RAM_SIZE: usize = 512;
+
+/// MBC2: up to 16 ROM banks and 512 half-byte RAM cells built into the
+/// mapper. Address bit 8 picks which register a write to 0000-3FFF hits.
+pub struct MBC2 {
+    rom: Vec<u8>,
+    rom_bank_num: u8,
+    /// One cell per byte, only the low nibble is stored.
+    ram: Vec<u8>,
+    ram_enable: bool,
+    battery: bool,
+}
+
+impl MBC2 {
+    pub fn new(rom: Vec<u8>) -> Box<dyn Cartridge> {
+        let battery = rom.get(0x147) == Some(&0x06);
+        Box::new(MBC2 {
+            rom,
+            rom_bank_num: 1,
+            ram: vec![0u8; MBC2_RAM_SIZE],
+            ram_enable: false,
+            battery,
+        })
+    }
+
+    fn rom_bank(&self) -> usize {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        self.rom_bank_num as usize % banks
+    }
+}
+
+impl Bus for MBC2 {
+    fn write8(&mut self, addr: BusWidth, data: u8) {
+        match addr {
+            0x0000..=0x3FFF if addr & 0x100 == 0 => {
+                self.ram_enable = data & 0xF == 0xA;
+            }
+            0x0000..=0x3FFF => {
+                self.rom_bank_num = match data & 0xF {
+                    0 => 1,
+                    bank => bank,
+                };
+            }
+            // The 512 cells repeat across the whole range
+            0xA000..=0xBFFF if self.ram_enable => {
+                self.ram[(addr & 0x1FF) as usize] = data & 0xF;
+            }
+            _ => (),
+        }
+    }
+
+    fn read8(&self, addr: BusWidth) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom.get(addr as usize).cloned().unwrap_or(0xFF),
+            0x4000..=0x7FFF => {
+                let offset = self.rom_bank() * ROM_BANK_SIZE + (addr - 0x4000) as usize;
+                self.rom.get(offset).cloned().unwrap_or(0xFF)
+            }
+            // Only four data lines are connected, the rest float high
+            0xA000..=0xBFFF if self.ram_enable => 0xF0 | self.ram[(addr & 0x1FF) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    fn direct_page(&self, addr: BusWidth) -> Option<&[u8]> {
+        let page_start = self.rom_offset(addr)? & !0xFF;
+        self.rom.get(page_start..page_start + 0x100)
+    }
+
+    fn write16(&mut self, addr: BusWidth, data: u16) {
+        self._write16_using_write8(addr, data);
+    }
+
+    fn read16(&self, addr: BusWidth) -> u16 {
+        self._read16_using_read8(addr)
+    }
+}
+
+impl Cartridge for MBC2 {
+    fn bank_state(&self) -> BankState {
+        BankState {
+            rom0: 0,
+            romx: self.rom_bank() as u16,
+            ram: 0,
+            ram_enabled: self.ram_enable,
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_offset(&self, addr: BusWidth) -> Option<usize> {
+        match addr {
+            0xA000..=0xBFFF if self.ram_enable => Some((addr & 0x1FF) as usize),
+            _ => None,
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) -> bool {
+        if !self.battery || data.len() != MBC2_RAM_SIZE {
+            return false;
+        }
+        for (cell, byte) in self.ram.iter_mut().zip(data) {
+            *cell = byte & 0xF;
+        }
+        true
+    }
+}
+
+impl SaveState for MBC2 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rom_bank_num);
+        w.write_bytes(&self.ram);
+        w.write_bool(self.ram_enable);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.rom_bank_num = r.read_u8()?;
+        r.read_into(&mut self.ram)?;
+        self.ram_enable = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn mbc2_registers_and_ram() {
+    let mut rom = vec![0u8; ROM_BANK_SIZE * 16];
+    for bank in 0..16 {
+        rom[bank * ROM_BANK_SIZE + 1] = bank as u8;
+    }
+    rom[0x147] = 0x06;
+    let mut mbc2 = MBC2::new(rom);
+
+    // Bit 8 clear: RAM enable, so the bank doesn't change
+    mbc2.write8(0x2000, 0x05);
+    assert_eq!(mbc2.read8(0x4001), 1);
+    mbc2.write8(0x2100, 0x05);
+    assert_eq!(mbc2.read8(0x4001), 5);
+    mbc2.write8(0x01FF, 0xF0);
+    assert_eq!(mbc2.read8(0x4001), 1);
+
+    assert_eq!(mbc2.read8(0xA000), 0xFF);
+    mbc2.write8(0x0000, 0x0A);
+    mbc2.write8(0xA001, 0x3C);
+    assert_eq!(mbc2.read8(0xA001), 0xFC);
+    assert_eq!(mbc2.read8(0xA201), 0xFC);
+    assert_eq!(mbc2.read8(0xBE01), 0xFC);
+    assert_eq!(mbc2.battery_ram().map(|ram| ram[1]), Some(0x0C));
+    mbc2.write8(0x0000, 0x00);
+    assert_eq!(mbc2.read8(0xA001), 0xFF);
+}
@@ -0,0 +1,257 @@
+use crate::hw::controller::rtc::{Rtc, WallClock};
+use crate::hw::controller::{header, BankState, Cartridge, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::hw::memory::{Bus, BusWidth};
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
+
+/// MBC3: up to 128 ROM banks, 4 RAM banks, and on types 0F/10 a real time
+/// clock whose registers are mapped in place of RAM.
+pub struct MBC3 {
+    rom: Vec<u8>,
+    rom_bank_num: u8,
+    ram: Vec<u8>,
+    ram_enable: bool,
+    /// 00-03 selects a RAM bank, 08-0C an RTC register.
+    ram_select: u8,
+    /// The last write to 6000-7FFF, to spot the 0 then 1 latch sequence.
+    latch_reg: u8,
+    rtc: Option<Rtc>,
+    battery: bool,
+}
+
+impl MBC3 {
+    pub fn new(rom: Vec<u8>) -> Box<dyn Cartridge> {
+        let cart_type = rom.get(0x147).cloned().unwrap_or(0);
+        let ramsize = rom
+            .get(0x149)
+            .and_then(|code| header::ram_size(*code).ok())
+            .map_or(0, |size| size.min(RAM_BANK_SIZE * 4));
+        let rtc = match cart_type {
+            0x0F | 0x10 => Some(Rtc::new(Box::new(WallClock))),
+            _ => None,
+        };
+        Box::new(MBC3 {
+            rom,
+            rom_bank_num: 1,
+            ram: vec![0u8; ramsize],
+            ram_enable: false,
+            ram_select: 0,
+            latch_reg: 0xFF,
+            rtc,
+            battery: cart_type == 0x0F || cart_type == 0x10 || cart_type == 0x13,
+        })
+    }
+
+    fn rom_bank(&self) -> usize {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        self.rom_bank_num as usize % banks
+    }
+
+    fn ram_index(&self, addr: BusWidth) -> Option<usize> {
+        match self.ram_select {
+            0x00..=0x03 if self.ram_enable && !self.ram.is_empty() => {
+                let offset = self.ram_select as usize * RAM_BANK_SIZE + (addr - 0xA000) as usize;
+                Some(offset % self.ram.len())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Bus for MBC3 {
+    fn write8(&mut self, addr: BusWidth, data: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enable = data & 0xF == 0xA,
+            0x2000..=0x3FFF => {
+                self.rom_bank_num = match data & 0x7F {
+                    0 => 1,
+                    bank => bank,
+                };
+            }
+            0x4000..=0x5FFF => self.ram_select = data & 0x0F,
+            0x6000..=0x7FFF => {
+                if self.latch_reg == 0x00 && data == 0x01 {
+                    if let Some(rtc) = self.rtc.as_mut() {
+                        rtc.latch();
+                    }
+                }
+                self.latch_reg = data;
+            }
+            0xA000..=0xBFFF => match (self.ram_select, self.rtc.as_mut()) {
+                (0x08..=0x0C, Some(rtc)) if self.ram_enable => rtc.write(self.ram_select, data),
+                _ => {
+                    if let Some(idx) = self.ram_index(addr) {
+                        self.ram[idx] = data;
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn read8(&self, addr: BusWidth) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom.get(addr as usize).cloned().unwrap_or(0xFF),
+            0x4000..=0x7FFF => {
+                let offset = self.rom_bank() * ROM_BANK_SIZE + (addr - 0x4000) as usize;
+                self.rom.get(offset).cloned().unwrap_or(0xFF)
+            }
+            0xA000..=0xBFFF => match (self.ram_select, self.rtc.as_ref()) {
+                (0x08..=0x0C, Some(rtc)) if self.ram_enable => rtc.read(self.ram_select),
+                _ => self.ram_index(addr).map_or(0xFF, |idx| self.ram[idx]),
+            },
+            _ => 0xFF,
+        }
+    }
+
+    fn direct_page(&self, addr: BusWidth) -> Option<&[u8]> {
+        let page_start = self.rom_offset(addr)? & !0xFF;
+        self.rom.get(page_start..page_start + 0x100)
+    }
+
+    fn write16(&mut self, addr: BusWidth, data: u16) {
+        self._write16_using_write8(addr, data);
+    }
+
+    fn read16(&self, addr: BusWidth) -> u16 {
+        self._read16_using_read8(addr)
+    }
+}
+
+impl Cartridge for MBC3 {
+    fn bank_state(&self) -> BankState {
+        BankState {
+            rom0: 0,
+            romx: self.rom_bank() as u16,
+            ram: self.ram_select as u16,
+            ram_enabled: self.ram_enable,
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn ram_offset(&self, addr: BusWidth) -> Option<usize> {
+        match addr {
+            0xA000..=0xBFFF => self.ram_index(addr),
+            _ => None,
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    fn rtc(&self) -> Option<&Rtc> {
+        self.rtc.as_ref()
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.tick(cycles);
+        }
+    }
+}
+
+impl SaveState for MBC3 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rom_bank_num);
+        w.write_bytes(&self.ram);
+        w.write_bool(self.ram_enable);
+        w.write_u8(self.ram_select);
+        w.write_u8(self.latch_reg);
+        if let Some(rtc) = self.rtc.as_ref() {
+            rtc.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.rom_bank_num = r.read_u8()?;
+        r.read_into(&mut self.ram)?;
+        self.ram_enable = r.read_bool()?;
+        self.ram_select = r.read_u8()?;
+        self.latch_reg = r.read_u8()?;
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.load_state(r)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn mbc3_banks() {
+    let mut rom = vec![0u8; ROM_BANK_SIZE * 128];
+    for bank in 0..128 {
+        rom[bank * ROM_BANK_SIZE] = bank as u8;
+    }
+    rom[0x147] = 0x13;
+    rom[0x149] = 0x03;
+    let mut mbc3 = MBC3::new(rom);
+
+    mbc3.write8(0x2000, 0x00);
+    assert_eq!(mbc3.read8(0x4000), 1);
+    mbc3.write8(0x2000, 0x7F);
+    assert_eq!(mbc3.read8(0x4000), 0x7F);
+    mbc3.write8(0x2000, 0x21);
+    assert_eq!(mbc3.read8(0x4000), 0x21);
+
+    mbc3.write8(0x0000, 0x0A);
+    for bank in 0..4 {
+        mbc3.write8(0x4000, bank);
+        mbc3.write8(0xA000, 0x10 + bank);
+    }
+    mbc3.write8(0x4000, 2);
+    assert_eq!(mbc3.read8(0xA000), 0x12);
+    assert_eq!(mbc3.ram_offset(0xA001), Some(2 * RAM_BANK_SIZE + 1));
+    // No RTC on this type
+    mbc3.write8(0x4000, 0x08);
+    assert_eq!(mbc3.read8(0xA000), 0xFF);
+    assert!(mbc3.rtc().is_none());
+}
+
+#[test]
+fn mbc3_rtc_latch() {
+    use crate::hw::controller::rtc::{self, CycleClock, CPU_CLOCK_HZ};
+
+    let mut rom = vec![0u8; ROM_BANK_SIZE * 2];
+    rom[0x147] = 0x10;
+    rom[0x149] = 0x03;
+    let mut mbc3 = MBC3::new(rom);
+    mbc3.rtc_mut().unwrap().set_clock(Box::new(CycleClock));
+
+    mbc3.write8(0x0000, 0x0A);
+    mbc3.write8(0x4000, 0x08);
+    mbc3.write8(0xA000, 30);
+    mbc3.tick(CPU_CLOCK_HZ * 45);
+    // Not latched yet
+    assert_eq!(mbc3.read8(0xA000), 30);
+    mbc3.write8(0x6000, 0x01);
+    assert_eq!(mbc3.read8(0xA000), 30);
+    mbc3.write8(0x6000, 0x00);
+    mbc3.write8(0x6000, 0x01);
+    assert_eq!(mbc3.read8(0xA000), 15);
+    mbc3.write8(0x4000, 0x09);
+    assert_eq!(mbc3.read8(0xA000), 1);
+
+    // RAM banks still work alongside the clock
+    mbc3.write8(0x4000, 0x01);
+    mbc3.write8(0xA000, 0x99);
+    assert_eq!(mbc3.read8(0xA000), 0x99);
+
+    let save = mbc3.save_data().unwrap();
+    assert_eq!(save.len(), 4 * RAM_BANK_SIZE + rtc::RTC_FOOTER_SIZE);
+    assert_eq!(save[RAM_BANK_SIZE], 0x99);
+    assert_eq!(save[4 * RAM_BANK_SIZE], 15);
+}
@@ -0,0 +1,196 @@
+use crate::hw::controller::{header, BankState, Cartridge, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::hw::memory::{Bus, BusWidth};
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
+
+/// MBC5: a 9-bit ROM bank number where bank 0 can be mapped at 4000, and
+/// up to 16 RAM banks. On rumble carts bit 3 of the RAM bank register
+/// drives the motor instead.
+pub struct MBC5 {
+    rom: Vec<u8>,
+    rom_bank_num: u16,
+    ram: Vec<u8>,
+    ram_enable: bool,
+    ram_bank_num: u8,
+    rumble: Option<bool>,
+    battery: bool,
+}
+
+impl MBC5 {
+    pub fn new(rom: Vec<u8>) -> Box<dyn Cartridge> {
+        let cart_type = rom.get(0x147).cloned().unwrap_or(0);
+        let ramsize = rom
+            .get(0x149)
+            .and_then(|code| header::ram_size(*code).ok())
+            .unwrap_or(0);
+        Box::new(MBC5 {
+            rom,
+            rom_bank_num: 1,
+            ram: vec![0u8; ramsize],
+            ram_enable: false,
+            ram_bank_num: 0,
+            rumble: match cart_type {
+                0x1C..=0x1E => Some(false),
+                _ => None,
+            },
+            battery: cart_type == 0x1B || cart_type == 0x1E,
+        })
+    }
+
+    fn rom_bank(&self) -> usize {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        self.rom_bank_num as usize % banks
+    }
+
+    fn ram_index(&self, addr: BusWidth) -> Option<usize> {
+        if !self.ram_enable || self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_bank_num as usize * RAM_BANK_SIZE + (addr - 0xA000) as usize;
+        Some(offset % self.ram.len())
+    }
+}
+
+impl Bus for MBC5 {
+    fn write8(&mut self, addr: BusWidth, data: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enable = data == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank_num = (self.rom_bank_num & 0x100) | data as u16,
+            0x3000..=0x3FFF => {
+                self.rom_bank_num = (self.rom_bank_num & 0xFF) | ((data & 0x1) as u16) << 8
+            }
+            0x4000..=0x5FFF => match self.rumble {
+                Some(_) => {
+                    self.rumble = Some(data & 0x08 != 0);
+                    self.ram_bank_num = data & 0x07;
+                }
+                None => self.ram_bank_num = data & 0x0F,
+            },
+            0xA000..=0xBFFF => {
+                if let Some(idx) = self.ram_index(addr) {
+                    self.ram[idx] = data;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn read8(&self, addr: BusWidth) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom.get(addr as usize).cloned().unwrap_or(0xFF),
+            0x4000..=0x7FFF => {
+                let offset = self.rom_bank() * ROM_BANK_SIZE + (addr - 0x4000) as usize;
+                self.rom.get(offset).cloned().unwrap_or(0xFF)
+            }
+            0xA000..=0xBFFF => self.ram_index(addr).map_or(0xFF, |idx| self.ram[idx]),
+            _ => 0xFF,
+        }
+    }
+
+    fn direct_page(&self, addr: BusWidth) -> Option<&[u8]> {
+        let page_start = self.rom_offset(addr)? & !0xFF;
+        self.rom.get(page_start..page_start + 0x100)
+    }
+
+    fn write16(&mut self, addr: BusWidth, data: u16) {
+        self._write16_using_write8(addr, data);
+    }
+
+    fn read16(&self, addr: BusWidth) -> u16 {
+        self._read16_using_read8(addr)
+    }
+}
+
+impl Cartridge for MBC5 {
+    fn bank_state(&self) -> BankState {
+        BankState {
+            rom0: 0,
+            romx: self.rom_bank() as u16,
+            ram: self.ram_bank_num as u16,
+            ram_enabled: self.ram_enable,
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    fn rumble(&self) -> Option<bool> {
+        self.rumble
+    }
+}
+
+impl SaveState for MBC5 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.rom_bank_num);
+        w.write_bytes(&self.ram);
+        w.write_bool(self.ram_enable);
+        w.write_u8(self.ram_bank_num);
+        w.write_bool(self.rumble == Some(true));
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.rom_bank_num = r.read_u16()? & 0x1FF;
+        r.read_into(&mut self.ram)?;
+        self.ram_enable = r.read_bool()?;
+        self.ram_bank_num = r.read_u8()?;
+        let motor = r.read_bool()?;
+        if let Some(rumble) = self.rumble.as_mut() {
+            *rumble = motor;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn mbc5_nine_bit_banks() {
+    let mut rom = vec![0u8; ROM_BANK_SIZE * 512];
+    for bank in 0..512 {
+        rom[bank * ROM_BANK_SIZE] = bank as u8;
+        rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+    }
+    rom[0x147] = 0x1B;
+    rom[0x149] = 0x04;
+    let mut mbc5 = MBC5::new(rom);
+
+    // Bank 0 really is bank 0 on MBC5
+    mbc5.write8(0x2000, 0x00);
+    assert_eq!(mbc5.read8(0x4000), 0);
+    mbc5.write8(0x2000, 0x23);
+    mbc5.write8(0x3000, 0x01);
+    assert_eq!((mbc5.read8(0x4000), mbc5.read8(0x4001)), (0x23, 1));
+    assert_eq!(mbc5.bank_state().romx, 0x123);
+
+    mbc5.write8(0x0000, 0x0A);
+    mbc5.write8(0x4000, 0x0F);
+    mbc5.write8(0xBFFF, 0x77);
+    assert_eq!(mbc5.ram_offset(0xBFFF), Some(0x20000 - 1));
+    assert_eq!(mbc5.rumble(), None);
+}
+
+#[test]
+fn mbc5_rumble() {
+    let mut rom = vec![0u8; ROM_BANK_SIZE * 4];
+    rom[0x147] = 0x1E;
+    rom[0x149] = 0x03;
+    let mut mbc5 = MBC5::new(rom);
+    assert_eq!(mbc5.rumble(), Some(false));
+
+    mbc5.write8(0x0000, 0x0A);
+    mbc5.write8(0x4000, 0x09);
+    assert_eq!(mbc5.rumble(), Some(true));
+    assert_eq!(mbc5.bank_state().ram, 1);
+    mbc5.write8(0x4000, 0x01);
+    assert_eq!(mbc5.rumble(), Some(false));
+}
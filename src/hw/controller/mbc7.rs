@@ -0,0 +1,237 @@
+use crate::hw::controller::eeprom::Eeprom;
+use crate::hw::controller::{BankState, Cartridge, ROM_BANK_SIZE};
+use crate::hw::memory::{Bus, BusWidth};
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
+
+/// Accelerometer reading when the Game Boy is held flat.
+const ACCEL_CENTER: u16 = 0x81D0;
+/// Change in the reading for 1g of tilt.
+const ACCEL_ONE_G: f32 = 112.0;
+
+/// MBC7: ROM banking, plus a two axis accelerometer and a 93LC56 EEPROM
+/// behind registers at A000-AFFF instead of RAM.
+pub struct MBC7 {
+    rom: Vec<u8>,
+    rom_bank_num: u8,
+    /// Both enables have to be set to reach the registers.
+    ram_enable1: bool,
+    ram_enable2: bool,
+    /// Tilt in g from the input layer, positive to the right and down.
+    tilt: (f32, f32),
+    latched: (u16, u16),
+    /// The latch only takes new values after being erased.
+    latch_erased: bool,
+    eeprom: Eeprom,
+}
+
+impl MBC7 {
+    pub fn new(rom: Vec<u8>) -> Box<dyn Cartridge> {
+        Box::new(MBC7 {
+            rom,
+            rom_bank_num: 1,
+            ram_enable1: false,
+            ram_enable2: false,
+            tilt: (0.0, 0.0),
+            latched: (0x8000, 0x8000),
+            latch_erased: false,
+            eeprom: Eeprom::new(),
+        })
+    }
+
+    fn rom_bank(&self) -> usize {
+        let banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        self.rom_bank_num as usize % banks
+    }
+
+    fn registers_enabled(&self) -> bool {
+        self.ram_enable1 && self.ram_enable2
+    }
+
+    fn accel(tilt: f32) -> u16 {
+        let tilt = tilt.clamp(-2.0, 2.0);
+        (ACCEL_CENTER as f32 + tilt * ACCEL_ONE_G) as u16
+    }
+}
+
+impl Bus for MBC7 {
+    fn write8(&mut self, addr: BusWidth, data: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enable1 = data & 0xF == 0xA,
+            0x2000..=0x3FFF => self.rom_bank_num = data & 0x7F,
+            0x4000..=0x5FFF => self.ram_enable2 = data == 0x40,
+            0xA000..=0xAFFF if self.registers_enabled() => match addr & 0xF0 {
+                0x00 if data == 0x55 => {
+                    self.latched = (0x8000, 0x8000);
+                    self.latch_erased = true;
+                }
+                0x10 if data == 0xAA && self.latch_erased => {
+                    // X reads lower when tilted right
+                    self.latched = (MBC7::accel(-self.tilt.0), MBC7::accel(self.tilt.1));
+                    self.latch_erased = false;
+                }
+                0x80 => self.eeprom.write_pins(data),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    fn read8(&self, addr: BusWidth) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom.get(addr as usize).cloned().unwrap_or(0xFF),
+            0x4000..=0x7FFF => {
+                let offset = self.rom_bank() * ROM_BANK_SIZE + (addr - 0x4000) as usize;
+                self.rom.get(offset).cloned().unwrap_or(0xFF)
+            }
+            0xA000..=0xAFFF if self.registers_enabled() => match addr & 0xF0 {
+                0x20 => self.latched.0 as u8,
+                0x30 => (self.latched.0 >> 8) as u8,
+                0x40 => self.latched.1 as u8,
+                0x50 => (self.latched.1 >> 8) as u8,
+                0x60 => 0x00,
+                0x80 => self.eeprom.read_pins(),
+                _ => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    fn direct_page(&self, addr: BusWidth) -> Option<&[u8]> {
+        let page_start = self.rom_offset(addr)? & !0xFF;
+        self.rom.get(page_start..page_start + 0x100)
+    }
+
+    fn write16(&mut self, addr: BusWidth, data: u16) {
+        self._write16_using_write8(addr, data);
+    }
+
+    fn read16(&self, addr: BusWidth) -> u16 {
+        self._read16_using_read8(addr)
+    }
+}
+
+impl Cartridge for MBC7 {
+    fn bank_state(&self) -> BankState {
+        BankState {
+            rom0: 0,
+            romx: self.rom_bank() as u16,
+            ram: 0,
+            ram_enabled: self.registers_enabled(),
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    /// The EEPROM, which is what gets saved.
+    fn ram(&self) -> &[u8] {
+        self.eeprom.data()
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        self.eeprom.data_mut()
+    }
+
+    /// There's no RAM to address directly.
+    fn ram_offset(&self, _addr: BusWidth) -> Option<usize> {
+        None
+    }
+
+    fn has_battery(&self) -> bool {
+        true
+    }
+
+    fn set_tilt(&mut self, x: f32, y: f32) {
+        self.tilt = (x, y);
+    }
+
+    fn has_tilt_sensor(&self) -> bool {
+        true
+    }
+}
+
+impl SaveState for MBC7 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rom_bank_num);
+        w.write_bool(self.ram_enable1);
+        w.write_bool(self.ram_enable2);
+        w.write_u32(self.tilt.0.to_bits());
+        w.write_u32(self.tilt.1.to_bits());
+        w.write_u16(self.latched.0);
+        w.write_u16(self.latched.1);
+        w.write_bool(self.latch_erased);
+        self.eeprom.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.rom_bank_num = r.read_u8()? & 0x7F;
+        self.ram_enable1 = r.read_bool()?;
+        self.ram_enable2 = r.read_bool()?;
+        self.tilt = (f32::from_bits(r.read_u32()?), f32::from_bits(r.read_u32()?));
+        self.latched = (r.read_u16()?, r.read_u16()?);
+        self.latch_erased = r.read_bool()?;
+        self.eeprom.load_state(r)
+    }
+}
+
+#[test]
+fn mbc7_accelerometer() {
+    let mut mbc7 = MBC7::new(vec![0u8; ROM_BANK_SIZE * 4]);
+    mbc7.set_tilt(1.0, -0.5);
+    // Locked until both enables are written
+    mbc7.write8(0x0000, 0x0A);
+    assert_eq!(mbc7.read8(0xA020), 0xFF);
+    mbc7.write8(0x4000, 0x40);
+
+    // Latching needs an erase first
+    mbc7.write8(0xA010, 0xAA);
+    assert_eq!(mbc7.read8(0xA030), 0x80);
+    mbc7.write8(0xA000, 0x55);
+    mbc7.write8(0xA010, 0xAA);
+    let x = mbc7.read8(0xA020) as u16 | (mbc7.read8(0xA030) as u16) << 8;
+    let y = mbc7.read8(0xA040) as u16 | (mbc7.read8(0xA050) as u16) << 8;
+    assert_eq!(x, ACCEL_CENTER - 112);
+    assert_eq!(y, ACCEL_CENTER - 56);
+    // Registers repeat through A000-AFFF
+    assert_eq!(mbc7.read8(0xAF25), x as u8);
+    assert_eq!(mbc7.read8(0xA060), 0x00);
+    assert_eq!(mbc7.read8(0xA070), 0xFF);
+    assert_eq!(mbc7.read8(0xB000), 0xFF);
+
+    // The latch holds until the next erase and latch
+    mbc7.set_tilt(0.0, 0.0);
+    mbc7.write8(0xA010, 0xAA);
+    assert_eq!(mbc7.read8(0xA020), x as u8);
+}
+
+#[test]
+fn mbc7_state_keeps_tilt() {
+    let mut mbc7 = MBC7::new(vec![0u8; ROM_BANK_SIZE * 4]);
+    mbc7.set_tilt(0.5, 0.0);
+    let mut w = StateWriter::new();
+    mbc7.save_state(&mut w);
+    let state = w.into_bytes();
+
+    // Latching after the load sees the tilt from when the state was saved
+    mbc7.set_tilt(-1.0, 0.0);
+    mbc7.load_state(&mut StateReader::new(&state).unwrap()).unwrap();
+    mbc7.write8(0x0000, 0x0A);
+    mbc7.write8(0x4000, 0x40);
+    mbc7.write8(0xA000, 0x55);
+    mbc7.write8(0xA010, 0xAA);
+    let x = mbc7.read8(0xA020) as u16 | (mbc7.read8(0xA030) as u16) << 8;
+    assert_eq!(x, ACCEL_CENTER - 56);
+}
+
+#[test]
+fn mbc7_eeprom_is_battery_ram() {
+    use crate::hw::controller::eeprom::EEPROM_SIZE;
+
+    let mut mbc7 = MBC7::new(vec![0u8; ROM_BANK_SIZE * 4]);
+    assert_eq!(mbc7.battery_ram().map(|ram| ram.len()), Some(EEPROM_SIZE));
+    mbc7.write8(0x0000, 0x0A);
+    mbc7.write8(0x4000, 0x40);
+    mbc7.write8(0xA080, 0x80);
+    assert_eq!(mbc7.read8(0xA080) & 0x81, 0x81);
+}
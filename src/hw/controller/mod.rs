@@ -1,4 +1,222 @@
+pub mod camera;
+pub mod eeprom;
+pub mod header;
+pub mod huc1;
+pub mod huc3;
+pub mod infrared;
 pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+pub mod mbc7;
+pub mod rom_only;
+pub mod rtc;
 
+pub use self::camera::{ImageSensor, PocketCamera};
+pub use self::header::{CartridgeHeader, CgbSupport, HeaderError, HeaderResult, Mapper};
+pub use self::huc1::HuC1;
+pub use self::huc3::HuC3;
+pub use self::infrared::IrPort;
 pub use self::mbc1::MBC1;
+pub use self::mbc2::MBC2;
+pub use self::mbc3::MBC3;
+pub use self::mbc5::MBC5;
+pub use self::mbc7::MBC7;
+pub use self::rom_only::RomOnly;
+pub use self::rtc::Rtc;
 
+use self::rtc::RtcClock;
+use crate::hw::memory::{Bus, BusWidth};
+use crate::savestate::SaveState;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+/// The largest ROM any mapper can address (MBC5's 512 banks).
+pub const MAX_ROM_SIZE: usize = 512 * ROM_BANK_SIZE;
+
+/// Which banks a mapper currently has switched in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BankState {
+    /// Bank at 0000-3FFF. Only a few mappers can move it off 0.
+    pub rom0: u16,
+    /// Bank at 4000-7FFF.
+    pub romx: u16,
+    /// Bank at A000-BFFF.
+    pub ram: u16,
+    pub ram_enabled: bool,
+}
+
+/// A cartridge as seen from outside its mapper. `Bus` is what the CPU
+/// sees; everything here is for the debugger, save files and other
+/// tooling.
+pub trait Cartridge: Bus + SaveState {
+    fn bank_state(&self) -> BankState;
+
+    /// The whole ROM image.
+    fn rom(&self) -> &[u8];
+
+    /// External RAM, empty if the cartridge has none.
+    fn ram(&self) -> &[u8];
+
+    fn ram_mut(&mut self) -> &mut [u8];
+
+    fn has_battery(&self) -> bool;
+
+    /// Offset into the ROM file of a 0000-7FFF address, given the current
+    /// banks.
+    fn rom_offset(&self, addr: BusWidth) -> Option<usize> {
+        let banks = self.bank_state();
+        let (bank, offset) = match addr {
+            0x0000..=0x3FFF => (banks.rom0, addr),
+            0x4000..=0x7FFF => (banks.romx, addr - 0x4000),
+            _ => return None,
+        };
+        let offset = bank as usize * ROM_BANK_SIZE + offset as usize;
+        // Banks past the end of the ROM mirror it
+        Some(offset % self.rom().len().max(1))
+    }
+
+    /// Offset into external RAM of an A000-BFFF address, if RAM is
+    /// enabled there.
+    fn ram_offset(&self, addr: BusWidth) -> Option<usize> {
+        let banks = self.bank_state();
+        match addr {
+            0xA000..=0xBFFF if banks.ram_enabled && !self.ram().is_empty() => {
+                let offset = banks.ram as usize * RAM_BANK_SIZE + (addr - 0xA000) as usize;
+                Some(offset % self.ram().len())
+            }
+            _ => None,
+        }
+    }
+
+    /// RAM that survives power off and belongs in a save file.
+    fn battery_ram(&self) -> Option<&[u8]> {
+        if self.has_battery() && !self.ram().is_empty() {
+            Some(self.ram())
+        } else {
+            None
+        }
+    }
+
+    /// Restore battery RAM from a save file. Returns false if the
+    /// cartridge has none or the size is wrong.
+    fn load_battery_ram(&mut self, data: &[u8]) -> bool {
+        if self.battery_ram().map(|ram| ram.len()) != Some(data.len()) {
+            return false;
+        }
+        self.ram_mut().copy_from_slice(data);
+        true
+    }
+
+    /// Contents of a `.sav` file: battery RAM followed by the RTC footer if
+    /// there's a clock. None if nothing survives power off.
+    fn save_data(&mut self) -> Option<Vec<u8>> {
+        if !self.has_battery() {
+            return None;
+        }
+        let mut data = self.ram().to_vec();
+        if let Some(rtc) = self.rtc_mut() {
+            data.extend_from_slice(&rtc.footer());
+        }
+        Some(data)
+    }
+
+    /// Restore from a `.sav` file. A missing or unrecognised RTC footer
+    /// leaves the clock alone. Returns false if the RAM size is wrong.
+    fn load_save_data(&mut self, data: &[u8]) -> bool {
+        if !self.has_battery() || data.len() < self.ram().len() {
+            return false;
+        }
+        let (ram, footer) = data.split_at(self.ram().len());
+        if !ram.is_empty() && !self.load_battery_ram(ram) {
+            return false;
+        }
+        if let Some(rtc) = self.rtc_mut() {
+            rtc.load_footer(footer);
+        }
+        true
+    }
+
+    fn rtc(&self) -> Option<&Rtc> {
+        None
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        None
+    }
+
+    /// Whether the cartridge keeps time or runs hardware of its own, and
+    /// needs `tick`.
+    fn needs_tick(&self) -> bool {
+        self.rtc().is_some()
+    }
+
+    /// Change where the cartridge clock, if any, gets the time from.
+    fn set_clock(&mut self, clock: Box<dyn RtcClock>) {
+        if let Some(rtc) = self.rtc_mut() {
+            rtc.set_clock(clock);
+        }
+    }
+
+    /// Whether the rumble motor is running. None if there isn't one.
+    fn rumble(&self) -> Option<bool> {
+        None
+    }
+
+    /// Whether the speaker is sounding. None if there isn't one.
+    fn tone(&self) -> Option<bool> {
+        None
+    }
+
+    /// Attach the other end of the infrared link. Returns false if the
+    /// cartridge has no infrared port.
+    fn connect_ir(&mut self, _port: Box<dyn IrPort>) -> bool {
+        false
+    }
+
+    /// Attach the picture source for a camera. Returns false if the
+    /// cartridge has no camera.
+    fn connect_camera(&mut self, _sensor: Box<dyn ImageSensor>) -> bool {
+        false
+    }
+
+    fn has_tilt_sensor(&self) -> bool {
+        false
+    }
+
+    /// Feed the accelerometer, in g. Positive x is tilted right, positive
+    /// y tilted down (towards the player).
+    fn set_tilt(&mut self, _x: f32, _y: f32) {}
+
+    /// Called with the total clock cycles since power on, for mappers that
+    /// ask for it with `needs_tick`.
+    fn tick(&mut self, _cycles: u64) {}
+}
+
+/// Parse the header and build the mapper it asks for.
+pub fn load_cartridge(rom: Vec<u8>) -> HeaderResult<(CartridgeHeader, Box<dyn Cartridge>)> {
+    let header = CartridgeHeader::parse(&rom)?;
+    let cartridge = match header.mapper() {
+        Mapper::RomOnly => RomOnly::new(rom),
+        Mapper::Mbc1 => MBC1::new(rom),
+        Mapper::Mbc2 => MBC2::new(rom),
+        Mapper::Mbc3 => MBC3::new(rom),
+        Mapper::Mbc5 => MBC5::new(rom),
+        Mapper::Mbc7 => MBC7::new(rom),
+        Mapper::PocketCamera => PocketCamera::new(rom),
+        Mapper::HuC1 => HuC1::new(rom),
+        Mapper::HuC3 => HuC3::new(rom),
+        mapper => return Err(HeaderError::UnsupportedMapper(mapper)),
+    };
+    Ok((header, cartridge))
+}
+
+#[test]
+fn load_cartridge_picks_mapper() {
+    assert!(load_cartridge(header::test_rom(0x03, 0x01, 0x02)).is_ok());
+    assert_eq!(
+        load_cartridge(header::test_rom(0xFD, 0x00, 0x00)).err(),
+        Some(HeaderError::UnsupportedMapper(Mapper::Tama5))
+    );
+}
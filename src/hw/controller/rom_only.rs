@@ -0,0 +1,118 @@
+use crate::hw::controller::{BankState, Cartridge};
+use crate::hw::memory::{Bus, BusWidth};
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
+
+/// A cartridge with no mapper: 32KiB of ROM wired straight to 0000-7FFF,
+/// and for types 08/09 8KiB of RAM at A000-BFFF.
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    battery: bool,
+}
+
+impl RomOnly {
+    pub fn new(rom: Vec<u8>) -> Box<dyn Cartridge> {
+        let (ramsize, battery) = match rom.get(0x147) {
+            Some(0x08) => (0x2000, false),
+            Some(0x09) => (0x2000, true),
+            _ => (0, false),
+        };
+        Box::new(RomOnly {
+            rom,
+            ram: vec![0u8; ramsize],
+            battery,
+        })
+    }
+}
+
+impl Bus for RomOnly {
+    fn write8(&mut self, addr: BusWidth, data: u8) {
+        // Writes to ROM go nowhere
+        if let 0xA000..=0xBFFF = addr {
+            if let Some(byte) = self.ram.get_mut((addr - 0xA000) as usize) {
+                *byte = data;
+            }
+        }
+    }
+
+    fn read8(&self, addr: BusWidth) -> u8 {
+        match addr {
+            0x0000..=0x7FFF => self.rom.get(addr as usize).cloned().unwrap_or(0xFF),
+            0xA000..=0xBFFF => self.ram.get((addr - 0xA000) as usize).cloned().unwrap_or(0xFF),
+            _ => 0xFF,
+        }
+    }
+
+    fn direct_page(&self, addr: BusWidth) -> Option<&[u8]> {
+        let page_start = match addr {
+            0x0000..=0x7FFF => (addr & !0xFF) as usize,
+            _ => return None,
+        };
+        self.rom.get(page_start..page_start + 0x100)
+    }
+
+    fn write16(&mut self, addr: BusWidth, data: u16) {
+        self._write16_using_write8(addr, data);
+    }
+
+    fn read16(&self, addr: BusWidth) -> u16 {
+        self._read16_using_read8(addr)
+    }
+}
+
+impl Cartridge for RomOnly {
+    fn bank_state(&self) -> BankState {
+        BankState {
+            rom0: 0,
+            romx: 1,
+            ram: 0,
+            ram_enabled: !self.ram.is_empty(),
+        }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+}
+
+impl SaveState for RomOnly {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        r.read_into(&mut self.ram)
+    }
+}
+
+#[test]
+fn rom_only_ignores_writes() {
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x147] = 0x09;
+    rom[0x4000] = 0x12;
+    let mut cart = RomOnly::new(rom);
+
+    cart.write8(0x2000, 0x02);
+    cart.write8(0x4000, 0x34);
+    assert_eq!(cart.read8(0x4000), 0x12);
+
+    cart.write8(0xA000, 0x56);
+    assert_eq!(cart.read8(0xA000), 0x56);
+    assert_eq!(cart.battery_ram().map(|ram| ram.len()), Some(0x2000));
+
+    let cart = RomOnly::new(vec![0u8; 0x8000]);
+    assert_eq!(cart.read8(0xA000), 0xFF);
+    assert_eq!(cart.battery_ram(), None);
+}
@@ -0,0 +1,399 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
+
+/// CPU clock in Hz, which the emulated clock counts time in.
+pub const CPU_CLOCK_HZ: u64 = 4_194_304;
+
+/// Size of the RTC block appended to battery RAM in save files, in the
+/// layout most emulators share: the current and latched registers as ten
+/// little endian u32s, then a u64 UNIX timestamp.
+pub const RTC_FOOTER_SIZE: usize = 48;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Where the RTC gets the time from.
+pub trait RtcClock {
+    /// Current time in whole seconds from some fixed origin. `cycles` is
+    /// the number of emulated clock cycles since power on.
+    fn now(&self, cycles: u64) -> u64;
+
+    /// True if `now` is UNIX time, so time that passed while the emulator
+    /// wasn't running can be caught up on from a save file's timestamp.
+    fn is_real_time(&self) -> bool;
+}
+
+/// Real time, for normal play.
+pub struct WallClock;
+
+impl RtcClock for WallClock {
+    fn now(&self, _cycles: u64) -> u64 {
+        unix_time()
+    }
+
+    fn is_real_time(&self) -> bool {
+        true
+    }
+}
+
+/// Time derived from emulated cycles, so that runs are reproducible. Used
+/// for movies and headless runs.
+pub struct CycleClock;
+
+impl RtcClock for CycleClock {
+    fn now(&self, cycles: u64) -> u64 {
+        cycles / CPU_CLOCK_HZ
+    }
+
+    fn is_real_time(&self) -> bool {
+        false
+    }
+}
+
+/// The timestamp for a save file footer. Only real time is worth catching
+/// up on later, so other clocks write 0, which also keeps their saves the
+/// same from run to run.
+pub(crate) fn footer_timestamp(clock: &dyn RtcClock, cycles: u64) -> u64 {
+    if clock.is_real_time() {
+        clock.now(cycles)
+    } else {
+        0
+    }
+}
+
+/// The clock time a footer's registers were last up to date at: the
+/// footer's timestamp if there's one to catch up from, otherwise now.
+pub(crate) fn footer_last_sync(clock: &dyn RtcClock, cycles: u64, timestamp: u64) -> u64 {
+    if clock.is_real_time() && timestamp != 0 {
+        timestamp
+    } else {
+        clock.now(cycles)
+    }
+}
+
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+/// The clock registers of a cartridge real time clock.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct RtcRegisters {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    /// Nine bit day counter.
+    pub days: u16,
+    pub halted: bool,
+    /// Set when the day counter overflows, until software clears it.
+    pub day_carry: bool,
+}
+
+impl RtcRegisters {
+    /// Values software can write that the counters never reach on their
+    /// own. They still count up, but wrap at the register width without
+    /// carrying.
+    fn is_valid(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    fn tick_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.add_days(1);
+    }
+
+    fn add_days(&mut self, days: u64) {
+        let days = self.days as u64 + days;
+        if days > 0x1FF {
+            self.day_carry = true;
+        }
+        self.days = (days & 0x1FF) as u16;
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        while seconds > 0 && !self.is_valid() {
+            self.tick_second();
+            seconds -= 1;
+        }
+        let time_of_day =
+            self.seconds as u64 + self.minutes as u64 * 60 + self.hours as u64 * 3600 + seconds;
+        self.seconds = (time_of_day % 60) as u8;
+        self.minutes = (time_of_day / 60 % 60) as u8;
+        self.hours = (time_of_day / 3600 % 24) as u8;
+        self.add_days(time_of_day / SECONDS_PER_DAY);
+    }
+
+    /// Register 08-0C as the CPU sees it.
+    pub fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.days as u8,
+            0x0C => {
+                (self.days >> 8) as u8
+                    | if self.halted { 0x40 } else { 0 }
+                    | if self.day_carry { 0x80 } else { 0 }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, reg: u8, data: u8) {
+        match reg {
+            0x08 => self.seconds = data & 0x3F,
+            0x09 => self.minutes = data & 0x3F,
+            0x0A => self.hours = data & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | data as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | ((data & 0x1) as u16) << 8;
+                self.halted = data & 0x40 != 0;
+                self.day_carry = data & 0x80 != 0;
+            }
+            _ => (),
+        }
+    }
+
+    fn to_footer(self, out: &mut [u8]) {
+        for (reg, chunk) in (0x08..=0x0C).zip(out.chunks_mut(4)) {
+            chunk.copy_from_slice(&(self.read(reg) as u32).to_le_bytes());
+        }
+    }
+
+    fn from_footer(data: &[u8]) -> RtcRegisters {
+        let mut regs = RtcRegisters::default();
+        for (reg, chunk) in (0x08..=0x0C).zip(data.chunks(4)) {
+            regs.write(reg, chunk[0]);
+        }
+        regs
+    }
+}
+
+/// A cartridge real time clock: the running registers, the copy software
+/// reads after latching, and the clock that drives them.
+///
+/// The registers only catch up with the clock when something looks at or
+/// changes them, so an idle RTC costs nothing.
+pub struct Rtc {
+    current: RtcRegisters,
+    latched: RtcRegisters,
+    clock: Box<dyn RtcClock>,
+    /// Clock time the current registers were last brought up to date.
+    last_sync: u64,
+    /// Emulated cycles since power on, as of the last tick.
+    cycles: u64,
+}
+
+impl Rtc {
+    pub fn new(clock: Box<dyn RtcClock>) -> Rtc {
+        let last_sync = clock.now(0);
+        Rtc {
+            current: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+            clock,
+            last_sync,
+            cycles: 0,
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: Box<dyn RtcClock>) {
+        self.clock = clock;
+        self.last_sync = self.clock.now(self.cycles);
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        self.cycles = cycles;
+    }
+
+    fn sync(&mut self) {
+        let now = self.clock.now(self.cycles);
+        if !self.current.halted {
+            self.current.advance(now.saturating_sub(self.last_sync));
+        }
+        self.last_sync = now;
+    }
+
+    /// The running registers, brought up to date.
+    pub fn current(&mut self) -> RtcRegisters {
+        self.sync();
+        self.current
+    }
+
+    pub fn latched(&self) -> RtcRegisters {
+        self.latched
+    }
+
+    pub fn latch(&mut self) {
+        self.sync();
+        self.latched = self.current;
+    }
+
+    pub fn read(&self, reg: u8) -> u8 {
+        self.latched.read(reg)
+    }
+
+    /// Writes go to the running registers. The latched copy is updated too
+    /// so software reads back what it wrote.
+    pub fn write(&mut self, reg: u8, data: u8) {
+        self.sync();
+        self.current.write(reg, data);
+        self.latched.write(reg, data);
+    }
+
+    pub fn footer(&mut self) -> [u8; RTC_FOOTER_SIZE] {
+        self.sync();
+        let mut footer = [0u8; RTC_FOOTER_SIZE];
+        self.current.to_footer(&mut footer[0..20]);
+        self.latched.to_footer(&mut footer[20..40]);
+        let timestamp = footer_timestamp(&*self.clock, self.cycles);
+        footer[40..48].copy_from_slice(&timestamp.to_le_bytes());
+        footer
+    }
+
+    /// Restore from a save file footer. With a real time clock the
+    /// registers also catch up on the time since the file was written.
+    /// Returns false if `data` isn't a footer.
+    pub fn load_footer(&mut self, data: &[u8]) -> bool {
+        if data.len() != RTC_FOOTER_SIZE {
+            return false;
+        }
+        self.current = RtcRegisters::from_footer(&data[0..20]);
+        self.latched = RtcRegisters::from_footer(&data[20..40]);
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&data[40..48]);
+        self.last_sync = footer_last_sync(&*self.clock, self.cycles, u64::from_le_bytes(timestamp));
+        self.sync();
+        true
+    }
+}
+
+fn save_registers(regs: &RtcRegisters, w: &mut StateWriter) {
+    for reg in 0x08..=0x0C {
+        w.write_u8(regs.read(reg));
+    }
+}
+
+fn load_registers(r: &mut StateReader) -> StateResult<RtcRegisters> {
+    let mut regs = RtcRegisters::default();
+    for reg in 0x08..=0x0C {
+        regs.write(reg, r.read_u8()?);
+    }
+    Ok(regs)
+}
+
+// States keep the seconds not yet counted rather than a time on the clock,
+// as the clock they're loaded under may count from a different origin.
+impl SaveState for Rtc {
+    fn save_state(&self, w: &mut StateWriter) {
+        save_registers(&self.current, w);
+        save_registers(&self.latched, w);
+        w.write_u64(self.clock.now(self.cycles).saturating_sub(self.last_sync));
+        w.write_u64(self.cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.current = load_registers(r)?;
+        self.latched = load_registers(r)?;
+        let pending = r.read_u64()?;
+        self.cycles = r.read_u64()?;
+        if !self.current.halted {
+            self.current.advance(pending);
+        }
+        self.last_sync = self.clock.now(self.cycles);
+        Ok(())
+    }
+}
+
+#[test]
+fn rtc_counts_and_wraps() {
+    let mut rtc = Rtc::new(Box::new(CycleClock));
+    rtc.write(0x08, 59);
+    rtc.write(0x09, 59);
+    rtc.write(0x0A, 23);
+    rtc.write(0x0B, 0xFF);
+    rtc.write(0x0C, 0x01);
+    rtc.tick(CPU_CLOCK_HZ);
+    let regs = rtc.current();
+    assert_eq!((regs.seconds, regs.minutes, regs.hours, regs.days), (0, 0, 0, 0));
+    assert!(regs.day_carry);
+
+    // Out of range values count up to the register width, then wrap
+    // without carrying
+    rtc.write(0x0C, 0x00);
+    rtc.write(0x08, 62);
+    rtc.tick(CPU_CLOCK_HZ * 4);
+    let regs = rtc.current();
+    assert_eq!((regs.seconds, regs.minutes), (1, 0));
+
+    // Halted clocks stand still
+    rtc.write(0x0C, 0x40);
+    rtc.tick(CPU_CLOCK_HZ * 100);
+    assert_eq!(rtc.current().seconds, 1);
+    rtc.write(0x0C, 0x00);
+    rtc.tick(CPU_CLOCK_HZ * 3700);
+    let regs = rtc.current();
+    assert_eq!((regs.seconds, regs.minutes, regs.hours), (1, 0, 1));
+}
+
+#[test]
+fn rtc_footer_round_trip() {
+    let mut rtc = Rtc::new(Box::new(CycleClock));
+    rtc.write(0x09, 12);
+    rtc.write(0x0B, 0x34);
+    rtc.latch();
+    rtc.write(0x08, 5);
+    let footer = rtc.footer();
+    assert_eq!(&footer[0..8], &[5, 0, 0, 0, 12, 0, 0, 0]);
+    assert_eq!(footer[32], 0x34);
+
+    let mut restored = Rtc::new(Box::new(CycleClock));
+    assert!(restored.load_footer(&footer));
+    assert_eq!(restored.current().seconds, 5);
+    assert_eq!(restored.latched().minutes, 12);
+    assert_eq!(restored.read(0x08), 5);
+    assert!(!restored.load_footer(&footer[..44]));
+}
+
+#[test]
+fn rtc_state_across_clocks() {
+    let mut w = StateWriter::new();
+    let mut wall = Rtc::new(Box::new(WallClock));
+    wall.write(0x09, 30);
+    wall.save_state(&mut w);
+    let state = w.into_bytes();
+
+    // A wall clock state under the cycle clock keeps running
+    let mut cycles = Rtc::new(Box::new(CycleClock));
+    cycles.load_state(&mut StateReader::new(&state).unwrap()).unwrap();
+    cycles.tick(CPU_CLOCK_HZ * 2);
+    let regs = cycles.current();
+    assert_eq!((regs.seconds, regs.minutes), (2, 30));
+
+    // A cycle clock state deep into a run doesn't jump ahead under the wall
+    // clock
+    let mut w = StateWriter::new();
+    cycles.tick(CPU_CLOCK_HZ * 1_000_000);
+    cycles.save_state(&mut w);
+    let state = w.into_bytes();
+    let mut wall = Rtc::new(Box::new(WallClock));
+    wall.load_state(&mut StateReader::new(&state).unwrap()).unwrap();
+    let regs = wall.current();
+    assert_eq!(u64::from(regs.days), 1_000_000 / SECONDS_PER_DAY);
+    assert!(!regs.day_carry);
+}
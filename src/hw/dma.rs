@@ -0,0 +1,294 @@
+use crate::hw::memory::BusWidth;
+use crate::savestate::{SaveState, StateError, StateReader, StateResult, StateWriter};
+
+pub const OAM_DMA_LEN: u16 = 0xA0;
+
+// M-cycles between the write to FF46 and the first byte being copied.
+const OAM_DMA_START_DELAY: u8 = 1;
+
+/// OAM DMA controller. Copies one byte per M-cycle from `source` into OAM.
+///
+/// A write to FF46 while a transfer is running restarts it, but the old
+/// transfer keeps going (and keeps the bus locked) until the new one has
+/// finished its start delay.
+pub struct OamDma {
+    reg: u8,
+    source: BusWidth,
+    index: Option<u16>,
+    pending: Option<(BusWidth, u8)>,
+    last_byte: u8,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DmaBus {
+    External,
+    Video,
+}
+
+impl DmaBus {
+    /// Which bus an address lives on, as far as OAM DMA conflicts go.
+    /// Accesses to FE00 and above never conflict.
+    pub fn for_addr(addr: BusWidth) -> Option<DmaBus> {
+        match addr {
+            0x8000..=0x9FFF => Some(DmaBus::Video),
+            0x0000..=0xFDFF => Some(DmaBus::External),
+            _ => None,
+        }
+    }
+}
+
+impl OamDma {
+    pub fn new() -> OamDma {
+        OamDma {
+            reg: 0xFF,
+            source: 0,
+            index: None,
+            pending: None,
+            last_byte: 0xFF,
+        }
+    }
+
+    pub fn read_reg(&self) -> u8 {
+        self.reg
+    }
+
+    pub fn write_reg(&mut self, data: u8) {
+        self.reg = data;
+        self.pending = Some(((data as BusWidth) << 8, OAM_DMA_START_DELAY));
+    }
+
+    /// True while bytes are being copied, which is when the CPU is locked
+    /// out of everything but HRAM and the PPU can't see OAM.
+    pub fn is_active(&self) -> bool {
+        self.index.is_some()
+    }
+
+    /// True when there's neither a transfer running nor one about to start.
+    pub fn is_idle(&self) -> bool {
+        self.index.is_none() && self.pending.is_none()
+    }
+
+    /// The bus the running transfer is reading from.
+    pub fn source_bus(&self) -> Option<DmaBus> {
+        self.index.and_then(|_| DmaBus::for_addr(self.source))
+    }
+
+    /// The byte most recently driven onto the source bus. This is what the
+    /// CPU sees when it reads from the same bus during a transfer.
+    pub fn last_byte(&self) -> u8 {
+        self.last_byte
+    }
+
+    /// Advance one M-cycle. Returns the (source, OAM offset) pair to copy
+    /// this cycle, if any; the caller performs the actual transfer and
+    /// reports the byte back through `transferred`.
+    pub fn step(&mut self) -> Option<(BusWidth, u16)> {
+        let copy = self.index.map(|idx| {
+            let next = idx + 1;
+            self.index = if next < OAM_DMA_LEN { Some(next) } else { None };
+            (self.source + idx, idx)
+        });
+
+        if let Some((source, delay)) = self.pending {
+            if delay <= 1 {
+                self.pending = None;
+                self.source = source;
+                self.index = Some(0);
+            } else {
+                self.pending = Some((source, delay - 1));
+            }
+        }
+
+        copy
+    }
+
+    pub fn transferred(&mut self, data: u8) {
+        self.last_byte = data;
+    }
+}
+
+impl SaveState for OamDma {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.reg);
+        w.write_u16(self.source);
+        w.write_u16(self.index.unwrap_or(0xFFFF));
+        let (pending_source, pending_delay) = self.pending.unwrap_or((0, 0));
+        w.write_u16(pending_source);
+        w.write_u8(pending_delay);
+        w.write_u8(self.last_byte);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.reg = r.read_u8()?;
+        self.source = r.read_u16()?;
+        self.index = match r.read_u16()? {
+            0xFFFF => None,
+            idx => Some(idx),
+        };
+        let pending_source = r.read_u16()?;
+        let pending_delay = r.read_u8()?;
+        self.pending = match pending_delay {
+            0 => None,
+            delay => Some((pending_source, delay)),
+        };
+        self.last_byte = r.read_u8()?;
+        Ok(())
+    }
+}
+
+/// Bytes copied per HBlank, and the unit HDMA5 counts lengths in.
+pub const VRAM_DMA_BLOCK: u16 = 0x10;
+
+/// Clock cycles the CPU is halted for each block copied.
+pub const VRAM_DMA_BLOCK_CYCLES: u32 = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum VramDmaMode {
+    Idle,
+    /// General purpose DMA. Copies everything at once with the CPU halted.
+    General,
+    /// Copies one block at the start of each HBlank.
+    HBlank,
+}
+
+/// CGB VRAM DMA controller (HDMA1-HDMA5).
+///
+/// The controller only keeps the addresses and the remaining length;
+/// `Memory` moves the bytes a block at a time through `next_block`.
+pub struct VramDma {
+    source: BusWidth,
+    dest: BusWidth,
+    /// Blocks left to copy, 0 to 0x80.
+    remaining: u8,
+    mode: VramDmaMode,
+}
+
+impl VramDma {
+    pub fn new() -> VramDma {
+        VramDma {
+            source: 0,
+            dest: 0x8000,
+            remaining: 0,
+            mode: VramDmaMode::Idle,
+        }
+    }
+
+    /// HDMA1-HDMA4 are write only. HDMA5 reads the blocks left minus one,
+    /// with bit 7 clear while an HBlank transfer is running. A finished
+    /// transfer reads FF.
+    pub fn read_reg(&self, addr: BusWidth) -> u8 {
+        match addr {
+            0xFF55 => {
+                let length = self.remaining.wrapping_sub(1) & 0x7F;
+                match self.mode {
+                    VramDmaMode::HBlank => length,
+                    _ => 0x80 | length,
+                }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_reg(&mut self, addr: BusWidth, data: u8) {
+        match addr {
+            0xFF51 => self.source = (self.source & 0x00FF) | (data as BusWidth) << 8,
+            0xFF52 => self.source = (self.source & 0xFF00) | (data & 0xF0) as BusWidth,
+            0xFF53 => {
+                self.dest = 0x8000 | (self.dest & 0x00FF) | ((data & 0x1F) as BusWidth) << 8
+            }
+            0xFF54 => self.dest = (self.dest & 0xFF00) | (data & 0xF0) as BusWidth,
+            0xFF55 => {
+                if self.mode == VramDmaMode::HBlank && data & 0x80 == 0 {
+                    // Cancel. The remaining length stays readable.
+                    self.mode = VramDmaMode::Idle;
+                    return;
+                }
+                self.remaining = (data & 0x7F) + 1;
+                self.mode = if data & 0x80 != 0 {
+                    VramDmaMode::HBlank
+                } else {
+                    VramDmaMode::General
+                };
+            }
+            _ => (),
+        }
+    }
+
+    /// True when a general purpose transfer is waiting to be run.
+    pub fn general_pending(&self) -> bool {
+        self.mode == VramDmaMode::General
+    }
+
+    pub fn hblank_active(&self) -> bool {
+        self.mode == VramDmaMode::HBlank
+    }
+
+    /// Take the next block to copy as (source, VRAM destination), advancing
+    /// both addresses. The destination wraps within VRAM.
+    pub fn next_block(&mut self) -> Option<(BusWidth, BusWidth)> {
+        if self.mode == VramDmaMode::Idle {
+            return None;
+        }
+        let block = (self.source, self.dest);
+        self.source = self.source.wrapping_add(VRAM_DMA_BLOCK);
+        self.dest = 0x8000 | (self.dest.wrapping_add(VRAM_DMA_BLOCK) & 0x1FF0);
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.mode = VramDmaMode::Idle;
+        }
+        Some(block)
+    }
+}
+
+impl SaveState for VramDma {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.source);
+        w.write_u16(self.dest);
+        w.write_u8(self.remaining);
+        w.write_u8(self.mode as u8);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.source = r.read_u16()?;
+        self.dest = r.read_u16()?;
+        self.remaining = r.read_u8()?;
+        self.mode = match r.read_u8()? {
+            0 => VramDmaMode::Idle,
+            1 => VramDmaMode::General,
+            2 => VramDmaMode::HBlank,
+            _ => return Err(StateError::Invalid("VRAM DMA mode")),
+        };
+        if self.remaining > 0x80 || (self.remaining == 0 && self.mode != VramDmaMode::Idle) {
+            return Err(StateError::Invalid("VRAM DMA length"));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn vram_dma_registers() {
+    let mut dma = VramDma::new();
+    dma.write_reg(0xFF51, 0xC1);
+    dma.write_reg(0xFF52, 0x2F);
+    dma.write_reg(0xFF53, 0xFF);
+    dma.write_reg(0xFF54, 0xE5);
+    assert_eq!(dma.read_reg(0xFF51), 0xFF);
+    assert_eq!(dma.read_reg(0xFF55), 0xFF);
+
+    dma.write_reg(0xFF55, 0x81);
+    assert!(dma.hblank_active());
+    assert_eq!(dma.read_reg(0xFF55), 0x01);
+    assert_eq!(dma.next_block(), Some((0xC120, 0x9FE0)));
+    assert_eq!(dma.read_reg(0xFF55), 0x00);
+    assert_eq!(dma.next_block(), Some((0xC130, 0x9FF0)));
+    assert_eq!(dma.next_block(), None);
+    assert_eq!(dma.read_reg(0xFF55), 0xFF);
+
+    dma.write_reg(0xFF55, 0x83);
+    // Destination wraps back to the start of VRAM
+    assert_eq!(dma.next_block(), Some((0xC140, 0x8000)));
+    dma.write_reg(0xFF55, 0x00);
+    assert!(!dma.hblank_active());
+    assert_eq!(dma.read_reg(0xFF55), 0x82);
+    assert_eq!(dma.next_block(), None);
+}
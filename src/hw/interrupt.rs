@@ -0,0 +1,102 @@
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InterruptType {
+    VBlank = 0x1,
+    LCDC = 0x2,
+    Timer = 0x4,
+    SerialTransferDone = 0x8,
+    PinFallingEdge = 0x10,
+}
+
+/// Highest priority first.
+const PRIORITY: [InterruptType; 5] = [
+    InterruptType::VBlank,
+    InterruptType::LCDC,
+    InterruptType::Timer,
+    InterruptType::SerialTransferDone,
+    InterruptType::PinFallingEdge,
+];
+
+impl InterruptType {
+    pub fn vector(self) -> u16 {
+        match self {
+            InterruptType::VBlank => 0x0040,
+            InterruptType::LCDC => 0x0048,
+            InterruptType::Timer => 0x0050,
+            InterruptType::SerialTransferDone => 0x0058,
+            InterruptType::PinFallingEdge => 0x0060,
+        }
+    }
+}
+
+/// The IE (FFFF) and IF (FF0F) registers. Peripherals raise requests here
+/// and the CPU services them.
+pub struct InterruptController {
+    enable: u8,
+    flag: u8,
+}
+
+impl InterruptController {
+    pub fn new() -> InterruptController {
+        InterruptController { enable: 0, flag: 0 }
+    }
+
+    pub fn request(&mut self, interrupt_type: InterruptType) {
+        self.flag |= interrupt_type as u8;
+    }
+
+    pub fn acknowledge(&mut self, interrupt_type: InterruptType) {
+        self.flag &= !(interrupt_type as u8);
+    }
+
+    /// The highest priority interrupt that is both requested and enabled.
+    pub fn pending(&self) -> Option<InterruptType> {
+        let active = self.enable & self.flag;
+        PRIORITY.iter().cloned().find(|&t| active & t as u8 != 0)
+    }
+
+    pub fn read_ie(&self) -> u8 {
+        self.enable
+    }
+
+    pub fn write_ie(&mut self, data: u8) {
+        self.enable = data;
+    }
+
+    /// Only five request lines exist; the upper bits read as 1.
+    pub fn read_if(&self) -> u8 {
+        0xE0 | self.flag
+    }
+
+    pub fn write_if(&mut self, data: u8) {
+        self.flag = data & 0x1F;
+    }
+}
+
+impl SaveState for InterruptController {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.enable);
+        w.write_u8(self.flag);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.enable = r.read_u8()?;
+        self.flag = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn interrupt_priority() {
+    let mut interrupts = InterruptController::new();
+    interrupts.request(InterruptType::Timer);
+    interrupts.request(InterruptType::PinFallingEdge);
+    assert_eq!(interrupts.pending(), None);
+
+    interrupts.write_ie(0x1F);
+    assert_eq!(interrupts.pending(), Some(InterruptType::Timer));
+    interrupts.acknowledge(InterruptType::Timer);
+    assert_eq!(interrupts.pending(), Some(InterruptType::PinFallingEdge));
+    assert_eq!(interrupts.read_if(), 0xF0);
+}
@@ -1,22 +1,68 @@
+use crate::hw::io_regs::{self, IoHandler, IoRegister};
+use crate::hw::joypad::Joypad;
 use crate::hw::lcd::LCD;
 use crate::hw::memory::{Bus, BusWidth};
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
 
 pub struct IO {
     ioram: Vec<u8>,
+    registers: Vec<Option<&'static IoRegister>>,
     pub lcd: LCD,
+    pub joypad: Joypad,
+    pub cgb_mode: bool,
 }
 
 impl IO {
     pub fn new() -> IO {
-        let mut io = IO {
+        IO {
             ioram: vec![0u8; 0x80], // FF00-FF7F
+            registers: (0xFF00..=0xFF7F).map(io_regs::lookup).collect(),
             lcd: LCD::new(),
-        };
-        io.ioram[0] = 0b0000_1111;
-        io
+            joypad: Joypad::new(),
+            cgb_mode: false,
+        }
+    }
+
+    pub fn new_cgb() -> IO {
+        IO {
+            lcd: LCD::new_cgb(),
+            cgb_mode: true,
+            ..IO::new()
+        }
     }
 
-    // TODO joypad
+    /// The register mapped at `addr` in the current hardware mode.
+    pub fn register(&self, addr: BusWidth) -> Option<&'static IoRegister> {
+        self.registers[(addr - 0xFF00) as usize].filter(|reg| self.cgb_mode || !reg.cgb_only)
+    }
+
+    fn read_raw(&self, reg: &IoRegister) -> u8 {
+        match reg.handler {
+            IoHandler::Joypad => self.joypad.read(),
+            IoHandler::Lcd => self.lcd.read8(reg.addr),
+            IoHandler::Ram
+            | IoHandler::Timer
+            | IoHandler::Interrupt
+            | IoHandler::OamDma
+            | IoHandler::VramDma => {
+                self.ioram[(reg.addr - 0xFF00) as usize]
+            }
+        }
+    }
+
+    fn write_raw(&mut self, reg: &IoRegister, data: u8) {
+        match reg.handler {
+            IoHandler::Joypad => self.joypad.write(data),
+            IoHandler::Lcd => self.lcd.write8(reg.addr, data),
+            IoHandler::Ram
+            | IoHandler::Timer
+            | IoHandler::Interrupt
+            | IoHandler::OamDma
+            | IoHandler::VramDma => {
+                self.ioram[(reg.addr - 0xFF00) as usize] = data
+            }
+        }
+    }
 
     // TODO sound
 }
@@ -30,14 +76,13 @@ impl Bus for IO {
             0xFE00..=0xFE9F => {
                 self.lcd.write8(addr, data);
             }
-            0xFF00 => {
-                // TODO joypad support
-            }
-            0xFF40..=0xFF4B => {
-                self.lcd.write8(addr, data);
-            }
             0xFF00..=0xFF7F => {
-                self.ioram[(addr - 0xFF00) as usize] = data;
+                if let Some(reg) = self.register(addr) {
+                    if reg.write_mask != 0 {
+                        let kept = self.read_raw(reg) & !reg.write_mask;
+                        self.write_raw(reg, kept | (data & reg.write_mask));
+                    }
+                }
             }
             _ => {
                 panic!("Unknown address: {}", addr);
@@ -49,11 +94,52 @@ impl Bus for IO {
         match addr {
             0x8000..=0x9FFF => self.lcd.read8(addr),
             0xFE00..=0xFE9F => self.lcd.read8(addr),
-            0xFF40..=0xFF4B => self.lcd.read8(addr),
-            0xFF00..=0xFF7F => self.ioram[(addr - 0xFF00) as usize],
+            0xFF00..=0xFF7F => match self.register(addr) {
+                Some(reg) => self.read_raw(reg) | !reg.read_mask,
+                None => 0xFF,
+            },
             _ => {
                 panic!("Unknown address: {}", addr);
             }
         }
     }
 }
+
+impl SaveState for IO {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.ioram);
+        self.lcd.save_state(w);
+        self.joypad.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        r.read_into(&mut self.ioram)?;
+        self.lcd.load_state(r)?;
+        self.joypad.load_state(r)
+    }
+}
+
+#[test]
+fn io_read_and_write_masks() {
+    let mut io = IO::new();
+
+    // Unused bits read as 1, unmapped registers read FF
+    io.write8(0xFF07, 0x00);
+    assert_eq!(io.read8(0xFF07), 0xF8);
+    assert_eq!(io.read8(0xFF03), 0xFF);
+    io.write8(0xFF03, 0x00);
+    assert_eq!(io.read8(0xFF03), 0xFF);
+
+    // Write-only and read-only bits
+    io.write8(0xFF13, 0x12);
+    assert_eq!(io.read8(0xFF13), 0xFF);
+    io.write8(0xFF26, 0x0F);
+    assert_eq!(io.read8(0xFF26), 0x70);
+
+    // CGB registers only exist in CGB mode
+    io.write8(0xFF70, 0x03);
+    assert_eq!(io.read8(0xFF70), 0xFF);
+    let mut io = IO::new_cgb();
+    io.write8(0xFF70, 0x03);
+    assert_eq!(io.read8(0xFF70), 0xFB);
+}
@@ -0,0 +1,154 @@
+use crate::hw::memory::BusWidth;
+
+/// Which piece of hardware owns a register. `Ram` registers are plain
+/// latches kept in `IO::ioram`. `Timer`, `Interrupt`, `OamDma` and
+/// `VramDma` registers are intercepted by `Memory` before they reach `IO`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IoHandler {
+    Ram,
+    Joypad,
+    Timer,
+    Interrupt,
+    Lcd,
+    OamDma,
+    VramDma,
+}
+
+pub struct IoRegister {
+    pub addr: BusWidth,
+    pub name: &'static str,
+    /// Bits that read back. Everything else reads as 1.
+    pub read_mask: u8,
+    /// Bits the CPU can change. Everything else keeps its value.
+    pub write_mask: u8,
+    /// Register only exists on the CGB; reads FF and ignores writes on DMG.
+    pub cgb_only: bool,
+    pub handler: IoHandler,
+}
+
+macro_rules! io_reg {
+    ($addr:expr, $name:expr, $read:expr, $write:expr, $handler:ident) => {
+        IoRegister {
+            addr: $addr,
+            name: $name,
+            read_mask: $read,
+            write_mask: $write,
+            cgb_only: false,
+            handler: IoHandler::$handler,
+        }
+    };
+    ($addr:expr, $name:expr, $read:expr, $write:expr, $handler:ident, cgb) => {
+        IoRegister {
+            addr: $addr,
+            name: $name,
+            read_mask: $read,
+            write_mask: $write,
+            cgb_only: true,
+            handler: IoHandler::$handler,
+        }
+    };
+}
+
+/// Every register in FF00-FF7F. Addresses missing from this table are
+/// unmapped: they read FF and ignore writes.
+pub static IO_REGISTERS: &[IoRegister] = &[
+    io_reg!(0xFF00, "P1", 0x3F, 0x30, Joypad),
+    io_reg!(0xFF01, "SB", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF02, "SC", 0x81, 0x81, Ram),
+    io_reg!(0xFF04, "DIV", 0xFF, 0xFF, Timer),
+    io_reg!(0xFF05, "TIMA", 0xFF, 0xFF, Timer),
+    io_reg!(0xFF06, "TMA", 0xFF, 0xFF, Timer),
+    io_reg!(0xFF07, "TAC", 0x07, 0x07, Timer),
+    io_reg!(0xFF0F, "IF", 0x1F, 0x1F, Interrupt),
+    io_reg!(0xFF10, "NR10", 0x7F, 0x7F, Ram),
+    io_reg!(0xFF11, "NR11", 0xC0, 0xFF, Ram),
+    io_reg!(0xFF12, "NR12", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF13, "NR13", 0x00, 0xFF, Ram),
+    io_reg!(0xFF14, "NR14", 0x40, 0xC7, Ram),
+    io_reg!(0xFF16, "NR21", 0xC0, 0xFF, Ram),
+    io_reg!(0xFF17, "NR22", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF18, "NR23", 0x00, 0xFF, Ram),
+    io_reg!(0xFF19, "NR24", 0x40, 0xC7, Ram),
+    io_reg!(0xFF1A, "NR30", 0x80, 0x80, Ram),
+    io_reg!(0xFF1B, "NR31", 0x00, 0xFF, Ram),
+    io_reg!(0xFF1C, "NR32", 0x60, 0x60, Ram),
+    io_reg!(0xFF1D, "NR33", 0x00, 0xFF, Ram),
+    io_reg!(0xFF1E, "NR34", 0x40, 0xC7, Ram),
+    io_reg!(0xFF20, "NR41", 0x00, 0x3F, Ram),
+    io_reg!(0xFF21, "NR42", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF22, "NR43", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF23, "NR44", 0x40, 0xC0, Ram),
+    io_reg!(0xFF24, "NR50", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF25, "NR51", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF26, "NR52", 0x8F, 0x80, Ram),
+    io_reg!(0xFF30, "WAVE0", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF31, "WAVE1", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF32, "WAVE2", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF33, "WAVE3", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF34, "WAVE4", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF35, "WAVE5", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF36, "WAVE6", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF37, "WAVE7", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF38, "WAVE8", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF39, "WAVE9", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF3A, "WAVEA", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF3B, "WAVEB", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF3C, "WAVEC", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF3D, "WAVED", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF3E, "WAVEE", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF3F, "WAVEF", 0xFF, 0xFF, Ram),
+    io_reg!(0xFF40, "LCDC", 0xFF, 0xFF, Lcd),
+    io_reg!(0xFF41, "STAT", 0x7F, 0x78, Lcd),
+    io_reg!(0xFF42, "SCY", 0xFF, 0xFF, Lcd),
+    io_reg!(0xFF43, "SCX", 0xFF, 0xFF, Lcd),
+    io_reg!(0xFF44, "LY", 0xFF, 0x00, Lcd),
+    io_reg!(0xFF45, "LYC", 0xFF, 0xFF, Lcd),
+    io_reg!(0xFF46, "DMA", 0xFF, 0xFF, OamDma),
+    io_reg!(0xFF47, "BGP", 0xFF, 0xFF, Lcd),
+    io_reg!(0xFF48, "OBP0", 0xFF, 0xFF, Lcd),
+    io_reg!(0xFF49, "OBP1", 0xFF, 0xFF, Lcd),
+    io_reg!(0xFF4A, "WY", 0xFF, 0xFF, Lcd),
+    io_reg!(0xFF4B, "WX", 0xFF, 0xFF, Lcd),
+    io_reg!(0xFF4D, "KEY1", 0x81, 0x01, Ram, cgb),
+    io_reg!(0xFF4F, "VBK", 0x01, 0x01, Lcd, cgb),
+    io_reg!(0xFF50, "BOOT", 0x00, 0x01, Ram),
+    io_reg!(0xFF51, "HDMA1", 0x00, 0xFF, VramDma, cgb),
+    io_reg!(0xFF52, "HDMA2", 0x00, 0xF0, VramDma, cgb),
+    io_reg!(0xFF53, "HDMA3", 0x00, 0x1F, VramDma, cgb),
+    io_reg!(0xFF54, "HDMA4", 0x00, 0xF0, VramDma, cgb),
+    io_reg!(0xFF55, "HDMA5", 0xFF, 0xFF, VramDma, cgb),
+    io_reg!(0xFF56, "RP", 0xC3, 0xC1, Ram, cgb),
+    io_reg!(0xFF68, "BCPS", 0xBF, 0xBF, Ram, cgb),
+    io_reg!(0xFF69, "BCPD", 0xFF, 0xFF, Ram, cgb),
+    io_reg!(0xFF6A, "OCPS", 0xBF, 0xBF, Ram, cgb),
+    io_reg!(0xFF6B, "OCPD", 0xFF, 0xFF, Ram, cgb),
+    io_reg!(0xFF6C, "OPRI", 0x01, 0x01, Ram, cgb),
+    io_reg!(0xFF70, "SVBK", 0x07, 0x07, Ram, cgb),
+    io_reg!(0xFF72, "FF72", 0xFF, 0xFF, Ram, cgb),
+    io_reg!(0xFF73, "FF73", 0xFF, 0xFF, Ram, cgb),
+    io_reg!(0xFF74, "FF74", 0xFF, 0xFF, Ram, cgb),
+    io_reg!(0xFF75, "FF75", 0x70, 0x70, Ram, cgb),
+    io_reg!(0xFF76, "PCM12", 0xFF, 0x00, Ram, cgb),
+    io_reg!(0xFF77, "PCM34", 0xFF, 0x00, Ram, cgb),
+];
+
+pub fn lookup(addr: BusWidth) -> Option<&'static IoRegister> {
+    IO_REGISTERS.iter().find(|reg| reg.addr == addr)
+}
+
+/// Register name for an address, including IE which lives outside the
+/// FF00-FF7F block.
+pub fn name(addr: BusWidth) -> Option<&'static str> {
+    match addr {
+        0xFFFF => Some("IE"),
+        _ => lookup(addr).map(|reg| reg.name),
+    }
+}
+
+#[test]
+fn io_table_is_sorted_and_unique() {
+    for pair in IO_REGISTERS.windows(2) {
+        assert!(pair[0].addr < pair[1].addr, "{} out of order", pair[1].name);
+    }
+    assert!(IO_REGISTERS.iter().all(|reg| (0xFF00..=0xFF7F).contains(&reg.addr)));
+}
@@ -0,0 +1,82 @@
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
+
+// Button bits as stored in a frame's input byte. The lower nibble lines up
+// with the P1 direction lines and the upper nibble with the button lines.
+pub const BUTTON_RIGHT: u8 = 0x01;
+pub const BUTTON_LEFT: u8 = 0x02;
+pub const BUTTON_UP: u8 = 0x04;
+pub const BUTTON_DOWN: u8 = 0x08;
+pub const BUTTON_A: u8 = 0x10;
+pub const BUTTON_B: u8 = 0x20;
+pub const BUTTON_SELECT: u8 = 0x40;
+pub const BUTTON_START: u8 = 0x80;
+
+const SELECT_DIRECTIONS: u8 = 0x10;
+const SELECT_BUTTONS: u8 = 0x20;
+
+pub struct Joypad {
+    select: u8,
+    pressed: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: SELECT_DIRECTIONS | SELECT_BUTTONS,
+            pressed: 0,
+        }
+    }
+
+    /// Set the full button state, one bit per button (1 = pressed).
+    pub fn set_buttons(&mut self, pressed: u8) {
+        self.pressed = pressed;
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.pressed
+    }
+
+    pub fn read(&self) -> u8 {
+        let mut lines = 0x0F;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            lines &= !(self.pressed & 0x0F);
+        }
+        if self.select & SELECT_BUTTONS == 0 {
+            lines &= !(self.pressed >> 4);
+        }
+        0xC0 | self.select | lines
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.select = data & (SELECT_DIRECTIONS | SELECT_BUTTONS);
+    }
+}
+
+impl SaveState for Joypad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.select);
+        w.write_u8(self.pressed);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.select = r.read_u8()?;
+        self.pressed = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn joypad_select_lines() {
+    let mut joypad = Joypad::new();
+    joypad.set_buttons(BUTTON_DOWN | BUTTON_START);
+    assert_eq!(joypad.read(), 0xFF);
+
+    joypad.write(SELECT_BUTTONS); // select directions
+    assert_eq!(joypad.read() & 0x0F, 0b0111);
+
+    joypad.write(SELECT_DIRECTIONS); // select buttons
+    assert_eq!(joypad.read() & 0x0F, 0b0111);
+
+    joypad.set_buttons(BUTTON_A);
+    assert_eq!(joypad.read() & 0x0F, 0b1110);
+}
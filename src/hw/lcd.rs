@@ -1,5 +1,8 @@
 use crate::emu_log;
+use crate::hw::interrupt::{InterruptController, InterruptType};
 use crate::hw::memory::{Bus, BusWidth};
+use crate::hw::page_table::PageBuffer;
+use crate::savestate::{SaveState, StateError, StateReader, StateResult, StateWriter};
 use rgb::RGBA8;
 
 const OAM_TICKS: u16 = 80;
@@ -7,6 +10,7 @@ const OAM_AND_VRAM_TICKS: u16 = 172;
 const HBLANK_TICKS: u16 = 204;
 const FULL_LINE_TICKS: u16 = OAM_TICKS + OAM_AND_VRAM_TICKS + HBLANK_TICKS;
 const VBLANK_TICKS: u16 = 4560;
+const VRAM_BANK_SIZE: usize = 0x2000;
 
 pub struct Point {
     x: u8,
@@ -38,45 +42,74 @@ pub enum LcdControllerMode {
 
 // TODO should vram be put in here?
 pub struct LCD {
-    vram: Vec<u8>,
+    vram: PageBuffer,
+    vram_bank: usize,
     oam: Vec<u8>,
     lcdram: Vec<u8>,
     pub lcd_display: Vec<RGBA8>,
     pub drawing_state: LcdControllerMode,
+    oam_dma_active: bool,
 }
 
 impl Bus for LCD {
     fn write8(&mut self, addr: BusWidth, data: u8) {
         match addr {
             0x8000..=0x9FFF => {
-                self.vram[(addr - 0x8000) as usize] = data;
+                let offset = self.vram_offset(addr);
+                self.vram[offset] = data;
             }
             0xFE00..=0xFE9F => {
                 self.oam[(addr - 0xFE00) as usize] = data;
             }
             0xFF46 => panic!("Should not have DMA addr in LCD"),
-            0xFF41 => println!("Writing to LCDSTAT not currently supported!"),
+            0xFF41 => self.lcdram[0x1] = data & 0x78,
             0xFF40..=0xFF4B => self.lcdram[(addr as usize) - 0xFF40] = data,
+            0xFF4F => self.vram_bank = (data & 0x1) as usize % self.vram_banks(),
             _ => panic!("Illegal write address {} for LCD", addr),
         }
     }
 
     fn read8(&self, addr: BusWidth) -> u8 {
         match addr {
-            0x8000..=0x9FFF => self.vram[(addr - 0x8000) as usize],
+            0x8000..=0x9FFF => self.vram[self.vram_offset(addr)],
             0xFE00..=0xFE9F => self.oam[(addr - 0xFE00) as usize],
             0xFF46 => panic!("Should not have DMA addr in LCD"),
             0xFF41 => self.lcdstat(),
             0xFF40..=0xFF4B => self.lcdram[(addr as usize) - 0xFF40],
+            0xFF4F => 0xFE | self.vram_bank as u8,
             _ => panic!("Illegal read address {} for LCD", addr),
         }
     }
+
+    fn direct_page(&self, addr: BusWidth) -> Option<&[u8]> {
+        match addr {
+            0x8000..=0x9FFF => Some(&self.vram[(self.vram_offset(addr) & !0xFF)..]),
+            _ => None,
+        }
+    }
+
+    fn direct_page_mut(&self, addr: BusWidth) -> Option<(&PageBuffer, usize)> {
+        match addr {
+            0x8000..=0x9FFF => Some((&self.vram, self.vram_offset(addr) & !0xFF)),
+            _ => None,
+        }
+    }
 }
 
 impl LCD {
     pub fn new() -> LCD {
+        LCD::with_vram_banks(1)
+    }
+
+    /// The CGB has a second VRAM bank, switched through VBK (FF4F).
+    pub fn new_cgb() -> LCD {
+        LCD::with_vram_banks(2)
+    }
+
+    fn with_vram_banks(banks: usize) -> LCD {
         LCD {
-            vram: vec![0u8; 8 * 1024],
+            vram: PageBuffer::new(banks * VRAM_BANK_SIZE),
+            vram_bank: 0,
             oam: vec![0u8; 0x100],
             lcdram: vec![0u8; 0xC], // FF40 - FF4B
             lcd_display: vec![
@@ -89,11 +122,50 @@ impl LCD {
                 160 * 144
             ],
             drawing_state: LcdControllerMode::OamAccess(0),
+            oam_dma_active: false,
+        }
+    }
+
+    fn vram_banks(&self) -> usize {
+        self.vram.len() / VRAM_BANK_SIZE
+    }
+
+    fn vram_offset(&self, addr: BusWidth) -> usize {
+        self.vram_bank * VRAM_BANK_SIZE + (addr - 0x8000) as usize
+    }
+
+    /// Read VRAM from a specific bank regardless of VBK.
+    pub fn vram_read(&self, bank: usize, addr: BusWidth) -> Option<u8> {
+        if bank < self.vram_banks() {
+            Some(self.vram[bank * VRAM_BANK_SIZE + (addr - 0x8000) as usize])
+        } else {
+            None
+        }
+    }
+
+    pub fn vram_bank(&self) -> usize {
+        self.vram_bank
+    }
+
+    /// OAM is driven by the DMA unit while a transfer runs, so the PPU can't
+    /// read sprite attributes until it finishes.
+    pub fn set_oam_dma_active(&mut self, active: bool) {
+        self.oam_dma_active = active;
+    }
+
+    /// OAM as seen by the PPU during sprite fetches.
+    pub fn ppu_oam_read(&self, offset: usize) -> u8 {
+        if self.oam_dma_active {
+            0xFF
+        } else {
+            self.oam[offset]
         }
     }
 
-    pub fn tick_update(&mut self) {
+    pub fn tick_update(&mut self, interrupts: &mut InterruptController) {
         use self::LcdControllerMode::*;
+        let old_mode = self.lcdstat() & 0x3;
+        let old_line = self.curline();
         self.drawing_state = match self.drawing_state {
             OamAccess(cnt) => {
                 if cnt >= OAM_TICKS {
@@ -135,12 +207,39 @@ impl LCD {
                 }
             }
         };
+
+        let stat = self.lcdstat();
+        let mode = stat & 0x3;
+        if mode != old_mode {
+            if mode == 1 {
+                interrupts.request(InterruptType::VBlank);
+            }
+            let mode_source = match mode {
+                0 => 0x08,
+                1 => 0x10,
+                2 => 0x20,
+                _ => 0x00,
+            };
+            if stat & mode_source != 0 {
+                interrupts.request(InterruptType::LCDC);
+            }
+        }
+        // LY=LYC coincidence, when enabled
+        if self.curline() != old_line && stat & 0x44 == 0x44 {
+            interrupts.request(InterruptType::LCDC);
+        }
     }
 
     pub fn vblank_interrupt_should_trigger(&self) -> bool {
         self.drawing_state == LcdControllerMode::VerticalBlank(0)
     }
 
+    /// True on the tick the controller enters HBlank, which is when an
+    /// HBlank VRAM DMA copies its next block.
+    pub fn hblank_started(&self) -> bool {
+        self.drawing_state == LcdControllerMode::HorizontalBlank(0)
+    }
+
     fn set_lcd_pixel(&mut self, point: Point, rgb: RGBA8) {
         let y = point.y as usize;
         let x = point.x as usize;
@@ -149,8 +248,8 @@ impl LCD {
 
     fn pixel_from_tile(&self, tile_base_addr: u16, (x, y): (u16, u16)) -> u8 {
         let addr = tile_base_addr + (y as BusWidth / 8 * 2);
-        let upper_byte = self.read8(addr);
-        let lower_byte = self.read8(addr + 1);
+        let upper_byte = self.vram[(addr - 0x8000) as usize];
+        let lower_byte = self.vram[(addr + 1 - 0x8000) as usize];
         let upper_result = (upper_byte >> (7 - x)) & 1;
         let lower_result = (lower_byte >> (7 - x)) & 1;
         (upper_result << 1) | (lower_result)
@@ -167,14 +266,14 @@ impl LCD {
         match tpt_addr {
             0x8000 => {
                 let tt_addr = bg_tt_addr + tile_row * 32 + tile_col;
-                let tt_entry = self.read8(tt_addr) as i32;
+                let tt_entry = self.vram[(tt_addr - 0x8000) as usize] as i32;
                 let tile_base_addr = tpt_addr as i32 + tt_entry as i32 * 16;
                 let tile_base_addr = tile_base_addr as u16;
                 self.pixel_from_tile(tile_base_addr, (pixel_col, pixel_row))
             }
             0x9000 => {
                 let tt_addr = bg_tt_addr + tile_row * 32 + tile_col;
-                let tt_entry = self.read8(tt_addr) as i8;
+                let tt_entry = self.vram[(tt_addr - 0x8000) as usize] as i8;
                 let tile_base_addr = (tpt_addr as i32) + (tt_entry as i32) * 16;
                 let tile_base_addr = tile_base_addr as u16;
                 self.pixel_from_tile(tile_base_addr, (pixel_col, pixel_row))
@@ -275,14 +374,15 @@ impl LCD {
 
     // LCDSTAT register accessors
     pub fn lcdstat(&self) -> u8 {
-        // TODO add interrupt and coincidence flag to result
         use self::LcdControllerMode::*;
-        match self.drawing_state {
+        let mode = match self.drawing_state {
             OamAccess(_) => 2,
             OamAndVramAccess(_) => 3,
             HorizontalBlank(_) => 0,
             VerticalBlank(_) => 1,
-        }
+        };
+        let coincidence = if self.curline() == self.cmpline() { 0x4 } else { 0 };
+        (self.lcdram[0x1] & 0x78) | coincidence | mode
     }
 
     pub fn scanline_coincidence_interrupt(&self) -> bool {
@@ -351,6 +451,45 @@ impl LCD {
     }
 }
 
+impl SaveState for LCD {
+    fn save_state(&self, w: &mut StateWriter) {
+        use self::LcdControllerMode::*;
+        w.write_bytes(&self.vram);
+        w.write_bytes(&self.oam);
+        w.write_bytes(&self.lcdram);
+        let (mode, cnt) = match self.drawing_state {
+            OamAccess(cnt) => (0, cnt),
+            OamAndVramAccess(cnt) => (1, cnt),
+            HorizontalBlank(cnt) => (2, cnt),
+            VerticalBlank(cnt) => (3, cnt),
+        };
+        w.write_u8(mode);
+        w.write_u16(cnt);
+        w.write_u8(self.vram_bank as u8);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        use self::LcdControllerMode::*;
+        r.read_into(&mut self.vram)?;
+        r.read_into(&mut self.oam)?;
+        r.read_into(&mut self.lcdram)?;
+        let mode = r.read_u8()?;
+        let cnt = r.read_u16()?;
+        self.drawing_state = match mode {
+            0 => OamAccess(cnt),
+            1 => OamAndVramAccess(cnt),
+            2 => HorizontalBlank(cnt),
+            3 => VerticalBlank(cnt),
+            _ => return Err(StateError::Invalid("LCD mode")),
+        };
+        self.vram_bank = r.read_u8()? as usize;
+        if self.vram_bank >= self.vram_banks() {
+            return Err(StateError::Invalid("VRAM bank"));
+        }
+        Ok(())
+    }
+}
+
 #[test]
 fn basic_lcd() {
     let mut lcd = LCD::new();
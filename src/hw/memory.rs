@@ -1,7 +1,20 @@
+use std::cell::Cell;
+use std::ops::RangeInclusive;
+
+use crate::cheats::{Cheat, Cheats};
+use crate::hw::controller::{Cartridge, RAM_BANK_SIZE};
+use crate::hw::dma::{DmaBus, OamDma, VramDma, VRAM_DMA_BLOCK, VRAM_DMA_BLOCK_CYCLES};
+use crate::hw::interrupt::{InterruptController, InterruptType};
 use crate::hw::io::IO;
+use crate::hw::observer::{AccessKind, AccessMask, MemoryEvent, ObserverFn, ObserverId, Observers};
+use crate::hw::page_table::{PageBuffer, PageTable, PAGE_SIZE};
+use crate::hw::timer::Timer;
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
 
 pub type BusWidth = u16;
 
+const WRAM_BANK_SIZE: usize = 0x1000;
+
 pub trait Bus {
     fn write8(&mut self, addr: BusWidth, data: u8);
 
@@ -15,6 +28,26 @@ pub trait Bus {
         self._read16_using_read8(addr)
     }
 
+    /// Bank number currently mapped at `addr`, for devices that bank
+    /// switch. Unbanked memory always reports bank 0.
+    fn bank_at(&self, _addr: BusWidth) -> u16 {
+        0
+    }
+
+    /// Backing storage for the 256-byte page containing `addr`, starting at
+    /// the beginning of that page, if reads there have no side effects.
+    /// Memory serves such pages straight from its page table.
+    fn direct_page(&self, _addr: BusWidth) -> Option<&[u8]> {
+        None
+    }
+
+    /// Like `direct_page`, for pages where writes have no side effects
+    /// either. Returns the buffer and the page's offset in it, as the page
+    /// table has to take its pointer from the buffer itself.
+    fn direct_page_mut(&self, _addr: BusWidth) -> Option<(&PageBuffer, usize)> {
+        None
+    }
+
     fn _write16_using_write8(&mut self, addr: BusWidth, data: u16) {
         self.write8(addr + 1, (data >> 8) as u8);
         self.write8(addr, (data & 0xFF) as u8);
@@ -28,49 +61,490 @@ pub trait Bus {
 }
 
 pub struct Memory {
-    wram: Vec<u8>,
-    hram: Vec<u8>,
-    pub cartridge: Box<dyn Bus>,
+    wram: PageBuffer,
+    hram: PageBuffer,
+    cartridge: Box<dyn Cartridge>,
+    /// Only cartridges with a clock or a camera need to hear about every
+    /// tick.
+    cartridge_ticks: bool,
+    /// Set by writes to A000-BFFF, for the save file to notice.
+    cartridge_ram_written: bool,
+    cheats: Cheats,
+    /// Cartridge RAM offsets the GameShark has changed, with what the game
+    /// had there. Save files get the game's bytes.
+    cheat_ram: Vec<(usize, u8)>,
     pub io: IO,
+    pub interrupts: InterruptController,
+    timer: Timer,
+    /// M-cycles the current instruction has taken so far: one per bus
+    /// access, plus the internal ones the CPU reports. This lets the timer
+    /// catch up to the exact cycle a timer register is touched on.
+    instr_mcycles: Cell<u32>,
+    /// M-cycles of the current instruction the timer has already run.
+    timer_mcycles: u32,
+    oam_dma: OamDma,
+    vram_dma: VramDma,
+    /// Cycles the CPU is halted for by VRAM DMA, paid off by the next tick.
+    stall_cycles: u32,
+    cycles: u64,
+    observers: Observers,
+    pc: BusWidth,
+    instr_cycle: u64,
+    pages: PageTable,
+    /// Cleared to send every access down the slow path, for benchmarks.
+    pages_enabled: bool,
+    direct_reads: bool,
+    direct_writes: bool,
 }
 
 impl Memory {
-    pub fn new(cartridge: Box<dyn Bus>) -> Memory {
-        Memory {
-            wram: vec![0u8; 8 * 1024],
-            hram: vec![0u8; 0x7F],
+    pub fn new(cartridge: Box<dyn Cartridge>) -> Memory {
+        Memory::with_io(cartridge, IO::new(), Timer::new(), 2)
+    }
+
+    /// CGB memory map: eight WRAM banks switched through SVBK (FF70) and
+    /// two VRAM banks switched through VBK (FF4F).
+    pub fn new_cgb(cartridge: Box<dyn Cartridge>) -> Memory {
+        Memory::with_io(cartridge, IO::new_cgb(), Timer::new_cgb(), 8)
+    }
+
+    fn with_io(
+        cartridge: Box<dyn Cartridge>,
+        io: IO,
+        timer: Timer,
+        wram_banks: usize,
+    ) -> Memory {
+        let mut memory = Memory {
+            wram: PageBuffer::new(wram_banks * WRAM_BANK_SIZE),
+            hram: PageBuffer::new(0x7F),
+            cartridge_ticks: cartridge.needs_tick(),
+            cartridge_ram_written: false,
             cartridge: cartridge,
-            io: IO::new(),
+            cheats: Cheats::new(),
+            cheat_ram: Vec::new(),
+            io,
+            interrupts: InterruptController::new(),
+            timer,
+            instr_mcycles: Cell::new(0),
+            timer_mcycles: 0,
+            oam_dma: OamDma::new(),
+            vram_dma: VramDma::new(),
+            stall_cycles: 0,
+            cycles: 0,
+            observers: Observers::new(),
+            pc: 0,
+            instr_cycle: 0,
+            pages: PageTable::new(),
+            pages_enabled: true,
+            direct_reads: true,
+            direct_writes: true,
+        };
+        memory.map_pages();
+        memory
+    }
+
+    pub fn cartridge(&self) -> &dyn Cartridge {
+        &*self.cartridge
+    }
+
+    /// For save files and other tooling. Bank switches must go through the
+    /// bus instead, or the page table won't follow them.
+    pub fn cartridge_mut(&mut self) -> &mut dyn Cartridge {
+        &mut *self.cartridge
+    }
+
+    /// True if cartridge RAM was written since the last call.
+    pub fn take_cartridge_ram_written(&mut self) -> bool {
+        std::mem::replace(&mut self.cartridge_ram_written, false)
+    }
+
+    /// Every WRAM bank, in order.
+    pub fn wram(&self) -> &[u8] {
+        &self.wram
+    }
+
+    pub fn hram(&self) -> &[u8] {
+        &self.hram
+    }
+
+    pub fn cheats(&self) -> &Cheats {
+        &self.cheats
+    }
+
+    pub fn add_cheat(&mut self, cheat: Cheat) {
+        self.cheats.push(cheat);
+        self.map_cartridge_pages();
+    }
+
+    /// Returns false if there's no cheat `index`.
+    pub fn set_cheat_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        let found = self.cheats.set_enabled(index, enabled);
+        self.map_cartridge_pages();
+        found
+    }
+
+    /// Make the GameShark's RAM writes. It does this at every VBlank.
+    pub fn apply_gameshark(&mut self) {
+        let writes: Vec<(u8, u8, BusWidth)> = self.cheats.ram_writes().collect();
+        for (bank, value, addr) in writes {
+            let bank_num = (bank & 0x0F) as usize;
+            match (bank & 0xF0, addr) {
+                (0x80, 0xA000..=0xBFFF) => {
+                    let offset = bank_num * RAM_BANK_SIZE + (addr - 0xA000) as usize;
+                    if let Some(&before) = self.cartridge.ram().get(offset) {
+                        self.cartridge.ram_mut()[offset] = value;
+                        self.note_cheat_ram(offset, before);
+                    }
+                }
+                (_, 0xA000..=0xBFFF) => {
+                    // Goes through the mapper, which may have a clock
+                    // register rather than RAM switched in
+                    let offset = self.cartridge.ram_offset(addr);
+                    let before = offset.map(|offset| self.cartridge.ram()[offset]);
+                    (*self.cartridge).write8(addr, value);
+                    if let (Some(offset), Some(before)) = (offset, before) {
+                        self.note_cheat_ram(offset, before);
+                    }
+                }
+                (0x90, 0xD000..=0xDFFF) => {
+                    let offset = bank_num.max(1) * WRAM_BANK_SIZE + (addr - 0xD000) as usize;
+                    if let Some(byte) = self.wram.get_mut(offset) {
+                        *byte = value;
+                    }
+                }
+                // Never the ROM area, where writes switch banks
+                (_, 0x8000..=0xFFFF) => self.bus_write8(addr, value),
+                _ => (),
+            }
         }
     }
 
-    fn dma_func(&mut self, data: u8) {
-        let addr = (data as u16) << 8;
-        for i in 0..0xa0 {
-            self.write8(0xfe00 + i, self.read8(addr + i));
+    /// Remember the game's byte under a cheat's write, unless the write
+    /// didn't change it or an earlier one already covered it.
+    fn note_cheat_ram(&mut self, offset: usize, before: u8) {
+        let changed = self.cartridge.ram()[offset] != before;
+        if changed && self.cheat_ram.iter().all(|&(o, _)| o != offset) {
+            self.cheat_ram.push((offset, before));
         }
     }
-}
 
-impl Bus for Memory {
-    fn write8(&mut self, addr: BusWidth, data: u8) {
+    /// The cartridge's `.sav` contents, with the game's own bytes in place
+    /// of the GameShark's.
+    pub fn save_data(&mut self) -> Option<Vec<u8>> {
+        let mut data = self.cartridge.save_data()?;
+        // Battery RAM comes first in a save
+        for &(offset, byte) in &self.cheat_ram {
+            data[offset] = byte;
+        }
+        Some(data)
+    }
+
+    /// Rebuild the whole page table. Needed whenever the buffer behind any
+    /// page may have changed, e.g. after loading a state.
+    fn map_pages(&mut self) {
+        self.map_cartridge_pages();
+        // The buffers belong to `self` and so outlive the table, and no
+        // slice of them is kept past the access it was made for.
+        for addr in (0x8000..=0x9FFF).step_by(PAGE_SIZE) {
+            match self.io.lcd.direct_page_mut(addr) {
+                Some((buf, offset)) => unsafe { self.pages.map_read_write(addr, buf, offset) },
+                None => self.pages.unmap(addr),
+            }
+        }
+        for addr in (0xC000..=0xFDFF).step_by(PAGE_SIZE) {
+            let offset = self.wram_offset(addr);
+            unsafe { self.pages.map_read_write(addr, &self.wram, offset) };
+        }
+        unsafe { self.pages.map_high(&self.hram) };
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        self.io.cgb_mode
+    }
+
+    /// WRAM bank mapped at D000-DFFF. Bank 0 can't be selected there.
+    pub fn wram_bank(&self) -> usize {
+        if !self.is_cgb() {
+            return 1;
+        }
+        match self.io.read8(0xFF70) & 0x7 {
+            0 => 1,
+            bank => bank as usize,
+        }
+    }
+
+    /// Offset into `wram` for C000-FDFF. The echo region at E000 follows
+    /// the same banking as C000.
+    fn wram_offset(&self, addr: BusWidth) -> usize {
+        let offset = (addr & 0x1FFF) as usize;
+        if offset < WRAM_BANK_SIZE {
+            offset
+        } else {
+            self.wram_bank() * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE
+        }
+    }
+
+    /// Like `peek8`, but reading banked WRAM and VRAM from `bank` rather
+    /// than whichever bank is currently switched in. Returns None if the
+    /// bank doesn't exist.
+    pub fn peek8_banked(&self, addr: BusWidth, bank: usize) -> Option<u8> {
+        match addr {
+            0x8000..=0x9FFF => self.io.lcd.vram_read(bank, addr),
+            0xD000..=0xDFFF => self
+                .wram
+                .get(bank * WRAM_BANK_SIZE + (addr - 0xD000) as usize)
+                .cloned(),
+            _ if bank == self.bank_at(addr) as usize => Some(self.peek8(addr)),
+            _ => None,
+        }
+    }
+
+    /// Remap the ROM area after the mapper may have switched banks.
+    /// Pages with Game Genie codes on them have to be read through the bus.
+    fn map_cartridge_pages(&mut self) {
+        for addr in (0x0000..=0x7FFF).step_by(PAGE_SIZE) {
+            match self.cartridge.direct_page(addr) {
+                // Cartridges never write their ROM
+                Some(mem) if !self.cheats.patches_page(addr) => unsafe {
+                    self.pages.map_read(addr, mem)
+                },
+                _ => self.pages.unmap(addr),
+            }
+        }
+    }
+
+    /// The page table is bypassed whenever something needs to see or
+    /// interfere with individual accesses.
+    fn update_direct_access(&mut self) {
+        let direct = self.pages_enabled && !self.oam_dma.is_active();
+        self.direct_reads = direct && !self.observers.wants(AccessKind::Read);
+        self.direct_writes = direct && !self.observers.wants(AccessKind::Write);
+    }
+
+    /// Turn the page table off to decode every access the slow way, as a
+    /// baseline to measure it against.
+    pub fn set_pages_enabled(&mut self, enabled: bool) {
+        self.pages_enabled = enabled;
+        self.update_direct_access();
+    }
+
+    /// Call `func` for every access of one of `kinds` inside `range`.
+    pub fn subscribe(
+        &mut self,
+        kinds: AccessMask,
+        range: RangeInclusive<BusWidth>,
+        func: ObserverFn,
+    ) -> ObserverId {
+        let id = self.observers.subscribe(kinds, range, func);
+        self.update_direct_access();
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: ObserverId) -> bool {
+        let removed = self.observers.unsubscribe(id);
+        self.update_direct_access();
+        removed
+    }
+
+    /// Called by the CPU before it fetches an opcode so that accesses can
+    /// be attributed to the instruction making them.
+    pub fn begin_instruction(&mut self, pc: BusWidth) {
+        self.pc = pc;
+        self.instr_cycle = self.cycles;
+        self.instr_mcycles.set(0);
+        self.timer_mcycles = 0;
+    }
+
+    pub fn notify_execute(&self, addr: BusWidth, opcode: u8) {
+        if self.observers.wants(AccessKind::Execute) {
+            self.notify(AccessKind::Execute, addr, opcode);
+        }
+    }
+
+    fn notify(&self, kind: AccessKind, addr: BusWidth, value: u8) {
+        self.observers.notify(&MemoryEvent {
+            kind,
+            addr,
+            value,
+            pc: self.pc,
+            cycle: self.instr_cycle,
+            bank: self.bank_at(addr),
+        });
+    }
+
+    /// Read without DMA conflicts or observer notifications, for tools that
+    /// must not disturb the machine.
+    pub fn peek8(&self, addr: BusWidth) -> u8 {
+        self.bus_read8(addr)
+    }
+
+    pub fn tick_lcd(&mut self) {
+        self.io.lcd.tick_update(&mut self.interrupts);
+        if self.vram_dma.hblank_active() && self.io.lcd.hblank_started() {
+            self.copy_vram_dma_block();
+        }
+    }
+
+    /// Update the pressed buttons. A newly pressed button on a selected
+    /// line pulls P1 low, which requests the joypad interrupt.
+    pub fn set_buttons(&mut self, pressed: u8) {
+        let before = self.io.joypad.read();
+        self.io.joypad.set_buttons(pressed);
+        if before & !self.io.joypad.read() & 0x0F != 0 {
+            self.interrupts.request(InterruptType::PinFallingEdge);
+        }
+    }
+
+    /// Total clock cycles elapsed since power on.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Advance the memory-side hardware by `cycles` clock cycles.
+    pub fn tick(&mut self, cycles: u8) {
+        let cycles = cycles as u32 + self.stall_cycles;
+        self.stall_cycles = 0;
+        for _ in self.timer_mcycles..cycles / 4 {
+            self.timer.step(&mut self.interrupts);
+        }
+        self.instr_mcycles.set(0);
+        self.timer_mcycles = 0;
+        for _ in 0..cycles / 4 {
+            if !self.oam_dma.is_idle() {
+                self.step_oam_dma();
+            }
+        }
+        self.cycles += cycles as u64;
+        if self.cartridge_ticks {
+            self.cartridge.tick(self.cycles);
+        }
+    }
+
+    /// An M-cycle of the current instruction that doesn't touch the bus.
+    pub fn internal_cycle(&self) {
+        self.instr_mcycles.set(self.instr_mcycles.get() + 1);
+    }
+
+    /// M-cycles the current instruction has taken so far.
+    pub fn instr_mcycles(&self) -> u32 {
+        self.instr_mcycles.get()
+    }
+
+    /// Run the timer up to the M-cycle of the access being made.
+    fn catch_up_timer(&mut self) {
+        while self.timer_mcycles < self.instr_mcycles.get() {
+            self.timer.step(&mut self.interrupts);
+            self.timer_mcycles += 1;
+        }
+    }
+
+    /// The timer register at `addr` as it reads on the M-cycle of the
+    /// access being made. The timer itself only catches up on the next
+    /// write or tick, so this runs a copy of it.
+    fn read_timer(&self, addr: BusWidth) -> u8 {
+        let mut timer = self.timer.clone();
+        let mut interrupts = InterruptController::new();
+        for _ in self.timer_mcycles..self.instr_mcycles.get() {
+            timer.step(&mut interrupts);
+        }
+        timer.read_reg(addr)
+    }
+
+    fn write_vram_dma(&mut self, addr: BusWidth, data: u8) {
+        self.vram_dma.write_reg(addr, data);
+        if self.vram_dma.general_pending() {
+            while self.copy_vram_dma_block() {}
+        } else if self.vram_dma.hblank_active() && !self.io.lcd.lcd_operation() {
+            // With the LCD off there are no HBlanks, the first block is
+            // copied straight away
+            self.copy_vram_dma_block();
+        }
+    }
+
+    /// Copy the next VRAM DMA block into the current VRAM bank, halting
+    /// the CPU while it happens. Returns false if there was nothing left.
+    fn copy_vram_dma_block(&mut self) -> bool {
+        match self.vram_dma.next_block() {
+            Some((source, dest)) => {
+                for offset in 0..VRAM_DMA_BLOCK {
+                    let data = self.bus_read8(source.wrapping_add(offset));
+                    self.io.write8(dest + offset, data);
+                }
+                self.stall_cycles += VRAM_DMA_BLOCK_CYCLES;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn step_oam_dma(&mut self) {
+        let was_active = self.oam_dma.is_active();
+        if let Some((source, offset)) = self.oam_dma.step() {
+            // Sources past the end of WRAM read the echo region
+            let source = match source {
+                0xE000..=0xFFFF => source - 0x2000,
+                _ => source,
+            };
+            let data = self.bus_read8(source);
+            self.oam_dma.transferred(data);
+            self.io.lcd.write8(0xFE00 + offset, data);
+        }
+        if self.oam_dma.is_active() != was_active {
+            self.io.lcd.set_oam_dma_active(self.oam_dma.is_active());
+            self.update_direct_access();
+        }
+    }
+
+    /// While OAM DMA runs the CPU only has the FF00-FFFF range to itself.
+    /// OAM reads back as FF, and anything on the same bus as the transfer
+    /// sees whatever byte the DMA is currently moving.
+    fn dma_conflict(&self, addr: BusWidth) -> Option<u8> {
+        if !self.oam_dma.is_active() {
+            return None;
+        }
+        match addr {
+            0xFE00..=0xFEFF => Some(0xFF),
+            _ => match DmaBus::for_addr(addr) {
+                Some(bus) if Some(bus) == self.oam_dma.source_bus() => {
+                    Some(self.oam_dma.last_byte())
+                }
+                _ => None,
+            },
+        }
+    }
+
+    fn bus_write8(&mut self, addr: BusWidth, data: u8) {
         match addr {
             0x0000..=0x7FFF => {
                 (*self.cartridge).write8(addr, data);
+                self.map_cartridge_pages();
             }
             0x8000..=0x9FFF => {
                 self.io.write8(addr, data);
             }
             0xA000..=0xBFFF => {
                 (*self.cartridge).write8(addr, data);
+                self.cartridge_ram_written = true;
+                if !self.cheat_ram.is_empty() {
+                    // The game's own write replaces what a cheat put there
+                    if let Some(offset) = self.cartridge.ram_offset(addr) {
+                        if self.cartridge.ram()[offset] == data {
+                            self.cheat_ram.retain(|&(o, _)| o != offset);
+                        }
+                    }
+                }
             }
-            0xC000..=0xDFFF => {
-                self.wram[(addr - 0xC000) as usize] = data;
+            0xC000..=0xFDFF => {
+                let offset = self.wram_offset(addr);
+                self.wram[offset] = data;
             }
-            0xE000..=0xFDFF => {
-                self.wram[(addr - 0xE000) as usize] = data;
+            0xFF04..=0xFF07 => {
+                self.catch_up_timer();
+                self.timer.write_reg(addr, data);
             }
-            0xFF46 => self.dma_func(data),
+            0xFF0F => self.interrupts.write_if(data),
+            0xFF46 => self.oam_dma.write_reg(data),
+            0xFF51..=0xFF55 if self.is_cgb() => self.write_vram_dma(addr, data),
             0xFE00..=0xFE9F => {
                 self.io.write8(addr, data);
             }
@@ -79,24 +553,28 @@ impl Bus for Memory {
             }
             0xFF00..=0xFF7F => {
                 self.io.write8(addr, data);
+                // Bank switches change what backs the VRAM and WRAM pages
+                if addr == 0xFF4F || addr == 0xFF70 {
+                    self.map_pages();
+                }
             }
             0xFF80..=0xFFFE => {
                 self.hram[(addr - 0xFF80) as usize] = data;
             }
-            0xFFFF => {
-                // TODO Interrupt enable register
-            }
+            0xFFFF => self.interrupts.write_ie(data),
         };
     }
 
-    fn read8(&self, addr: BusWidth) -> u8 {
+    fn bus_read8(&self, addr: BusWidth) -> u8 {
         match addr {
-            0x000..=0x7FFF => (*self.cartridge).read8(addr),
+            0x000..=0x7FFF => self.cheats.read_rom(addr, (*self.cartridge).read8(addr)),
             0x8000..=0x9FFF => self.io.read8(addr),
             0xA000..=0xBFFF => (*self.cartridge).read8(addr),
-            0xC000..=0xDFFF => self.wram[(addr - 0xC000) as usize],
-            0xE000..=0xFDFF => self.wram[(addr - 0xE000) as usize],
-            0xFF46 => 0,
+            0xC000..=0xFDFF => self.wram[self.wram_offset(addr)],
+            0xFF04..=0xFF07 => self.read_timer(addr),
+            0xFF0F => self.interrupts.read_if(),
+            0xFF46 => self.oam_dma.read_reg(),
+            0xFF51..=0xFF55 if self.is_cgb() => self.vram_dma.read_reg(addr),
             0xFE00..=0xFE9F => self.io.read8(addr),
             0xFEA0..=0xFEFF => {
                 // Unusable memory address, actually a mirror of other memory
@@ -104,11 +582,51 @@ impl Bus for Memory {
             }
             0xFF00..=0xFF7F => self.io.read8(addr),
             0xFF80..=0xFFFE => self.hram[(addr - 0xFF80) as usize],
-            0xFFFF => {
-                // TODO Interrupt enable register
-                0
+            0xFFFF => self.interrupts.read_ie(),
+        }
+    }
+}
+
+impl Bus for Memory {
+    fn write8(&mut self, addr: BusWidth, data: u8) {
+        self.instr_mcycles.set(self.instr_mcycles.get() + 1);
+        if self.direct_writes && self.pages.write(addr, data) {
+            return;
+        }
+        if self.observers.wants(AccessKind::Write) {
+            self.notify(AccessKind::Write, addr, data);
+        }
+        if self.dma_conflict(addr).is_none() {
+            self.bus_write8(addr, data);
+        }
+    }
+
+    fn read8(&self, addr: BusWidth) -> u8 {
+        self.instr_mcycles.set(self.instr_mcycles.get() + 1);
+        if self.direct_reads {
+            if let Some(data) = self.pages.read(addr) {
+                return data;
             }
         }
+        let data = match self.dma_conflict(addr) {
+            Some(data) => data,
+            None => self.bus_read8(addr),
+        };
+        if self.observers.wants(AccessKind::Read) {
+            self.notify(AccessKind::Read, addr, data);
+        }
+        data
+    }
+
+    fn bank_at(&self, addr: BusWidth) -> u16 {
+        match addr {
+            0x0000..=0x3FFF => self.cartridge.bank_state().rom0,
+            0x4000..=0x7FFF => self.cartridge.bank_state().romx,
+            0xA000..=0xBFFF => self.cartridge.bank_state().ram,
+            0x8000..=0x9FFF => self.io.lcd.vram_bank() as u16,
+            0xD000..=0xDFFF | 0xF000..=0xFDFF => self.wram_bank() as u16,
+            _ => 0,
+        }
     }
 
     fn write16(&mut self, addr: BusWidth, data: u16) {
@@ -119,3 +637,305 @@ impl Bus for Memory {
         self._read16_using_read8(addr)
     }
 }
+
+impl SaveState for Memory {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.wram);
+        w.write_bytes(&self.hram);
+        self.io.save_state(w);
+        self.interrupts.save_state(w);
+        self.timer.save_state(w);
+        self.oam_dma.save_state(w);
+        self.vram_dma.save_state(w);
+        self.cartridge.save_state(w);
+        w.write_u64(self.cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        r.read_into(&mut self.wram)?;
+        r.read_into(&mut self.hram)?;
+        self.io.load_state(r)?;
+        self.interrupts.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.oam_dma.load_state(r)?;
+        self.vram_dma.load_state(r)?;
+        self.cartridge.load_state(r)?;
+        self.cycles = r.read_u64()?;
+        // The state's RAM is what the game had; cheats rewrite it next VBlank
+        self.cheat_ram.clear();
+        self.map_pages();
+        self.io.lcd.set_oam_dma_active(self.oam_dma.is_active());
+        self.update_direct_access();
+        Ok(())
+    }
+}
+
+#[test]
+fn oam_dma_is_progressive() {
+    use crate::hw::controller::MBC1;
+
+    let mut memory = Memory::new(MBC1::new(vec![0u8; 0x8000]));
+    for i in 0..0xA0 {
+        memory.write8(0xC000 + i, i as u8 + 1);
+    }
+    memory.write8(0xFF80, 0x42);
+    memory.write8(0xFF46, 0xC0);
+    assert_eq!(memory.read8(0xFF46), 0xC0);
+
+    // Start delay, then the first 10 bytes
+    memory.tick(4 * 11);
+    assert!(memory.oam_dma.is_active());
+    assert_eq!(memory.io.lcd.read8(0xFE09), 10);
+    assert_eq!(memory.io.lcd.read8(0xFE0A), 0);
+
+    // The CPU only sees HRAM, OAM reads FF and WRAM reads the DMA byte
+    assert_eq!(memory.read8(0xFF80), 0x42);
+    assert_eq!(memory.read8(0xFE00), 0xFF);
+    assert_eq!(memory.read8(0xC050), 10);
+    memory.write8(0xC050, 0x00);
+
+    for _ in 0..150 {
+        memory.tick(4);
+    }
+    assert!(!memory.oam_dma.is_active());
+    assert_eq!(memory.read8(0xFE9F), 0xA0);
+    assert_eq!(memory.read8(0xC050), 0x51);
+}
+
+#[test]
+fn oam_dma_restart_keeps_old_transfer_running() {
+    use crate::hw::controller::MBC1;
+
+    let mut memory = Memory::new(MBC1::new(vec![0u8; 0x8000]));
+    for i in 0..0xA0 {
+        memory.write8(0xC000 + i, 0x11);
+        memory.write8(0xD000 + i, 0x22);
+    }
+    memory.write8(0xFF46, 0xC0);
+    memory.tick(4 * 5);
+    memory.write8(0xFF46, 0xD0);
+    memory.tick(4);
+    assert!(memory.oam_dma.is_active());
+    assert_eq!(memory.io.lcd.read8(0xFE04), 0x11);
+
+    for _ in 0..160 {
+        memory.tick(4);
+    }
+    assert!(!memory.oam_dma.is_active());
+    assert_eq!(memory.read8(0xFE00), 0x22);
+    assert_eq!(memory.read8(0xFE9F), 0x22);
+}
+
+#[test]
+fn observers_filter_by_kind_and_range() {
+    use crate::hw::controller::MBC1;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let mut memory = Memory::new(MBC1::new(vec![0u8; 0x8000]));
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let sink = events.clone();
+    let id = memory.subscribe(
+        AccessMask::WRITE,
+        0xC000..=0xC0FF,
+        Box::new(move |event| sink.borrow_mut().push(*event)),
+    );
+
+    memory.begin_instruction(0x0150);
+    memory.write8(0xC010, 0x12);
+    memory.write8(0xC100, 0x34);
+    memory.read8(0xC010);
+
+    assert_eq!(events.borrow().len(), 1);
+    let event = events.borrow()[0];
+    assert_eq!(event.kind, AccessKind::Write);
+    assert_eq!((event.addr, event.value, event.pc), (0xC010, 0x12, 0x0150));
+
+    assert!(memory.unsubscribe(id));
+    memory.write8(0xC010, 0x56);
+    assert_eq!(events.borrow().len(), 1);
+}
+
+#[test]
+fn interrupt_registers_on_bus() {
+    use crate::hw::controller::MBC1;
+
+    let mut memory = Memory::new(MBC1::new(vec![0u8; 0x8000]));
+    memory.write8(0xFFFF, 0x05);
+    memory.interrupts.request(InterruptType::Timer);
+    assert_eq!(memory.read8(0xFF0F), 0xE4);
+    assert_eq!(memory.interrupts.pending(), Some(InterruptType::Timer));
+
+    memory.write8(0xFF0F, 0x01);
+    assert_eq!(memory.interrupts.pending(), Some(InterruptType::VBlank));
+    assert_eq!(memory.read8(0xFFFF), 0x05);
+
+    memory.write8(0xFF00, 0x20); // select directions
+    memory.set_buttons(0x01);
+    assert_eq!(memory.read8(0xFF0F) & 0x10, 0x10);
+}
+
+#[test]
+fn cgb_wram_and_vram_banks() {
+    use crate::hw::controller::MBC1;
+
+    let mut memory = Memory::new_cgb(MBC1::new(vec![0u8; 0x8000]));
+    for bank in 0..8 {
+        memory.write8(0xFF70, bank);
+        memory.write8(0xD000, 0x10 + bank);
+    }
+    // Bank 0 selects bank 1
+    assert_eq!(memory.wram_bank(), 7);
+    memory.write8(0xFF70, 0);
+    assert_eq!(memory.read8(0xD000), 0x11);
+    assert_eq!(memory.read8(0xF000), 0x11);
+    memory.write8(0xFF70, 3);
+    assert_eq!(memory.read8(0xD000), 0x13);
+    assert_eq!(memory.read8(0xF000), 0x13);
+    assert_eq!(memory.peek8_banked(0xD000, 5), Some(0x15));
+
+    memory.write8(0xC000, 0xAA);
+    assert_eq!(memory.read8(0xE000), 0xAA);
+
+    memory.write8(0x8000, 0x01);
+    memory.write8(0xFF4F, 1);
+    assert_eq!(memory.read8(0xFF4F), 0xFF);
+    assert_eq!(memory.read8(0x8000), 0x00);
+    memory.write8(0x8000, 0x02);
+    memory.write8(0xFF4F, 0);
+    assert_eq!(memory.read8(0x8000), 0x01);
+    assert_eq!(memory.peek8_banked(0x8000, 1), Some(0x02));
+
+    // DMG ignores both registers
+    let mut memory = Memory::new(MBC1::new(vec![0u8; 0x8000]));
+    memory.write8(0xD000, 0x55);
+    memory.write8(0xFF70, 2);
+    assert_eq!(memory.read8(0xD000), 0x55);
+    assert_eq!(memory.peek8_banked(0x8000, 1), None);
+}
+
+#[test]
+fn vram_dma_general_and_hblank() {
+    use crate::hw::controller::MBC1;
+
+    let mut memory = Memory::new_cgb(MBC1::new(vec![0u8; 0x8000]));
+    for offset in 0..0x40 {
+        memory.write8(0xC000 + offset, offset as u8 + 1);
+    }
+    memory.write8(0xFF51, 0xC0);
+    memory.write8(0xFF52, 0x00);
+    memory.write8(0xFF53, 0x00);
+    memory.write8(0xFF54, 0x10);
+
+    // General purpose: two blocks at once, CPU halted for both
+    memory.write8(0xFF55, 0x01);
+    assert_eq!(memory.read8(0xFF55), 0xFF);
+    assert_eq!(memory.read8(0x8010), 0x01);
+    assert_eq!(memory.read8(0x802F), 0x20);
+    let cycles = memory.cycles();
+    memory.tick(4);
+    assert_eq!(memory.cycles() - cycles, 4 + 2 * VRAM_DMA_BLOCK_CYCLES as u64);
+
+    // HBlank: carries on from where the last transfer stopped, one block
+    // per HBlank
+    memory.write8(0xFF40, 0x80);
+    memory.write8(0xFF55, 0x81);
+    assert_eq!(memory.read8(0xFF55), 0x01);
+    while !memory.io.lcd.hblank_started() {
+        memory.tick_lcd();
+    }
+    assert_eq!(memory.read8(0xFF55), 0x00);
+    assert_eq!(memory.read8(0x8030), 0x21);
+    assert_eq!(memory.read8(0x8040), 0x00);
+
+    // Cancelled before the second block
+    memory.write8(0xFF55, 0x00);
+    assert_eq!(memory.read8(0xFF55), 0x80);
+    memory.tick_lcd();
+    while !memory.io.lcd.hblank_started() {
+        memory.tick_lcd();
+    }
+    assert_eq!(memory.read8(0x8040), 0x00);
+}
+
+#[test]
+fn cheats_patch_rom_and_ram() {
+    use crate::hw::controller::MBC1;
+
+    let mut rom = vec![0u8; 0x4000 * 4];
+    rom[0x4000 + 0x0A17] = 0x12;
+    rom[0x8000 + 0x0A17] = 0xC8;
+    let mut memory = Memory::new_cgb(MBC1::new(rom));
+    memory.add_cheat(Cheat::parse("00A-17B-C49", "").unwrap());
+
+    // Only the bank holding the compare byte is patched
+    assert_eq!(memory.read8(0x4A17), 0x12);
+    memory.write8(0x2000, 2);
+    assert_eq!(memory.read8(0x4A17), 0x00);
+    assert!(memory.set_cheat_enabled(0, false));
+    assert_eq!(memory.read8(0x4A17), 0xC8);
+
+    memory.add_cheat(Cheat::parse("0142C0C0", "").unwrap());
+    memory.add_cheat(Cheat::parse("93770FD0", "").unwrap());
+    memory.apply_gameshark();
+    assert_eq!(memory.read8(0xC0C0), 0x42);
+    assert_eq!(memory.read8(0xD00F), 0x00);
+    assert_eq!(memory.peek8_banked(0xD00F, 3), Some(0x77));
+}
+
+#[test]
+fn gameshark_writes_stay_out_of_saves() {
+    use crate::hw::controller::MBC1;
+
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x147] = 0x03;
+    rom[0x149] = 0x02;
+    let mut memory = Memory::new(MBC1::new(rom));
+    memory.write8(0x0000, 0x0A);
+    memory.write8(0xA010, 0x11);
+    memory.write8(0xA020, 0x22);
+    assert!(memory.take_cartridge_ram_written());
+
+    memory.add_cheat(Cheat::parse("019910A0", "").unwrap());
+    memory.add_cheat(Cheat::parse("809920A0", "").unwrap());
+    memory.apply_gameshark();
+    assert!(!memory.take_cartridge_ram_written());
+    assert_eq!((memory.read8(0xA010), memory.read8(0xA020)), (0x99, 0x99));
+    let save = memory.save_data().unwrap();
+    assert_eq!((save[0x10], save[0x20]), (0x11, 0x22));
+
+    // Once the game writes the byte itself, that's what gets saved
+    memory.write8(0xA010, 0x33);
+    memory.apply_gameshark();
+    let save = memory.save_data().unwrap();
+    assert_eq!((save[0x10], save[0x20]), (0x33, 0x22));
+}
+
+#[test]
+fn timer_registers_follow_instruction_cycles() {
+    use crate::hw::controller::MBC1;
+
+    let mut memory = Memory::new(MBC1::new(vec![0u8; 0x8000]));
+    memory.write8(0xFF07, 0x05);
+    // An instruction resetting DIV on its first M-cycle, then reading TIMA
+    // on its fourth and fifth. TIMA goes up every four M-cycles.
+    memory.begin_instruction(0xC000);
+    memory.write8(0xFF04, 0);
+    memory.read8(0xC000);
+    memory.read8(0xC000);
+    assert_eq!(memory.read8(0xFF05), 0);
+    assert_eq!(memory.read8(0xFF05), 1);
+    memory.tick(20);
+    assert_eq!(memory.peek8(0xFF05), 1);
+    assert_eq!(memory.peek8(0xFF04), 0);
+    assert_eq!(memory.peek8(0xFF07), 0xFD);
+
+    // Internal M-cycles count towards the timer the same way
+    memory.begin_instruction(0xC000);
+    memory.write8(0xFF04, 0);
+    memory.internal_cycle();
+    memory.internal_cycle();
+    assert_eq!(memory.read8(0xFF05), 1);
+    assert_eq!(memory.read8(0xFF05), 2);
+    assert_eq!(memory.instr_mcycles(), 5);
+}
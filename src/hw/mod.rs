@@ -1,4 +1,11 @@
 pub mod controller;
+pub mod dma;
+pub mod interrupt;
 pub mod memory;
 mod io;
+pub mod io_regs;
+pub mod joypad;
 pub mod lcd;
+pub mod observer;
+pub mod page_table;
+pub mod timer;
@@ -0,0 +1,124 @@
+use std::cell::RefCell;
+use std::ops::RangeInclusive;
+
+use crate::hw::memory::BusWidth;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AccessKind {
+    Read = 0x1,
+    Write = 0x2,
+    Execute = 0x4,
+}
+
+/// Set of access kinds an observer is interested in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AccessMask(u8);
+
+impl AccessMask {
+    pub const NONE: AccessMask = AccessMask(0);
+    pub const READ: AccessMask = AccessMask(AccessKind::Read as u8);
+    pub const WRITE: AccessMask = AccessMask(AccessKind::Write as u8);
+    pub const EXECUTE: AccessMask = AccessMask(AccessKind::Execute as u8);
+    pub const ALL: AccessMask = AccessMask(0x7);
+
+    pub fn contains(self, kind: AccessKind) -> bool {
+        self.0 & kind as u8 != 0
+    }
+
+    pub fn union(self, other: AccessMask) -> AccessMask {
+        AccessMask(self.0 | other.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryEvent {
+    pub kind: AccessKind,
+    pub addr: BusWidth,
+    pub value: u8,
+    /// Address of the instruction that made the access.
+    pub pc: BusWidth,
+    /// Clock cycle the instruction started on.
+    pub cycle: u64,
+    /// Bank mapped at `addr` when the access happened.
+    pub bank: u16,
+}
+
+pub type ObserverId = usize;
+
+pub type ObserverFn = Box<dyn FnMut(&MemoryEvent)>;
+
+struct Subscription {
+    id: ObserverId,
+    kinds: AccessMask,
+    range: RangeInclusive<BusWidth>,
+    func: ObserverFn,
+}
+
+/// Registry of memory access observers.
+///
+/// Memory checks `wants` before building an event, so with nothing
+/// subscribed the cost of an access is a single mask test.
+pub struct Observers {
+    subscriptions: RefCell<Vec<Subscription>>,
+    mask: AccessMask,
+    next_id: ObserverId,
+}
+
+impl Observers {
+    pub fn new() -> Observers {
+        Observers {
+            subscriptions: RefCell::new(Vec::new()),
+            mask: AccessMask::NONE,
+            next_id: 0,
+        }
+    }
+
+    pub fn subscribe(
+        &mut self,
+        kinds: AccessMask,
+        range: RangeInclusive<BusWidth>,
+        func: ObserverFn,
+    ) -> ObserverId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.subscriptions.get_mut().push(Subscription {
+            id,
+            kinds,
+            range,
+            func,
+        });
+        self.update_mask();
+        id
+    }
+
+    /// Returns false if `id` wasn't subscribed.
+    pub fn unsubscribe(&mut self, id: ObserverId) -> bool {
+        let subscriptions = self.subscriptions.get_mut();
+        let len = subscriptions.len();
+        subscriptions.retain(|sub| sub.id != id);
+        let removed = subscriptions.len() != len;
+        self.update_mask();
+        removed
+    }
+
+    fn update_mask(&mut self) {
+        self.mask = self
+            .subscriptions
+            .get_mut()
+            .iter()
+            .fold(AccessMask::NONE, |mask, sub| mask.union(sub.kinds));
+    }
+
+    #[inline]
+    pub fn wants(&self, kind: AccessKind) -> bool {
+        self.mask.contains(kind)
+    }
+
+    pub fn notify(&self, event: &MemoryEvent) {
+        for sub in self.subscriptions.borrow_mut().iter_mut() {
+            if sub.kinds.contains(event.kind) && sub.range.contains(&event.addr) {
+                (sub.func)(event);
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+
+use crate::hw::memory::BusWidth;
+
+pub const PAGE_SIZE: usize = 0x100;
+const PAGE_COUNT: usize = 0x100;
+/// HRAM sits in the same page as the IO registers, so it's mapped on its
+/// own rather than as a whole page.
+const HIGH_START: BusWidth = 0xFF80;
+const HIGH_END: BusWidth = 0xFFFE;
+
+/// A fixed-size byte buffer that pages can be mapped to with write access.
+///
+/// The bytes live in an allocation reached only through the raw pointer it
+/// was created with. Page pointers are copies of that pointer, and the
+/// buffer's own slices are made from it, so the owner using the buffer
+/// never invalidates pointers the page table already holds. Pointers taken
+/// from a `&mut` to a `Vec`'s contents would be invalidated by the next
+/// `&mut` to the same `Vec`.
+pub struct PageBuffer {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl PageBuffer {
+    pub fn new(len: usize) -> PageBuffer {
+        let bytes = vec![0u8; len].into_boxed_slice();
+        PageBuffer {
+            ptr: Box::into_raw(bytes) as *mut u8,
+            len,
+        }
+    }
+
+    /// Pointer to `len` bytes from `offset`, for the page table.
+    fn range_ptr(&self, offset: usize, len: usize) -> *mut u8 {
+        assert!(offset + len <= self.len);
+        // In bounds, checked above
+        unsafe { self.ptr.add(offset) }
+    }
+}
+
+impl Deref for PageBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for PageBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for PageBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(self.ptr, self.len)));
+        }
+    }
+}
+
+/// Maps each 256-byte page of the address space straight to its backing
+/// memory so that plain RAM and ROM accesses skip the device dispatch.
+/// Unmapped pages fall back to the owning device's `Bus` implementation.
+/// HRAM, which shares its page with the IO registers, has a mapping of its
+/// own.
+///
+/// The table holds raw pointers into buffers owned by other hardware. The
+/// owner (`Memory`) must remap a page whenever the buffer backing it
+/// changes; see the mapping functions for what else it has to uphold.
+pub struct PageTable {
+    read: [*const u8; PAGE_COUNT],
+    write: [*mut u8; PAGE_COUNT],
+    high: *mut u8,
+}
+
+fn page_of(addr: BusWidth) -> usize {
+    (addr >> 8) as usize
+}
+
+impl PageTable {
+    pub fn new() -> PageTable {
+        PageTable {
+            read: [ptr::null(); PAGE_COUNT],
+            write: [ptr::null_mut(); PAGE_COUNT],
+            high: ptr::null_mut(),
+        }
+    }
+
+    /// Serve reads of the page containing `addr` from `mem`.
+    ///
+    /// # Safety
+    ///
+    /// `mem` must stay allocated, and must not be written, for as long as
+    /// the page is mapped.
+    pub unsafe fn map_read(&mut self, addr: BusWidth, mem: &[u8]) {
+        assert!(mem.len() >= PAGE_SIZE);
+        self.read[page_of(addr)] = mem.as_ptr();
+        self.write[page_of(addr)] = ptr::null_mut();
+    }
+
+    /// Serve reads and writes of the page containing `addr` from `buf`,
+    /// starting at `offset`.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must outlive the mapping, and no slice of it may be held
+    /// across an access through the table.
+    pub unsafe fn map_read_write(&mut self, addr: BusWidth, buf: &PageBuffer, offset: usize) {
+        let page = buf.range_ptr(offset, PAGE_SIZE);
+        self.read[page_of(addr)] = page;
+        self.write[page_of(addr)] = page;
+    }
+
+    /// Serve FF80-FFFE from `hram`.
+    ///
+    /// # Safety
+    ///
+    /// As for `map_read_write`.
+    pub unsafe fn map_high(&mut self, hram: &PageBuffer) {
+        self.high = hram.range_ptr(0, (HIGH_END - HIGH_START) as usize + 1);
+    }
+
+    pub fn unmap(&mut self, addr: BusWidth) {
+        self.read[page_of(addr)] = ptr::null();
+        self.write[page_of(addr)] = ptr::null_mut();
+    }
+
+    #[inline]
+    fn high_offset(&self, addr: BusWidth) -> Option<usize> {
+        if (HIGH_START..=HIGH_END).contains(&addr) && !self.high.is_null() {
+            Some((addr - HIGH_START) as usize)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn read(&self, addr: BusWidth) -> Option<u8> {
+        let page = self.read[page_of(addr)];
+        // Safe as long as the owner upholds the mapping contracts; the
+        // offsets are always within what was mapped.
+        if !page.is_null() {
+            Some(unsafe { *page.add(addr as usize & (PAGE_SIZE - 1)) })
+        } else {
+            let offset = self.high_offset(addr)?;
+            Some(unsafe { *self.high.add(offset) })
+        }
+    }
+
+    /// Returns false if the page isn't directly writable.
+    #[inline]
+    pub fn write(&mut self, addr: BusWidth, data: u8) -> bool {
+        let page = self.write[page_of(addr)];
+        if !page.is_null() {
+            unsafe { *page.add(addr as usize & (PAGE_SIZE - 1)) = data };
+            true
+        } else if let Some(offset) = self.high_offset(addr) {
+            unsafe { *self.high.add(offset) = data };
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[test]
+fn page_table_maps_and_unmaps() {
+    let mut rom = vec![0u8; PAGE_SIZE];
+    let mut ram = PageBuffer::new(PAGE_SIZE * 2);
+    let mut hram = PageBuffer::new(0x7F);
+    rom[0x12] = 0x34;
+
+    let mut pages = PageTable::new();
+    unsafe {
+        pages.map_read(0x4000, &rom);
+        pages.map_read_write(0xC100, &ram, PAGE_SIZE);
+        pages.map_high(&hram);
+    }
+
+    assert_eq!(pages.read(0x4012), Some(0x34));
+    assert!(!pages.write(0x4012, 0x00));
+    assert!(pages.write(0xC1FF, 0x56));
+    assert_eq!(pages.read(0xC1FF), Some(0x56));
+    assert_eq!(pages.read(0xC000), None);
+
+    // The owner's own accesses leave the mapping usable
+    ram[PAGE_SIZE] = 0x78;
+    assert_eq!(pages.read(0xC100), Some(0x78));
+    assert!(pages.write(0xC101, 0x9A));
+    assert_eq!(ram[PAGE_SIZE + 1], 0x9A);
+
+    assert!(pages.write(0xFF80, 0x11));
+    hram[0x7E] = 0x22;
+    assert_eq!(pages.read(0xFFFE), Some(0x22));
+    assert_eq!(hram[0], 0x11);
+    assert_eq!(pages.read(0xFF7F), None);
+    assert_eq!(pages.read(0xFFFF), None);
+    assert!(!pages.write(0xFF0F, 0x00));
+
+    pages.unmap(0xC100);
+    assert_eq!(pages.read(0xC1FF), None);
+    assert_eq!(ram[PAGE_SIZE * 2 - 1], 0x56);
+}
@@ -0,0 +1,246 @@
+use crate::hw::interrupt::{InterruptController, InterruptType};
+use crate::hw::memory::BusWidth;
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
+
+/// System counter value when the DMG boot ROM hands over to the cartridge.
+const DMG_BOOT_COUNTER: u16 = 0xABCC;
+/// The same for the CGB boot ROM, on a DMG cartridge.
+const CGB_BOOT_COUNTER: u16 = 0x267C;
+
+/// Where TIMA is in its overflow sequence. Each state lasts one M-cycle.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Reload {
+    Idle,
+    /// TIMA has just overflowed and reads 00. Writing TIMA now cancels
+    /// the reload and the interrupt.
+    Pending,
+    /// TIMA has just been loaded from TMA. Writes to TIMA are lost, and
+    /// writes to TMA go to TIMA as well.
+    Reloading,
+}
+
+/// DIV, TIMA, TMA and TAC.
+///
+/// Everything runs off a 16-bit counter that goes up every clock cycle;
+/// DIV is its upper byte. TIMA counts falling edges of one of the
+/// counter's bits, picked by TAC and ANDed with the TAC enable. Because
+/// it's edge triggered, resetting DIV or changing TAC while the selected
+/// bit is high also counts.
+#[derive(Clone)]
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    reload: Reload,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            counter: DMG_BOOT_COUNTER,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            reload: Reload::Idle,
+        }
+    }
+
+    pub fn new_cgb() -> Timer {
+        Timer {
+            counter: CGB_BOOT_COUNTER,
+            ..Timer::new()
+        }
+    }
+
+    /// The input TIMA counts falling edges of.
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0x3 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        self.tac & 0x4 != 0 && self.counter & (1 << bit) != 0
+    }
+
+    fn increment(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            self.reload = Reload::Pending;
+        }
+    }
+
+    /// Run one M-cycle.
+    pub fn step(&mut self, interrupts: &mut InterruptController) {
+        match self.reload {
+            Reload::Pending => {
+                self.tima = self.tma;
+                interrupts.request(InterruptType::Timer);
+                self.reload = Reload::Reloading;
+            }
+            Reload::Reloading => self.reload = Reload::Idle,
+            Reload::Idle => (),
+        }
+        let before = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        if before && !self.signal() {
+            self.increment();
+        }
+    }
+
+    pub fn read_reg(&self, addr: BusWidth) -> u8 {
+        match addr {
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => 0xF8 | self.tac,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_reg(&mut self, addr: BusWidth, data: u8) {
+        let before = self.signal();
+        match addr {
+            0xFF04 => self.counter = 0,
+            0xFF05 => match self.reload {
+                Reload::Pending => {
+                    self.tima = data;
+                    self.reload = Reload::Idle;
+                }
+                Reload::Reloading => (),
+                Reload::Idle => self.tima = data,
+            },
+            0xFF06 => {
+                self.tma = data;
+                if self.reload == Reload::Reloading {
+                    self.tima = data;
+                }
+            }
+            0xFF07 => self.tac = data & 0x7,
+            _ => (),
+        }
+        if before && !self.signal() {
+            self.increment();
+        }
+    }
+}
+
+impl SaveState for Timer {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.counter);
+        w.write_u8(self.tima);
+        w.write_u8(self.tma);
+        w.write_u8(self.tac);
+        w.write_u8(match self.reload {
+            Reload::Idle => 0,
+            Reload::Pending => 1,
+            Reload::Reloading => 2,
+        });
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.counter = r.read_u16()?;
+        self.tima = r.read_u8()?;
+        self.tma = r.read_u8()?;
+        self.tac = r.read_u8()? & 0x7;
+        self.reload = match r.read_u8()? {
+            1 => Reload::Pending,
+            2 => Reload::Reloading,
+            _ => Reload::Idle,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn run(timer: &mut Timer, interrupts: &mut InterruptController, m_cycles: usize) {
+    for _ in 0..m_cycles {
+        timer.step(interrupts);
+    }
+}
+
+#[test]
+fn timer_counts_and_reloads() {
+    let mut timer = Timer::new();
+    let mut interrupts = InterruptController::new();
+    interrupts.write_ie(0xFF);
+    timer.write_reg(0xFF04, 0x12);
+    assert_eq!(timer.read_reg(0xFF04), 0);
+    run(&mut timer, &mut interrupts, 64);
+    assert_eq!(timer.read_reg(0xFF04), 1);
+
+    // 262144 Hz: one increment every 4 M-cycles
+    timer.write_reg(0xFF04, 0);
+    timer.write_reg(0xFF06, 0xAB);
+    timer.write_reg(0xFF05, 0xFE);
+    timer.write_reg(0xFF07, 0x05);
+    run(&mut timer, &mut interrupts, 8);
+    // Overflowed on the last cycle: TIMA reads 00 for one M-cycle, then
+    // TMA is loaded and the interrupt raised
+    assert_eq!(timer.read_reg(0xFF05), 0x00);
+    assert_eq!(interrupts.pending(), None);
+    run(&mut timer, &mut interrupts, 1);
+    assert_eq!(timer.read_reg(0xFF05), 0xAB);
+    assert_eq!(interrupts.pending(), Some(InterruptType::Timer));
+}
+
+#[test]
+fn timer_reload_window_writes() {
+    let mut interrupts = InterruptController::new();
+    interrupts.write_ie(0xFF);
+    let overflowed = || {
+        let mut timer = Timer::new();
+        timer.write_reg(0xFF04, 0);
+        timer.write_reg(0xFF06, 0x40);
+        timer.write_reg(0xFF05, 0xFF);
+        timer.write_reg(0xFF07, 0x05);
+        timer
+    };
+
+    // Writing TIMA in the cycle after the overflow cancels the reload
+    let mut timer = overflowed();
+    run(&mut timer, &mut interrupts, 4);
+    timer.write_reg(0xFF05, 0x10);
+    run(&mut timer, &mut interrupts, 1);
+    assert_eq!(timer.read_reg(0xFF05), 0x10);
+    assert_eq!(interrupts.pending(), None);
+
+    // Writing TIMA in the reload cycle is lost, writing TMA goes through
+    let mut timer = overflowed();
+    run(&mut timer, &mut interrupts, 5);
+    timer.write_reg(0xFF05, 0x10);
+    assert_eq!(timer.read_reg(0xFF05), 0x40);
+    timer.write_reg(0xFF06, 0x22);
+    assert_eq!(timer.read_reg(0xFF05), 0x22);
+    assert_eq!(interrupts.pending(), Some(InterruptType::Timer));
+}
+
+#[test]
+fn timer_glitch_increments() {
+    let mut interrupts = InterruptController::new();
+    let mut timer = Timer::new();
+    timer.write_reg(0xFF04, 0);
+    timer.write_reg(0xFF07, 0x05);
+    // Bit 3 goes high after two M-cycles; resetting DIV then is a falling
+    // edge
+    run(&mut timer, &mut interrupts, 2);
+    timer.write_reg(0xFF04, 0);
+    assert_eq!(timer.read_reg(0xFF05), 1);
+
+    // So is turning the timer off while the bit is high
+    run(&mut timer, &mut interrupts, 2);
+    timer.write_reg(0xFF07, 0x01);
+    assert_eq!(timer.read_reg(0xFF05), 2);
+    // Or switching to a bit that's low
+    timer.write_reg(0xFF07, 0x05);
+    timer.write_reg(0xFF07, 0x06);
+    assert_eq!(timer.read_reg(0xFF05), 3);
+}
+
+#[test]
+#[ignore = "needs the Mooneye ROMs"]
+fn mooneye_timer() {
+    crate::mooneye::run_all("acceptance/timer");
+}
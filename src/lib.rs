@@ -0,0 +1,61 @@
+//! The emulator core. The `GBEmu` binary is a window and a command line
+//! around it; everything here can also be driven from other programs, for
+//! example to script a RAM search in a headless run.
+
+#![allow(dead_code)]
+// The core was only a binary until it became a library, so its API
+// doesn't follow these conventions yet.
+#![allow(clippy::result_unit_err, clippy::new_without_default)]
+
+pub mod camera_input;
+pub mod cheats;
+pub mod cpu;
+pub mod dat;
+pub mod debugger;
+pub mod hw;
+pub mod movie;
+#[cfg(test)]
+mod mooneye;
+pub mod patch;
+pub mod ram_search;
+pub mod registers;
+pub mod rom_file;
+pub mod save_ram;
+pub mod savestate;
+pub mod tilt;
+
+use std::cell::RefCell;
+
+// log_stderr for per instruction register prints?
+
+thread_local! {
+    #[doc(hidden)]
+    pub static VERBOSE: RefCell<bool> = RefCell::new(false);
+}
+
+#[macro_export]
+macro_rules! emu_log {
+    () => ({
+        use $crate::VERBOSE;
+        VERBOSE.with(|f| {
+            if *f.borrow() == true {
+                println!();
+            }
+        });
+    });
+    ($($arg:tt)*) => ({
+        use $crate::VERBOSE;
+        VERBOSE.with(|f| {
+            if *f.borrow() == true {
+                println!($($arg)*);
+            }
+        });
+    })
+}
+
+/// Turn on `emu_log!` output for this thread.
+pub fn set_verbose() {
+    VERBOSE.with(|f| {
+        *f.borrow_mut() = true;
+    });
+}
@@ -1,52 +1,35 @@
 #![allow(dead_code)]
 
-mod cpu;
-mod debugger;
 mod display;
-mod hw;
-mod registers;
 
-use std::cell::RefCell;
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::{Path, PathBuf};
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
 
-use crate::cpu::Cpu;
-use crate::hw::controller::MBC1;
-use crate::hw::lcd::LcdControllerMode;
-use crate::hw::memory::Bus;
-use crate::hw::memory::Memory;
+use gbemu::camera_input::CameraImages;
+use gbemu::cheats::Cheats;
+use gbemu::cpu::Cpu;
+use gbemu::dat::{Dat, Identity, RomDigest};
+use gbemu::debugger;
+use gbemu::emu_log;
+use gbemu::hw::controller::infrared::TcpIrPort;
+use gbemu::hw::controller::rtc::CycleClock;
+use gbemu::hw::controller::{self, CartridgeHeader, CgbSupport, HeaderError};
+use gbemu::hw::joypad;
+use gbemu::hw::lcd::LcdControllerMode;
+use gbemu::hw::memory::Bus;
+use gbemu::hw::memory::Memory;
+use gbemu::movie::MovieSession;
+use gbemu::save_ram::SaveRam;
+use gbemu::tilt::{JoystickTilt, TiltScript, KEY_TILT};
+use gbemu::{patch, rom_file, savestate};
 
 use rgb::ComponentBytes;
 use structopt::StructOpt;
 
 use glium::glutin;
-
-// log_stderr for per instruction register prints?
-
-thread_local! {
-    static VERBOSE: RefCell<bool> = RefCell::new(false);
-}
-
-#[macro_export]
-macro_rules! emu_log {
-    () => ({
-        use crate::VERBOSE;
-        VERBOSE.with(|f| {
-            if *f.borrow() == true {
-                println!();
-            }
-        });
-    });
-    ($($arg:tt)*) => ({
-        use crate::VERBOSE;
-        VERBOSE.with(|f| {
-            if *f.borrow() == true {
-                println!($($arg)*);
-            }
-        });
-    })
-}
+use glium::glutin::{ElementState, VirtualKeyCode};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "basic")]
@@ -55,16 +38,105 @@ struct EmuOpts {
     #[structopt(short = "v")]
     verbose: bool,
 
+    /// Record joypad input to a movie file
+    #[structopt(long = "record", parse(from_os_str))]
+    record: Option<PathBuf>,
+
+    /// Replay the existing movie given to --record, then keep recording
+    #[structopt(long = "append", requires = "record")]
+    append: bool,
+
+    /// Replay joypad input from a movie file
+    #[structopt(long = "play", parse(from_os_str), conflicts_with = "record")]
+    play: Option<PathBuf>,
+
+    /// Start from a save state instead of power on
+    #[structopt(long = "load-state", parse(from_os_str))]
+    load_state: Option<PathBuf>,
+
+    /// Run without a window or debugger
+    #[structopt(long = "headless")]
+    headless: bool,
+
+    /// Stop after this many frames
+    #[structopt(long = "frames")]
+    frames: Option<u32>,
+
+    /// Accelerometer input for tilt cartridges, one `<frame> <x> <y>` per
+    /// line
+    #[structopt(long = "tilt-script", parse(from_os_str))]
+    tilt_script: Option<PathBuf>,
+
+    /// Linux joystick device, like /dev/input/js0, whose first two axes
+    /// tilt the Game Boy along with the IJKL keys
+    #[structopt(
+        long = "tilt-joystick",
+        parse(from_os_str),
+        raw(conflicts_with = r#""tilt_script""#)
+    )]
+    tilt_joystick: Option<PathBuf>,
+
+    /// Pictures for the Pocket Camera to see: image files or directories of
+    /// them, one per capture in turn. Repeat for a sequence. Movies don't
+    /// record them, so this can't be used with --record or --play
+    #[structopt(
+        long = "camera",
+        parse(from_os_str),
+        number_of_values = 1,
+        raw(conflicts_with_all = r#"&["record", "play"]"#)
+    )]
+    camera: Vec<PathBuf>,
+
+    /// Wait on this address for another emulator to connect its infrared
+    /// port to this one's. Movies don't record the link, so this can't be
+    /// used with --record or --play
+    #[structopt(
+        long = "ir-listen",
+        raw(conflicts_with_all = r#"&["ir_connect", "record", "play"]"#)
+    )]
+    ir_listen: Option<String>,
+
+    /// Connect this emulator's infrared port to another one started with
+    /// --ir-listen at this address
+    #[structopt(
+        long = "ir-connect",
+        raw(conflicts_with_all = r#"&["record", "play"]"#)
+    )]
+    ir_connect: Option<String>,
+
+    /// Directory for battery save files, instead of next to the ROM
+    #[structopt(long = "save-dir", parse(from_os_str))]
+    save_dir: Option<PathBuf>,
+
+    /// IPS, UPS or BPS patch to apply to the ROM, instead of one with the
+    /// ROM's name next to it. Repeat to apply several in order
+    #[structopt(long = "patch", parse(from_os_str), number_of_values = 1)]
+    patch: Vec<PathBuf>,
+
+    /// Game Genie and GameShark codes to load, one per line, instead of the
+    /// .cht file with the ROM's name next to it. Movies don't record them,
+    /// so this can't be used with --record or --play
+    #[structopt(
+        long = "cheats",
+        parse(from_os_str),
+        raw(conflicts_with_all = r#"&["record", "play"]"#)
+    )]
+    cheats: Option<PathBuf>,
+
+    /// No-Intro style XML DAT file to identify the ROM against
+    #[structopt(long = "dat", parse(from_os_str))]
+    dat: Option<PathBuf>,
+
+    /// Which file to run from a zip archive, instead of the first .gb or
+    /// .gbc in it
+    #[structopt(long = "rom-entry")]
+    rom_entry: Option<String>,
+
+    /// A ROM image, or a zip or gzip archive holding one
     #[structopt(parse(from_os_str))]
     rom_path: PathBuf,
 }
 
-fn set_verbose() {
-    VERBOSE.with(|f| {
-        *f.borrow_mut() = true;
-    });
-}
-
 fn dump_memory(cpu: &Cpu) {
     let mut line_output = Vec::<String>::new();
     for i in (0..=0xfff0).step_by(0x10) {
@@ -77,24 +149,131 @@ fn dump_memory(cpu: &Cpu) {
     }
 }
 
-fn init_cpu(rom_path: &Path) -> Result<Cpu, String> {
-    let mut file = File::open(rom_path).unwrap();
-
-    let mut rom = Vec::new();
+/// `patches` are applied to the ROM in order before its header is read.
+/// `deterministic` drives any cartridge clock from emulated time instead
+/// of the wall clock, so that runs can be reproduced.
+fn init_cpu(mut rom: Vec<u8>, patches: &[PathBuf], deterministic: bool) -> Result<Cpu, String> {
+    for patch_path in patches {
+        rom = patch::apply_file(&rom, patch_path)
+            .map_err(|e| format!("Error applying patch {}: {}", patch_path.display(), e))?;
+        emu_log!("Applied patch {}", patch_path.display());
+    }
+    let (header, mut new_cartridge) =
+        controller::load_cartridge(rom).map_err(|e| format!("Error loading game: {}", e))?;
+    emu_log!("Loaded {:?} ({:?})", header.title, header.cartridge_type);
+    if deterministic {
+        new_cartridge.set_clock(Box::new(CycleClock));
+    }
+    let new_memory = match header.cgb {
+        CgbSupport::None => Memory::new(new_cartridge),
+        CgbSupport::Compatible | CgbSupport::Only => Memory::new_cgb(new_cartridge),
+    };
+    Ok(Cpu::new(new_memory))
+}
 
-    match file.read_to_end(&mut rom) {
-        Ok(_) => (),
-        Err(m) => {
-            println!("Error loading game: {}", m);
-            return Err(format!("Error loading game: {}", m));
+/// Report the ROM's checksums and, given a DAT, which dump it is. This is
+/// the ROM as read from disk, before any patches.
+fn report_rom(rom: &[u8], dat: Option<&Dat>) {
+    let digest = RomDigest::of(rom);
+    emu_log!("ROM CRC32 {:08x}, SHA-1 {}", digest.crc32, digest.sha1);
+    // A header that doesn't parse is reported when the game is loaded
+    match CartridgeHeader::parse(rom) {
+        Ok(header) => {
+            let global_ok = header.global_checksum_ok(rom);
+            let global = if global_ok { "ok" } else { "bad" };
+            emu_log!("Header checksum ok, global checksum {}", global);
+            if !global_ok {
+                println!("Warning: global checksum doesn't match, the ROM may be a bad dump");
+            }
         }
+        Err(HeaderError::BadHeaderChecksum { .. }) => emu_log!("Header checksum bad"),
+        Err(_) => (),
     }
-    // From this point on the rom should never be modified
-    let rom = rom;
+    match dat.map(|dat| dat.identify(rom)) {
+        Some(Identity::Good(entry)) => println!("Identified as {}", entry.game),
+        Some(Identity::BadDump(entry)) => {
+            println!("Warning: this is a known bad dump of {}", entry.game)
+        }
+        Some(Identity::Overdump(entry)) => println!(
+            "Warning: this is an overdump of {}, {} bytes too long",
+            entry.game,
+            rom.len() - entry.size
+        ),
+        Some(Identity::Unknown) => println!("Warning: ROM isn't in the DAT file"),
+        None => (),
+    }
+}
 
-    let new_cartridge: Box<dyn Bus> = MBC1::new(rom);
-    let new_memory = Memory::new(new_cartridge);
-    Ok(Cpu::new(new_memory))
+fn start_movie(opts: &EmuOpts, rom_crc32: u32, cpu: &mut Cpu) -> Result<Option<MovieSession>, String> {
+    let from_snapshot = opts.load_state.is_some();
+    let session = match (&opts.record, &opts.play) {
+        (Some(path), _) if opts.append => MovieSession::append(path, rom_crc32, cpu),
+        (Some(path), _) => Ok(MovieSession::record(path, rom_crc32, cpu, from_snapshot)),
+        (None, Some(path)) => MovieSession::play(path, rom_crc32, cpu),
+        (None, None) => return Ok(None),
+    };
+    session.map(Some).map_err(|e| e.to_string())
+}
+
+fn report_output(headless: bool, frame: u32, name: &str, on: Option<bool>) {
+    let state = if on == Some(true) { "on" } else { "off" };
+    if headless {
+        println!("Frame {}: {} {}", frame, name, state);
+    } else {
+        emu_log!("Frame {}: {} {}", frame, name, state);
+    }
+}
+
+/// Whether input comes from or goes to a movie, so every run has to
+/// start out the same.
+fn deterministic_input(opts: &EmuOpts) -> bool {
+    opts.record.is_some() || opts.play.is_some()
+}
+
+/// IJKL tilt the Game Boy for cartridges with an accelerometer.
+fn key_to_tilt(key: VirtualKeyCode) -> Option<(f32, f32)> {
+    match key {
+        VirtualKeyCode::J => Some((-KEY_TILT, 0.0)),
+        VirtualKeyCode::L => Some((KEY_TILT, 0.0)),
+        VirtualKeyCode::I => Some((0.0, -KEY_TILT)),
+        VirtualKeyCode::K => Some((0.0, KEY_TILT)),
+        _ => None,
+    }
+}
+
+fn key_to_button(key: VirtualKeyCode) -> Option<u8> {
+    match key {
+        VirtualKeyCode::Right => Some(joypad::BUTTON_RIGHT),
+        VirtualKeyCode::Left => Some(joypad::BUTTON_LEFT),
+        VirtualKeyCode::Up => Some(joypad::BUTTON_UP),
+        VirtualKeyCode::Down => Some(joypad::BUTTON_DOWN),
+        VirtualKeyCode::Z => Some(joypad::BUTTON_A),
+        VirtualKeyCode::X => Some(joypad::BUTTON_B),
+        VirtualKeyCode::Back => Some(joypad::BUTTON_SELECT),
+        VirtualKeyCode::Return => Some(joypad::BUTTON_START),
+        _ => None,
+    }
+}
+
+struct Frontend {
+    events_loop: glutin::EventsLoop,
+    display: glium::Display,
+    program: glium::Program,
+    debugger: debugger::Debugger,
+}
+
+impl Frontend {
+    fn new() -> Frontend {
+        let mut events_loop = glutin::EventsLoop::new();
+        let display = display::init_display(&mut events_loop);
+        let program = display::create_program(&display);
+        Frontend {
+            events_loop,
+            display,
+            program,
+            debugger: debugger::Debugger::new(),
+        }
+    }
 }
 
 fn main() {
@@ -102,42 +281,304 @@ fn main() {
     let path = &opts.rom_path;
 
     if opts.verbose {
-        set_verbose();
+        gbemu::set_verbose();
     }
 
-    let mut cpu = match init_cpu(path) {
+    let rom = match rom_file::load(path, opts.rom_entry.as_deref()) {
+        Ok(rom_file) => {
+            if let Some(entry) = &rom_file.entry {
+                emu_log!("Loading {} from {}", entry, path.display());
+            }
+            rom_file.data
+        }
+        Err(e) => {
+            println!("Error loading game: {}", e);
+            return;
+        }
+    };
+    let dat = match &opts.dat {
+        Some(dat_path) => match Dat::load(dat_path) {
+            Ok(dat) => Some(dat),
+            Err(e) => {
+                println!("Error loading {}: {}", dat_path.display(), e);
+                return;
+            }
+        },
+        None => None,
+    };
+    report_rom(&rom, dat.as_ref());
+
+    let patches = if opts.patch.is_empty() {
+        patch::find_patch(&rom_file::base_name(path)).into_iter().collect()
+    } else {
+        opts.patch.clone()
+    };
+    let deterministic = opts.headless || deterministic_input(&opts);
+    let mut cpu = match init_cpu(rom, &patches, deterministic) {
         Ok(cpu) => cpu,
         Err(string) => {
             println!("{}", string);
             return;
         }
     };
+    // Movies are tied to the ROM as patched
+    let rom_crc32 = crc32fast::hash(cpu.memory.cartridge().rom());
 
-    let mut closed = false;
+    if !opts.camera.is_empty() {
+        let images = match CameraImages::load(&opts.camera) {
+            Ok(images) => images,
+            Err(e) => {
+                println!("Error loading camera images: {}", e);
+                return;
+            }
+        };
+        if !cpu.memory.cartridge_mut().connect_camera(Box::new(images)) {
+            println!("Warning: this cartridge has no camera, ignoring --camera");
+        }
+    }
 
-    let mut events_loop = glutin::EventsLoop::new();
-    let mut display = display::init_display(&mut events_loop);
-    let program = display::create_program(&mut display);
-    let mut debugger = debugger::Debugger::new();
+    let ir_port = match (&opts.ir_listen, &opts.ir_connect) {
+        (Some(addr), _) => {
+            println!("Waiting for the infrared link on {}", addr);
+            Some(TcpIrPort::listen(addr))
+        }
+        (None, Some(addr)) => Some(TcpIrPort::connect(addr)),
+        (None, None) => None,
+    };
+    match ir_port {
+        Some(Ok(port)) => {
+            let connected = cpu.memory.cartridge_mut().connect_ir(Box::new(port));
+            if !connected {
+                println!("Warning: this cartridge has no infrared port, ignoring the link");
+            }
+        }
+        Some(Err(e)) => {
+            println!("Error setting up the infrared link: {}", e);
+            return;
+        }
+        None => (),
+    }
+
+    let cheat_path = match &opts.cheats {
+        Some(cheat_path) => Some(cheat_path.clone()),
+        None => Some(rom_file::base_name(path).with_extension("cht")).filter(|p| p.is_file()),
+    };
+    // Movies don't record cheats, so a run with them wouldn't replay
+    let cheat_path = match cheat_path {
+        Some(cheat_path) if deterministic_input(&opts) => {
+            println!("Not loading cheats from {} for a movie", cheat_path.display());
+            None
+        }
+        cheat_path => cheat_path,
+    };
+    if let Some(cheat_path) = cheat_path {
+        let cheats = fs::read_to_string(&cheat_path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| Cheats::parse(&text).map_err(|e| e.to_string()));
+        match cheats {
+            Ok(cheats) => {
+                for cheat in cheats.iter() {
+                    cpu.memory.add_cheat(cheat.clone());
+                }
+                emu_log!("Loaded cheats from {}", cheat_path.display());
+            }
+            Err(e) => {
+                println!("Error loading cheats {}: {}", cheat_path.display(), e);
+                return;
+            }
+        }
+    }
+
+    // Movies always start from the same RAM, and mustn't touch real saves
+    let mut save_ram = if cpu.memory.cartridge().has_battery() && !deterministic_input(&opts) {
+        Some(SaveRam::new(&rom_file::base_name(path), opts.save_dir.as_deref()))
+    } else {
+        None
+    };
+    if let Some(save) = save_ram.as_mut() {
+        match save.load(cpu.memory.cartridge_mut()) {
+            Ok(true) => emu_log!("Loaded save from {}", save.path().display()),
+            Ok(false) => (),
+            Err(e) => {
+                println!("Error loading save {}: {}", save.path().display(), e);
+                return;
+            }
+        }
+    }
+
+    if let Some(state_path) = &opts.load_state {
+        let loaded = fs::read(state_path)
+            .map_err(|e| e.to_string())
+            .and_then(|state| savestate::load_from_slice(&mut cpu, &state).map_err(|e| e.to_string()));
+        if let Err(e) = loaded {
+            println!("Error loading state {}: {}", state_path.display(), e);
+            return;
+        }
+    }
+
+    let mut movie = match start_movie(&opts, rom_crc32, &mut cpu) {
+        Ok(movie) => movie,
+        Err(string) => {
+            println!("{}", string);
+            return;
+        }
+    };
+
+    let mut frontend = if opts.headless {
+        None
+    } else {
+        Some(Frontend::new())
+    };
+    if let Some(frontend) = frontend.as_mut() {
+        frontend.debugger.set_cheats_locked(movie.is_some());
+    }
+
+    let mut closed = false;
+    let mut keys = 0u8;
+    let mut tilt_keys = HashSet::new();
+    let mut tilt_script = match &opts.tilt_script {
+        Some(script_path) => match TiltScript::load(script_path) {
+            Ok(script) => Some(script),
+            Err(e) => {
+                println!("Error loading tilt script {}: {}", script_path.display(), e);
+                return;
+            }
+        },
+        None => None,
+    };
+    let tilt_joystick = match &opts.tilt_joystick {
+        Some(device_path) => match JoystickTilt::open(device_path) {
+            Ok(joystick) => Some(joystick),
+            Err(e) => {
+                println!("Error opening joystick {}: {}", device_path.display(), e);
+                return;
+            }
+        },
+        None => None,
+    };
+    let mut frame = 0u32;
+    let mut desynced = false;
+    let mut rumble = cpu.memory.cartridge().rumble();
+    let mut tone = cpu.memory.cartridge().tone();
 
     while !closed {
-        events_loop.poll_events(|event| match event {
-            glutin::Event::WindowEvent { event, .. } => match event {
-                glutin::WindowEvent::CloseRequested => closed = true,
-                _ => return,
-            },
-            _ => return,
-        });
+        if let Some(frontend) = frontend.as_mut() {
+            frontend.events_loop.poll_events(|event| {
+                if let glutin::Event::WindowEvent { event, .. } = event {
+                    match event {
+                        glutin::WindowEvent::CloseRequested => closed = true,
+                        glutin::WindowEvent::KeyboardInput { input, .. } => {
+                            if let Some(button) = input.virtual_keycode.and_then(key_to_button) {
+                                match input.state {
+                                    ElementState::Pressed => keys |= button,
+                                    ElementState::Released => keys &= !button,
+                                }
+                            }
+                            if let Some(key) = input.virtual_keycode {
+                                if key_to_tilt(key).is_some() {
+                                    match input.state {
+                                        ElementState::Pressed => tilt_keys.insert(key),
+                                        ElementState::Released => tilt_keys.remove(&key),
+                                    };
+                                }
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+            });
 
-        debugger.tick(&mut cpu);
+            frontend.debugger.tick(&mut cpu);
+        }
 
         cpu.execute_instr().unwrap();
-        cpu.memory.io.lcd.tick_update();
-        // thread::sleep(time::Duration::from_micros(10));
+        cpu.memory.tick_lcd();
+
+        // Input is only latched at frame boundaries so that live, recorded
+        // and replayed runs all see it at the same instruction.
+        if cpu.memory.io.lcd.vblank_interrupt_should_trigger() {
+            if opts.frames.is_some_and(|limit| frame >= limit) {
+                break;
+            }
+            if movie.as_ref().is_some_and(|m| m.finished()) {
+                println!("Movie playback finished after {} frames", frame);
+                match frontend.as_mut() {
+                    Some(frontend) => frontend.debugger.set_cheats_locked(false),
+                    None => break,
+                }
+                movie = None;
+            }
+            let tilt = if cpu.memory.cartridge().has_tilt_sensor() {
+                match tilt_script.as_mut() {
+                    Some(script) => script.at_frame(frame),
+                    None => {
+                        let stick = tilt_joystick.as_ref().map_or((0.0, 0.0), |j| j.tilt());
+                        Some(tilt_keys.iter().filter_map(|key| key_to_tilt(*key)).fold(
+                            stick,
+                            |(x, y), (dx, dy)| (x + dx, y + dy),
+                        ))
+                    }
+                }
+            } else {
+                None
+            };
+            match movie.as_mut() {
+                Some(session) => {
+                    if let Err(e) = session.frame(&mut cpu, keys, tilt) {
+                        println!("{}", e);
+                        desynced = true;
+                    }
+                }
+                None => {
+                    cpu.memory.set_buttons(keys);
+                    if let Some((x, y)) = tilt {
+                        cpu.memory.cartridge_mut().set_tilt(x, y);
+                    }
+                }
+            }
+            cpu.memory.apply_gameshark();
+            // There's no motor or speaker to drive, so they're reported in
+            // the log
+            let headless = frontend.is_none();
+            let new_rumble = cpu.memory.cartridge().rumble();
+            if new_rumble != rumble {
+                report_output(headless, frame, "rumble", new_rumble);
+                rumble = new_rumble;
+            }
+            let new_tone = cpu.memory.cartridge().tone();
+            if new_tone != tone {
+                report_output(headless, frame, "cartridge speaker", new_tone);
+                tone = new_tone;
+            }
+            if let Some(save) = save_ram.as_mut() {
+                if let Err(e) = save.frame(&mut cpu.memory) {
+                    println!("Error writing save {}: {}", save.path().display(), e);
+                }
+            }
+            frame += 1;
+        }
 
-        if cpu.memory.io.lcd.drawing_state == LcdControllerMode::VerticalBlank(4560) {
-            let lcd_vec = cpu.memory.io.lcd.lcd_display.as_bytes().to_vec();
-            display::draw(&mut display, &program, lcd_vec, (160, 144));
+        if let Some(frontend) = frontend.as_mut() {
+            if cpu.memory.io.lcd.drawing_state == LcdControllerMode::VerticalBlank(4560) {
+                let lcd_vec = cpu.memory.io.lcd.lcd_display.as_bytes().to_vec();
+                display::draw(&frontend.display, &frontend.program, lcd_vec, (160, 144));
+            }
         }
     }
+
+    if let Some(save) = save_ram.as_mut() {
+        if let Err(e) = save.flush(&mut cpu.memory) {
+            println!("Error writing save {}: {}", save.path().display(), e);
+        }
+    }
+
+    if let Some(session) = &movie {
+        if let Err(e) = session.save() {
+            println!("Error saving movie: {}", e);
+        }
+    }
+
+    if desynced {
+        process::exit(1);
+    }
 }
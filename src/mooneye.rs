@@ -0,0 +1,103 @@
+//! Runs ROMs from the Mooneye test suite, which isn't distributed with the
+//! emulator. Point `MOONEYE_ROMS` at a build of the suite, or put one in
+//! `test-roms/mooneye`. The tests that need them are ignored by default,
+//! so run them with `cargo test -- --ignored`; they fail if the ROMs are
+//! missing.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::Cpu;
+use crate::hw::controller;
+use crate::hw::memory::Memory;
+
+/// Far longer than any of the suite's ROMs take.
+const MAX_INSTRUCTIONS: u32 = 20_000_000;
+
+/// `LD B, B`, which the ROMs execute when they're done.
+const BREAKPOINT: u8 = 0x40;
+
+/// B, C, D, E, H and L after a pass. A failure leaves 0x42 in all of them.
+const PASS_REGS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+fn rom_dir() -> PathBuf {
+    match env::var_os("MOONEYE_ROMS") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("test-roms/mooneye"),
+    }
+}
+
+/// Run a ROM until it reaches its breakpoint.
+fn run(path: &Path) -> Result<(), String> {
+    let rom = fs::read(path).map_err(|e| e.to_string())?;
+    let (_, cartridge) = controller::load_cartridge(rom).map_err(|e| e.to_string())?;
+    let mut cpu = Cpu::new(Memory::new(cartridge));
+    for _ in 0..MAX_INSTRUCTIONS {
+        let pc = cpu.regs.get_pc();
+        if cpu.peek8(pc) == BREAKPOINT {
+            let regs = [
+                cpu.regs.get_b(),
+                cpu.regs.get_c(),
+                cpu.regs.get_d(),
+                cpu.regs.get_e(),
+                cpu.regs.get_h(),
+                cpu.regs.get_l(),
+            ];
+            return if regs == PASS_REGS {
+                Ok(())
+            } else {
+                Err(format!("failed with registers {:02x?}", regs))
+            };
+        }
+        cpu.execute_instr()
+            .map_err(|_| format!("unsupported instruction at {:04x}", pc))?;
+        cpu.memory.tick_lcd();
+    }
+    Err("timed out".to_string())
+}
+
+/// Run every ROM in `subdir` of the suite and panic listing the ones that
+/// fail, or if there are none to run.
+pub fn run_all(subdir: &str) {
+    let dir = rom_dir().join(subdir);
+    let mut roms: Vec<PathBuf> = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "gb"))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    assert!(!roms.is_empty(), "No Mooneye ROMs in {}", dir.display());
+    roms.sort();
+    let failures: Vec<String> = roms
+        .iter()
+        .filter_map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            run(path).err().map(|e| format!("{}: {}", name, e))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn run_checks_breakpoint_registers() {
+    let dir = env::temp_dir().join(format!("gbemu-mooneye-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // jp 0x150, past the header, to the code
+    let rom_with = |code: &[u8]| {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        rom[0x150..0x150 + code.len()].copy_from_slice(code);
+        rom[0x14D] = controller::header::header_checksum(&rom);
+        rom
+    };
+    // ld b, 3; ld c, 5; ld d, 8; ld e, 13; ld h, 21; ld l, 34; ld b, b
+    let pass = dir.join("pass.gb");
+    fs::write(&pass, rom_with(&[6, 3, 14, 5, 22, 8, 30, 13, 38, 21, 46, 34, 0x40])).unwrap();
+    assert_eq!(run(&pass), Ok(()));
+    let fail = dir.join("fail.gb");
+    fs::write(&fail, rom_with(&[6, 0x42, 0x40])).unwrap();
+    assert!(run(&fail).unwrap_err().starts_with("failed"));
+    fs::remove_dir_all(&dir).unwrap();
+}
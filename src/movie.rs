@@ -0,0 +1,436 @@
+mod error;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::Cpu;
+use crate::savestate;
+
+pub use self::error::{MovieError, MovieResult};
+
+// Movie file layout, all integers little endian:
+//
+//   "GBMV" version:u8 rom_crc32:u32 rerecords:u32 hash_interval:u32
+//   start:u8 (0 = power on, 1 = snapshot) [snapshot_len:u32 snapshot]
+//   frame_count:u32 [input:u8; frame_count]
+//   tilt_count:u32 [frame:u32 x:f32 y:f32; tilt_count]
+//   hash_count:u32 [state_hash:u32; hash_count]
+//
+// Each input byte is the joypad state latched at the start of that frame
+// (see hw::joypad for the bit layout). Tilt entries are accelerometer
+// changes, applied at the start of their frame along with the joypad.
+// State hash n is taken at the start of frame n * hash_interval, before
+// that frame's input is applied.
+const MOVIE_MAGIC: &[u8; 4] = b"GBMV";
+const MOVIE_VERSION: u8 = 2;
+const DEFAULT_HASH_INTERVAL: u32 = 60;
+
+pub enum StartPoint {
+    PowerOn,
+    Snapshot(Vec<u8>),
+}
+
+pub struct Movie {
+    pub rom_crc32: u32,
+    pub rerecord_count: u32,
+    pub start: StartPoint,
+    hash_interval: u32,
+    inputs: Vec<u8>,
+    tilts: Vec<(u32, f32, f32)>,
+    hashes: Vec<u32>,
+}
+
+struct ByteReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> MovieResult<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(MovieError::Truncated);
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> MovieResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> MovieResult<u32> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn f32(&mut self) -> MovieResult<f32> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+}
+
+impl Movie {
+    pub fn new(rom_crc32: u32, start: StartPoint) -> Movie {
+        Movie {
+            rom_crc32,
+            rerecord_count: 0,
+            start,
+            hash_interval: DEFAULT_HASH_INTERVAL,
+            inputs: Vec::new(),
+            tilts: Vec::new(),
+            hashes: Vec::new(),
+        }
+    }
+
+    pub fn frame_count(&self) -> u32 {
+        self.inputs.len() as u32
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = MOVIE_MAGIC.to_vec();
+        buf.push(MOVIE_VERSION);
+        buf.extend_from_slice(&self.rom_crc32.to_le_bytes());
+        buf.extend_from_slice(&self.rerecord_count.to_le_bytes());
+        buf.extend_from_slice(&self.hash_interval.to_le_bytes());
+        match &self.start {
+            StartPoint::PowerOn => buf.push(0),
+            StartPoint::Snapshot(state) => {
+                buf.push(1);
+                buf.extend_from_slice(&(state.len() as u32).to_le_bytes());
+                buf.extend_from_slice(state);
+            }
+        }
+        buf.extend_from_slice(&self.frame_count().to_le_bytes());
+        buf.extend_from_slice(&self.inputs);
+        buf.extend_from_slice(&(self.tilts.len() as u32).to_le_bytes());
+        for (frame, x, y) in &self.tilts {
+            buf.extend_from_slice(&frame.to_le_bytes());
+            buf.extend_from_slice(&x.to_bits().to_le_bytes());
+            buf.extend_from_slice(&y.to_bits().to_le_bytes());
+        }
+        buf.extend_from_slice(&(self.hashes.len() as u32).to_le_bytes());
+        for hash in &self.hashes {
+            buf.extend_from_slice(&hash.to_le_bytes());
+        }
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> MovieResult<Movie> {
+        let mut r = ByteReader { buf: bytes };
+        if r.take(MOVIE_MAGIC.len()).map_err(|_| MovieError::BadMagic)? != MOVIE_MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+        let version = r.u8()?;
+        if version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let rom_crc32 = r.u32()?;
+        let rerecord_count = r.u32()?;
+        let hash_interval = r.u32()?.max(1);
+        let start = match r.u8()? {
+            0 => StartPoint::PowerOn,
+            _ => {
+                let len = r.u32()? as usize;
+                StartPoint::Snapshot(r.take(len)?.to_vec())
+            }
+        };
+        let frame_count = r.u32()? as usize;
+        let inputs = r.take(frame_count)?.to_vec();
+        let tilt_count = r.u32()?;
+        let tilts = (0..tilt_count)
+            .map(|_| Ok((r.u32()?, r.f32()?, r.f32()?)))
+            .collect::<MovieResult<Vec<(u32, f32, f32)>>>()?;
+        let hash_count = r.u32()?;
+        let hashes = (0..hash_count)
+            .map(|_| r.u32())
+            .collect::<MovieResult<Vec<u32>>>()?;
+        Ok(Movie {
+            rom_crc32,
+            rerecord_count,
+            start,
+            hash_interval,
+            inputs,
+            tilts,
+            hashes,
+        })
+    }
+
+    pub fn load(path: &Path) -> MovieResult<Movie> {
+        Movie::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> MovieResult<()> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+}
+
+fn state_hash(cpu: &Cpu) -> u32 {
+    crc32fast::hash(&savestate::save_to_vec(cpu))
+}
+
+#[derive(PartialEq, Debug)]
+enum MovieMode {
+    Recording,
+    Playing,
+}
+
+/// Drives a movie alongside the emulator. `frame` must be called once at
+/// the start of every frame; it latches that frame's input into the joypad
+/// and accelerometer so recorded and replayed runs see input at exactly
+/// the same points.
+///
+/// Only the joypad and tilt are recorded. Anything else that changes what
+/// the game sees, like cheats or camera pictures, has to stay out of the
+/// run for it to replay.
+pub struct MovieSession {
+    movie: Movie,
+    path: PathBuf,
+    mode: MovieMode,
+    frame: u32,
+    /// Next entry of `movie.tilts` to replay.
+    next_tilt: usize,
+    /// Last tilt recorded, so that only changes are stored.
+    last_tilt: Option<(f32, f32)>,
+    record_after_playback: bool,
+}
+
+impl MovieSession {
+    /// Start a new recording. With `from_snapshot` the current machine state
+    /// is embedded as the starting point, otherwise the run must begin at
+    /// power on.
+    pub fn record(path: &Path, rom_crc32: u32, cpu: &Cpu, from_snapshot: bool) -> MovieSession {
+        let start = if from_snapshot {
+            StartPoint::Snapshot(savestate::save_to_vec(cpu))
+        } else {
+            StartPoint::PowerOn
+        };
+        MovieSession {
+            movie: Movie::new(rom_crc32, start),
+            path: path.to_path_buf(),
+            mode: MovieMode::Recording,
+            frame: 0,
+            next_tilt: 0,
+            last_tilt: None,
+            record_after_playback: false,
+        }
+    }
+
+    /// Replay a movie. The movie's starting point is applied to `cpu`.
+    pub fn play(path: &Path, rom_crc32: u32, cpu: &mut Cpu) -> MovieResult<MovieSession> {
+        let movie = Movie::load(path)?;
+        if movie.rom_crc32 != rom_crc32 {
+            return Err(MovieError::RomMismatch {
+                expected: movie.rom_crc32,
+                actual: rom_crc32,
+            });
+        }
+        if let StartPoint::Snapshot(state) = &movie.start {
+            savestate::load_from_slice(cpu, state)?;
+        }
+        Ok(MovieSession {
+            movie,
+            path: path.to_path_buf(),
+            mode: MovieMode::Playing,
+            frame: 0,
+            next_tilt: 0,
+            last_tilt: None,
+            record_after_playback: false,
+        })
+    }
+
+    /// Replay an existing movie and keep recording once its input runs out.
+    /// This counts as a re-record.
+    pub fn append(path: &Path, rom_crc32: u32, cpu: &mut Cpu) -> MovieResult<MovieSession> {
+        let mut session = MovieSession::play(path, rom_crc32, cpu)?;
+        session.movie.rerecord_count += 1;
+        session.record_after_playback = true;
+        if session.movie.frame_count() == 0 {
+            session.mode = MovieMode::Recording;
+        }
+        Ok(session)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.mode == MovieMode::Recording
+    }
+
+    /// True once a pure playback has consumed every recorded frame.
+    pub fn finished(&self) -> bool {
+        self.mode == MovieMode::Playing && self.frame >= self.movie.frame_count()
+    }
+
+    /// `live_tilt` is the accelerometer reading to record, or None to leave
+    /// it as it was.
+    pub fn frame(
+        &mut self,
+        cpu: &mut Cpu,
+        live_input: u8,
+        live_tilt: Option<(f32, f32)>,
+    ) -> MovieResult<()> {
+        let hash_due = self.frame.is_multiple_of(self.movie.hash_interval);
+        let mut desync = None;
+
+        let (input, tilt) = match self.mode {
+            MovieMode::Recording => {
+                if hash_due {
+                    self.movie.hashes.push(state_hash(cpu));
+                }
+                self.movie.inputs.push(live_input);
+                let tilt = live_tilt.filter(|&tilt| Some(tilt) != self.last_tilt);
+                if let Some((x, y)) = tilt {
+                    self.movie.tilts.push((self.frame, x, y));
+                    self.last_tilt = tilt;
+                }
+                (live_input, tilt)
+            }
+            MovieMode::Playing => {
+                let hash_idx = (self.frame / self.movie.hash_interval) as usize;
+                if let (true, Some(&expected)) = (hash_due, self.movie.hashes.get(hash_idx)) {
+                    let actual = state_hash(cpu);
+                    if actual != expected {
+                        desync = Some(MovieError::Desync {
+                            frame: self.frame,
+                            expected,
+                            actual,
+                        });
+                    }
+                }
+                let input = self.movie.inputs.get(self.frame as usize).cloned().unwrap_or(0);
+                let mut tilt = None;
+                while let Some(&(frame, x, y)) = self.movie.tilts.get(self.next_tilt) {
+                    if frame > self.frame {
+                        break;
+                    }
+                    tilt = Some((x, y));
+                    self.next_tilt += 1;
+                }
+                if tilt.is_some() {
+                    self.last_tilt = tilt;
+                }
+                if self.record_after_playback && self.frame + 1 >= self.movie.frame_count() {
+                    self.mode = MovieMode::Recording;
+                }
+                (input, tilt)
+            }
+        };
+
+        cpu.memory.set_buttons(input);
+        if let Some((x, y)) = tilt {
+            cpu.memory.cartridge_mut().set_tilt(x, y);
+        }
+        self.frame += 1;
+        match desync {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the movie back to disk. Pure playbacks are left untouched.
+    pub fn save(&self) -> MovieResult<()> {
+        if self.is_recording() || self.record_after_playback {
+            self.movie.save(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn movie_round_trip() {
+    let mut movie = Movie::new(0x1234_5678, StartPoint::Snapshot(vec![1, 2, 3]));
+    movie.rerecord_count = 4;
+    movie.inputs = vec![0x00, 0x10, 0x81];
+    movie.tilts = vec![(1, 0.5, -0.25)];
+    movie.hashes = vec![0xDEAD_BEEF];
+
+    let parsed = Movie::from_bytes(&movie.to_bytes()).unwrap();
+    assert_eq!(parsed.rom_crc32, 0x1234_5678);
+    assert_eq!(parsed.rerecord_count, 4);
+    assert_eq!(parsed.inputs, vec![0x00, 0x10, 0x81]);
+    assert_eq!(parsed.tilts, vec![(1, 0.5, -0.25)]);
+    assert_eq!(parsed.hashes, vec![0xDEAD_BEEF]);
+    match parsed.start {
+        StartPoint::Snapshot(state) => assert_eq!(state, vec![1, 2, 3]),
+        StartPoint::PowerOn => panic!("lost snapshot start point"),
+    }
+
+    let bytes = movie.to_bytes();
+    match Movie::from_bytes(&bytes[..bytes.len() - 1]) {
+        Err(MovieError::Truncated) => (),
+        _ => panic!("truncated movie was accepted"),
+    }
+}
+
+#[test]
+fn movie_record_and_replay() {
+    use crate::hw::controller::MBC1;
+    use crate::hw::memory::Memory;
+
+    fn new_cpu() -> Cpu {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x100] = 0x18; // jr -2
+        rom[0x101] = 0xFE;
+        Cpu::new(Memory::new(MBC1::new(rom)))
+    }
+
+    fn run_frames(cpu: &mut Cpu, session: &mut MovieSession, inputs: &[u8]) -> MovieResult<()> {
+        for &input in inputs {
+            session.frame(cpu, input, None)?;
+            loop {
+                cpu.execute_instr().unwrap();
+                cpu.memory.tick_lcd();
+                if cpu.memory.io.lcd.vblank_interrupt_should_trigger() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    let path = std::env::temp_dir().join(format!("gbemu-movie-{}.gbm", std::process::id()));
+    let inputs = [0x00, 0x10, 0x10, 0x81];
+
+    let mut cpu = new_cpu();
+    let mut session = MovieSession::record(&path, 0xABCD, &cpu, false);
+    run_frames(&mut cpu, &mut session, &inputs).unwrap();
+    session.save().unwrap();
+
+    let mut cpu = new_cpu();
+    let mut session = MovieSession::play(&path, 0xABCD, &mut cpu).unwrap();
+    run_frames(&mut cpu, &mut session, &[0; 4]).unwrap();
+    assert!(session.finished());
+    assert_eq!(cpu.memory.io.joypad.buttons(), 0x81);
+
+    match MovieSession::play(&path, 0x1234, &mut new_cpu()) {
+        Err(MovieError::RomMismatch { .. }) => (),
+        _ => panic!("movie played against the wrong ROM"),
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn movie_records_tilt_changes() {
+    use crate::hw::controller::MBC1;
+    use crate::hw::memory::Memory;
+
+    let path = std::env::temp_dir().join(format!("gbemu-tilt-{}.gbm", std::process::id()));
+    let mut cpu = Cpu::new(Memory::new(MBC1::new(vec![0u8; 0x8000])));
+    let mut session = MovieSession::record(&path, 0xABCD, &cpu, false);
+    let tilts = [None, Some((0.5, 0.0)), Some((0.5, 0.0)), None, Some((0.0, -1.0))];
+    for &tilt in &tilts {
+        session.frame(&mut cpu, 0, tilt).unwrap();
+    }
+    assert_eq!(session.movie.tilts, vec![(1, 0.5, 0.0), (4, 0.0, -1.0)]);
+    session.save().unwrap();
+
+    // Live tilt is ignored on playback
+    let mut session = MovieSession::play(&path, 0xABCD, &mut cpu).unwrap();
+    let mut replayed = Vec::new();
+    for _ in 0..tilts.len() {
+        session.frame(&mut cpu, 0, Some((2.0, 2.0))).unwrap();
+        replayed.push(session.last_tilt);
+    }
+    let (a, b) = (Some((0.5, 0.0)), Some((0.0, -1.0)));
+    assert_eq!(replayed, vec![None, a, a, a, b]);
+    fs::remove_file(&path).unwrap();
+}
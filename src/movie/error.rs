@@ -0,0 +1,58 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::savestate::StateError;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    RomMismatch { expected: u32, actual: u32 },
+    State(StateError),
+    Desync { frame: u32, expected: u32, actual: u32 },
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Io(e) => write!(f, "Movie I/O error: {}", e),
+            MovieError::BadMagic => write!(f, "Not a movie file"),
+            MovieError::UnsupportedVersion(v) => write!(f, "Unsupported movie version {}", v),
+            MovieError::Truncated => write!(f, "Movie file is truncated"),
+            MovieError::RomMismatch { expected, actual } => write!(
+                f,
+                "Movie was recorded with ROM CRC32 {:08x}, loaded ROM is {:08x}",
+                expected, actual
+            ),
+            MovieError::State(e) => write!(f, "Movie snapshot: {}", e),
+            MovieError::Desync {
+                frame,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Movie desync at frame {}: state hash {:08x}, expected {:08x}",
+                frame, actual, expected
+            ),
+        }
+    }
+}
+
+impl error::Error for MovieError {}
+
+impl From<io::Error> for MovieError {
+    fn from(e: io::Error) -> MovieError {
+        MovieError::Io(e)
+    }
+}
+
+impl From<StateError> for MovieError {
+    fn from(e: StateError) -> MovieError {
+        MovieError::State(e)
+    }
+}
+
+pub type MovieResult<T> = Result<T, MovieError>;
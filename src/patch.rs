@@ -0,0 +1,408 @@
+mod error;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::hw::controller::MAX_ROM_SIZE;
+
+pub use self::error::{PatchError, PatchResult};
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+/// UPS and BPS both end with the source, target and patch CRC32s.
+const FOOTER_SIZE: usize = 12;
+
+/// Target sizes come straight from the patch and are allocated before any
+/// CRC can be checked, so ones larger than any cartridge are refused.
+const MAX_TARGET_SIZE: usize = MAX_ROM_SIZE;
+
+/// Extensions of patches picked up from next to the ROM, in the order
+/// they're looked for.
+const PATCH_EXTENSIONS: &[&str] = &["ips", "ups", "bps"];
+
+/// The patch next to the ROM with the same base name, if there is one.
+/// `base` is the path from `rom_file::base_name`.
+pub fn find_patch(base: &Path) -> Option<PathBuf> {
+    PATCH_EXTENSIONS
+        .iter()
+        .map(|ext| base.with_extension(ext))
+        .find(|path| path.is_file())
+}
+
+/// Read the patch at `path` and apply it to `rom`.
+pub fn apply_file(rom: &[u8], path: &Path) -> PatchResult<Vec<u8>> {
+    let patch = fs::read(path).map_err(|error| PatchError::Io {
+        path: path.to_path_buf(),
+        error,
+    })?;
+    apply(rom, &patch)
+}
+
+/// Apply an IPS, UPS or BPS patch, told apart by their magic numbers.
+/// UPS and BPS patches are checked against the CRCs they carry.
+pub fn apply(rom: &[u8], patch: &[u8]) -> PatchResult<Vec<u8>> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+/// Reads through a patch, failing with `Truncated` at the end.
+struct PatchReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> PatchReader<'a> {
+        PatchReader { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> PatchResult<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(PatchError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> PatchResult<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16_be(&mut self) -> PatchResult<usize> {
+        let bytes = self.bytes(2)?;
+        Ok((bytes[0] as usize) << 8 | bytes[1] as usize)
+    }
+
+    fn u24_be(&mut self) -> PatchResult<usize> {
+        let bytes = self.bytes(3)?;
+        Ok((bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize)
+    }
+
+    fn u32_le(&mut self) -> PatchResult<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// The variable length numbers UPS and BPS use: seven bits a byte,
+    /// least significant first, with the top bit marking the last byte.
+    fn number(&mut self) -> PatchResult<usize> {
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.u8()?;
+            value = (byte as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|v| v.checked_add(value))
+                .ok_or(PatchError::OutOfRange)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).ok_or(PatchError::OutOfRange)?;
+            value = value.checked_add(shift).ok_or(PatchError::OutOfRange)?;
+        }
+    }
+
+    /// A UPS or BPS target size, which has to fit a cartridge.
+    fn target_size(&mut self) -> PatchResult<usize> {
+        let size = self.number()?;
+        if size > MAX_TARGET_SIZE {
+            return Err(PatchError::OutOfRange);
+        }
+        Ok(size)
+    }
+}
+
+/// IPS: records of offset, length and bytes to write, with length 0
+/// meaning a run of one byte. The ROM grows to fit, and an optional length
+/// after the end marker truncates it.
+fn apply_ips(rom: &[u8], patch: &[u8]) -> PatchResult<Vec<u8>> {
+    let mut out = rom.to_vec();
+    let mut r = PatchReader::new(patch, IPS_MAGIC.len());
+    loop {
+        if r.data[r.pos..].starts_with(IPS_EOF) {
+            r.pos += IPS_EOF.len();
+            if let Ok(len) = r.u24_be() {
+                out.truncate(len);
+            }
+            return Ok(out);
+        }
+        let offset = r.u24_be()?;
+        let len = r.u16_be()?;
+        let (len, run) = match len {
+            0 => (r.u16_be()?, Some(r.u8()?)),
+            len => (len, None),
+        };
+        if out.len() < offset + len {
+            out.resize(offset + len, 0);
+        }
+        match run {
+            Some(byte) => out[offset..offset + len].iter_mut().for_each(|b| *b = byte),
+            None => out[offset..offset + len].copy_from_slice(r.bytes(len)?),
+        }
+    }
+}
+
+/// The CRC footer shared by UPS and BPS, checked against the patch itself
+/// and the source ROM.
+struct Footer {
+    source: u32,
+    target: u32,
+}
+
+fn check_footer(rom: &[u8], patch: &[u8]) -> PatchResult<Footer> {
+    if patch.len() < FOOTER_SIZE + 4 {
+        return Err(PatchError::Truncated);
+    }
+    let mut r = PatchReader::new(patch, patch.len() - FOOTER_SIZE);
+    let footer = Footer {
+        source: r.u32_le()?,
+        target: r.u32_le()?,
+    };
+    let expected = r.u32_le()?;
+    let actual = crc32fast::hash(&patch[..patch.len() - 4]);
+    if expected != actual {
+        return Err(PatchError::PatchMismatch { expected, actual });
+    }
+    let actual = crc32fast::hash(rom);
+    if footer.source != actual {
+        return Err(PatchError::SourceMismatch {
+            expected: footer.source,
+            actual,
+        });
+    }
+    Ok(footer)
+}
+
+fn check_target(footer: &Footer, out: &[u8]) -> PatchResult<()> {
+    let actual = crc32fast::hash(out);
+    if footer.target != actual {
+        return Err(PatchError::TargetMismatch {
+            expected: footer.target,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+/// UPS: runs of bytes XORed into the ROM, each after a gap of unchanged
+/// ones.
+fn apply_ups(rom: &[u8], patch: &[u8]) -> PatchResult<Vec<u8>> {
+    let footer = check_footer(rom, patch)?;
+    let end = patch.len() - FOOTER_SIZE;
+    let mut r = PatchReader::new(&patch[..end], UPS_MAGIC.len());
+    let _source_size = r.number()?;
+    let target_size = r.target_size()?;
+    let mut out = rom.to_vec();
+    out.resize(target_size, 0);
+
+    let mut pos = 0usize;
+    while r.pos < end {
+        pos = pos.checked_add(r.number()?).ok_or(PatchError::OutOfRange)?;
+        loop {
+            let byte = r.u8()?;
+            if byte == 0 {
+                pos += 1;
+                break;
+            }
+            if let Some(out_byte) = out.get_mut(pos) {
+                *out_byte ^= byte;
+            }
+            pos += 1;
+        }
+    }
+    check_target(&footer, &out)?;
+    Ok(out)
+}
+
+/// BPS: the target is built up from runs copied out of the source, the
+/// patch, or the target written so far.
+fn apply_bps(rom: &[u8], patch: &[u8]) -> PatchResult<Vec<u8>> {
+    let footer = check_footer(rom, patch)?;
+    let end = patch.len() - FOOTER_SIZE;
+    let mut r = PatchReader::new(&patch[..end], BPS_MAGIC.len());
+    let _source_size = r.number()?;
+    let target_size = r.target_size()?;
+    let metadata_size = r.number()?;
+    r.bytes(metadata_size)?;
+
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_rel = 0usize;
+    let mut target_rel = 0usize;
+    // Moves a relative copy position by the signed offset that comes next
+    let seek = |r: &mut PatchReader, rel: usize| -> PatchResult<usize> {
+        let offset = r.number()?;
+        let moved = if offset & 1 != 0 {
+            rel.checked_sub(offset >> 1)
+        } else {
+            rel.checked_add(offset >> 1)
+        };
+        moved.ok_or(PatchError::OutOfRange)
+    };
+    while r.pos < end {
+        let action = r.number()?;
+        let len = (action >> 2) + 1;
+        if out.len() + len > target_size {
+            return Err(PatchError::OutOfRange);
+        }
+        match action & 3 {
+            // Source read: the bytes at the same offset in the ROM
+            0 => {
+                let start = out.len();
+                let bytes = rom.get(start..start + len).ok_or(PatchError::OutOfRange)?;
+                out.extend_from_slice(bytes);
+            }
+            // Target read: bytes from the patch
+            1 => out.extend_from_slice(r.bytes(len)?),
+            2 => {
+                source_rel = seek(&mut r, source_rel)?;
+                let source_end = source_rel.checked_add(len).ok_or(PatchError::OutOfRange)?;
+                let bytes = rom.get(source_rel..source_end).ok_or(PatchError::OutOfRange)?;
+                out.extend_from_slice(bytes);
+                source_rel += len;
+            }
+            // Target copy can overlap what it's writing, so go a byte at
+            // a time
+            _ => {
+                target_rel = seek(&mut r, target_rel)?;
+                for _ in 0..len {
+                    let byte = *out.get(target_rel).ok_or(PatchError::OutOfRange)?;
+                    out.push(byte);
+                    target_rel += 1;
+                }
+            }
+        }
+    }
+    if out.len() != target_size {
+        return Err(PatchError::Truncated);
+    }
+    check_target(&footer, &out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+fn encode_number(mut value: usize, out: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte | 0x80);
+            return;
+        }
+        out.push(byte);
+        value -= 1;
+    }
+}
+
+#[cfg(test)]
+fn finish_patch(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+    patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+    patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+    let crc = crc32fast::hash(&patch);
+    patch.extend_from_slice(&crc.to_le_bytes());
+    patch
+}
+
+#[test]
+fn ips_records_and_runs() {
+    let mut patch = IPS_MAGIC.to_vec();
+    patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+    patch.extend_from_slice(&[0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+    patch.extend_from_slice(IPS_EOF);
+    assert_eq!(
+        apply(&[0; 4], &patch).unwrap(),
+        vec![0x00, 0xAA, 0xBB, 0x00, 0x00, 0xCC, 0xCC, 0xCC]
+    );
+
+    // Truncation length after the end marker
+    patch.extend_from_slice(&[0x00, 0x00, 0x03]);
+    assert_eq!(apply(&[0; 4], &patch).unwrap(), vec![0x00, 0xAA, 0xBB]);
+
+    assert!(matches!(apply(&[0; 4], &patch[..10]), Err(PatchError::Truncated)));
+    assert!(matches!(apply(&[0; 4], b"NOT A PATCH"), Err(PatchError::UnknownFormat)));
+}
+
+#[test]
+fn ups_xor_hunks_and_crcs() {
+    let source = [1, 2, 3, 4];
+    let target = [1, 9, 3, 4, 5];
+    let mut patch = UPS_MAGIC.to_vec();
+    encode_number(source.len(), &mut patch);
+    encode_number(target.len(), &mut patch);
+    encode_number(1, &mut patch);
+    patch.extend_from_slice(&[2 ^ 9, 0]);
+    encode_number(1, &mut patch);
+    patch.extend_from_slice(&[5, 0]);
+    let patch = finish_patch(patch, &source, &target);
+    assert_eq!(apply(&source, &patch).unwrap(), target.to_vec());
+
+    assert!(matches!(
+        apply(&[1, 2, 3, 5], &patch),
+        Err(PatchError::SourceMismatch { .. })
+    ));
+    let mut huge = UPS_MAGIC.to_vec();
+    encode_number(source.len(), &mut huge);
+    encode_number(usize::MAX >> 8, &mut huge);
+    let huge = finish_patch(huge, &source, &target);
+    assert!(matches!(apply(&source, &huge), Err(PatchError::OutOfRange)));
+    let mut damaged = patch.clone();
+    damaged[6] ^= 1;
+    assert!(matches!(
+        apply(&source, &damaged),
+        Err(PatchError::PatchMismatch { .. })
+    ));
+}
+
+#[test]
+fn bps_actions_and_crcs() {
+    let source = [1, 2, 3, 4];
+    let target = [1, 2, 9, 9, 9, 9, 1, 2];
+    let mut patch = BPS_MAGIC.to_vec();
+    encode_number(source.len(), &mut patch);
+    encode_number(target.len(), &mut patch);
+    encode_number(0, &mut patch);
+    // Source read 2, target read 1, target copy 3 from 2, source copy 2
+    // from 0
+    encode_number((2 - 1) << 2, &mut patch);
+    encode_number(1, &mut patch);
+    patch.push(9);
+    encode_number((3 - 1) << 2 | 3, &mut patch);
+    encode_number(2 << 1, &mut patch);
+    encode_number((2 - 1) << 2 | 2, &mut patch);
+    encode_number(0, &mut patch);
+    let good = finish_patch(patch.clone(), &source, &target);
+    assert_eq!(apply(&source, &good).unwrap(), target.to_vec());
+
+    let mut huge = BPS_MAGIC.to_vec();
+    encode_number(source.len(), &mut huge);
+    encode_number(MAX_TARGET_SIZE + 1, &mut huge);
+    encode_number(0, &mut huge);
+    let huge = finish_patch(huge, &source, &target);
+    assert!(matches!(apply(&source, &huge), Err(PatchError::OutOfRange)));
+
+    // A source copy seeked about as far as an offset can go
+    let mut far = BPS_MAGIC.to_vec();
+    encode_number(source.len(), &mut far);
+    encode_number(target.len(), &mut far);
+    encode_number(0, &mut far);
+    encode_number((2 - 1) << 2 | 2, &mut far);
+    encode_number((usize::MAX - 1) & !1, &mut far);
+    let far = finish_patch(far, &source, &target);
+    assert!(matches!(apply(&source, &far), Err(PatchError::OutOfRange)));
+
+    let wrong_target = finish_patch(patch, &source, &[0; 8]);
+    assert!(matches!(
+        apply(&source, &wrong_target),
+        Err(PatchError::TargetMismatch { .. })
+    ));
+}
@@ -0,0 +1,62 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum PatchError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// Not an IPS, UPS or BPS patch.
+    UnknownFormat,
+    Truncated,
+    /// A record that reads or writes outside the ROM.
+    OutOfRange,
+    /// The patch was made for a different ROM.
+    SourceMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// The patched ROM isn't what the patch says it should produce.
+    TargetMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// The patch file itself is damaged.
+    PatchMismatch {
+        expected: u32,
+        actual: u32,
+    },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::Io { path, error } => write!(f, "Can't read {}: {}", path.display(), error),
+            PatchError::UnknownFormat => write!(f, "Not an IPS, UPS or BPS patch"),
+            PatchError::Truncated => write!(f, "Patch is truncated"),
+            PatchError::OutOfRange => write!(f, "Patch reaches outside the ROM"),
+            PatchError::SourceMismatch { expected, actual } => write!(
+                f,
+                "Patch is for a ROM with CRC32 {:08x}, this one is {:08x}",
+                expected, actual
+            ),
+            PatchError::TargetMismatch { expected, actual } => write!(
+                f,
+                "Patched ROM has CRC32 {:08x}, expected {:08x}",
+                actual, expected
+            ),
+            PatchError::PatchMismatch { expected, actual } => write!(
+                f,
+                "Patch file has CRC32 {:08x}, expected {:08x}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl error::Error for PatchError {}
+
+pub type PatchResult<T> = Result<T, PatchError>;
@@ -0,0 +1,246 @@
+use std::fmt;
+
+use crate::hw::controller::RAM_BANK_SIZE;
+use crate::hw::memory::{BusWidth, Memory};
+
+const WRAM_BANK_SIZE: usize = 0x1000;
+
+/// Where a candidate lives. Offsets are into the whole region, across all
+/// of its banks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Region {
+    Wram,
+    Hram,
+    CartridgeRam,
+}
+
+const REGIONS: [Region; 3] = [Region::Wram, Region::Hram, Region::CartridgeRam];
+
+fn region_bytes(memory: &Memory, region: Region) -> &[u8] {
+    match region {
+        Region::Wram => memory.wram(),
+        Region::Hram => memory.hram(),
+        Region::CartridgeRam => memory.cartridge().ram(),
+    }
+}
+
+/// How the bytes at a candidate are read as a number.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ValueType {
+    U8,
+    /// Little endian.
+    U16,
+    /// Two packed BCD digits.
+    Bcd8,
+    /// Four packed BCD digits, little endian.
+    Bcd16,
+}
+
+impl ValueType {
+    pub fn parse(name: &str) -> Option<ValueType> {
+        match name {
+            "u8" => Some(ValueType::U8),
+            "u16" => Some(ValueType::U16),
+            "bcd8" => Some(ValueType::Bcd8),
+            "bcd16" => Some(ValueType::Bcd16),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            ValueType::U8 | ValueType::Bcd8 => 1,
+            ValueType::U16 | ValueType::Bcd16 => 2,
+        }
+    }
+
+    /// None if the bytes aren't valid BCD.
+    fn decode(self, bytes: &[u8]) -> Option<u32> {
+        let bcd = |byte: u8| {
+            let (high, low) = (byte >> 4, byte & 0xF);
+            if high < 10 && low < 10 {
+                Some(high as u32 * 10 + low as u32)
+            } else {
+                None
+            }
+        };
+        match self {
+            ValueType::U8 => Some(bytes[0] as u32),
+            ValueType::U16 => Some(bytes[0] as u32 | (bytes[1] as u32) << 8),
+            ValueType::Bcd8 => bcd(bytes[0]),
+            ValueType::Bcd16 => Some(bcd(bytes[0])? + bcd(bytes[1])? * 100),
+        }
+    }
+}
+
+/// How to narrow the candidates, comparing each one's value now with
+/// the value it had at the last snapshot.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Filter {
+    Equal(u32),
+    Unchanged,
+    Changed,
+    Increased,
+    Decreased,
+    IncreasedBy(u32),
+    DecreasedBy(u32),
+}
+
+impl Filter {
+    fn keeps(self, previous: u32, current: u32) -> bool {
+        match self {
+            Filter::Equal(value) => current == value,
+            Filter::Unchanged => current == previous,
+            Filter::Changed => current != previous,
+            Filter::Increased => current > previous,
+            Filter::Decreased => current < previous,
+            Filter::IncreasedBy(n) => previous.checked_add(n) == Some(current),
+            Filter::DecreasedBy(n) => previous.checked_sub(n) == Some(current),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Candidate {
+    pub region: Region,
+    pub offset: usize,
+    /// Value as of the last snapshot.
+    pub value: u32,
+}
+
+impl Candidate {
+    /// Where the CPU sees the candidate when its bank is switched in.
+    pub fn addr(&self) -> BusWidth {
+        match self.region {
+            Region::Wram if self.offset < WRAM_BANK_SIZE => 0xC000 + self.offset as BusWidth,
+            Region::Wram => 0xD000 + (self.offset % WRAM_BANK_SIZE) as BusWidth,
+            Region::Hram => 0xFF80 + self.offset as BusWidth,
+            Region::CartridgeRam => 0xA000 + (self.offset % RAM_BANK_SIZE) as BusWidth,
+        }
+    }
+
+    pub fn bank(&self) -> usize {
+        match self.region {
+            Region::Wram => self.offset / WRAM_BANK_SIZE,
+            Region::Hram => 0,
+            Region::CartridgeRam => self.offset / RAM_BANK_SIZE,
+        }
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:04x} = {}", self.bank(), self.addr(), self.value)
+    }
+}
+
+/// Finds where a game keeps a value by taking a snapshot of RAM and then
+/// repeatedly throwing out the locations that don't behave as the value
+/// did, e.g. "decreased" after losing a life.
+pub struct RamSearch {
+    value_type: ValueType,
+    candidates: Vec<Candidate>,
+}
+
+impl RamSearch {
+    /// Start with every location in WRAM, HRAM and cartridge RAM.
+    pub fn new(memory: &Memory, value_type: ValueType) -> RamSearch {
+        let mut candidates = Vec::new();
+        for &region in &REGIONS {
+            let bytes = region_bytes(memory, region);
+            let size = value_type.size();
+            for offset in 0..(bytes.len() + 1).saturating_sub(size) {
+                if let Some(value) = value_type.decode(&bytes[offset..offset + size]) {
+                    candidates.push(Candidate {
+                        region,
+                        offset,
+                        value,
+                    });
+                }
+            }
+        }
+        RamSearch {
+            value_type,
+            candidates,
+        }
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        self.value_type
+    }
+
+    pub fn candidates(&self) -> &[Candidate] {
+        &self.candidates
+    }
+
+    fn current(&self, memory: &Memory, candidate: &Candidate) -> Option<u32> {
+        let bytes = region_bytes(memory, candidate.region);
+        let size = self.value_type.size();
+        self.value_type
+            .decode(bytes.get(candidate.offset..candidate.offset + size)?)
+    }
+
+    /// Drop the candidates `filter` rejects and snapshot the rest. Returns
+    /// how many are left.
+    pub fn filter(&mut self, memory: &Memory, filter: Filter) -> usize {
+        let mut kept = Vec::new();
+        for candidate in &self.candidates {
+            if let Some(value) = self.current(memory, candidate) {
+                if filter.keeps(candidate.value, value) {
+                    kept.push(Candidate {
+                        value,
+                        ..*candidate
+                    });
+                }
+            }
+        }
+        self.candidates = kept;
+        self.candidates.len()
+    }
+}
+
+#[test]
+fn ram_search_narrows_candidates() {
+    use crate::hw::controller::MBC1;
+    use crate::hw::memory::Bus;
+
+    let mut memory = Memory::new(MBC1::new(vec![0u8; 0x8000]));
+    memory.write8(0xC123, 3);
+    memory.write8(0xD456, 3);
+    let mut search = RamSearch::new(&memory, ValueType::U8);
+    search.filter(&memory, Filter::Equal(3));
+    assert_eq!(search.candidates().len(), 2);
+
+    memory.write8(0xC123, 2);
+    memory.write8(0xD456, 4);
+    assert_eq!(search.filter(&memory, Filter::Decreased), 1);
+    let found = search.candidates()[0];
+    assert_eq!((found.addr(), found.bank(), found.value), (0xC123, 0, 2));
+    assert_eq!(found.to_string(), "00:c123 = 2");
+
+    memory.write8(0xC123, 1);
+    assert_eq!(search.filter(&memory, Filter::DecreasedBy(2)), 0);
+}
+
+#[test]
+fn ram_search_value_types() {
+    use crate::hw::controller::MBC1;
+    use crate::hw::memory::Bus;
+
+    let mut memory = Memory::new(MBC1::new(vec![0u8; 0x8000]));
+    memory.write8(0xFF90, 0x99);
+    memory.write8(0xFF91, 0x12);
+    let mut search = RamSearch::new(&memory, ValueType::Bcd16);
+    assert_eq!(search.filter(&memory, Filter::Equal(1299)), 1);
+    assert_eq!(search.candidates()[0].addr(), 0xFF90);
+
+    memory.write8(0xFF90, 0x00);
+    memory.write8(0xFF91, 0x13);
+    assert_eq!(search.filter(&memory, Filter::IncreasedBy(1)), 1);
+
+    let search = RamSearch::new(&memory, ValueType::U16);
+    assert!(search
+        .candidates()
+        .iter()
+        .any(|c| c.addr() == 0xFF90 && c.value == 0x1300));
+}
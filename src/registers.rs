@@ -1,5 +1,7 @@
 #![allow(dead_code)]
 
+use crate::savestate::{SaveState, StateReader, StateResult, StateWriter};
+
 #[derive(Debug)]
 pub struct Registers {
     af: u16,
@@ -150,6 +152,24 @@ impl Registers {
 
 }
 
+impl SaveState for Registers {
+    fn save_state(&self, w: &mut StateWriter) {
+        for reg in &[self.af, self.bc, self.de, self.hl, self.sp, self.pc] {
+            w.write_u16(*reg);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.af = r.read_u16()?;
+        self.bc = r.read_u16()?;
+        self.de = r.read_u16()?;
+        self.hl = r.read_u16()?;
+        self.sp = r.read_u16()?;
+        self.pc = r.read_u16()?;
+        Ok(())
+    }
+}
+
 #[test]
 fn basic_reg_test() {
     let mut regs = Registers::new();
@@ -0,0 +1,170 @@
+mod error;
+
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use crate::hw::controller::MAX_ROM_SIZE;
+
+pub use self::error::{RomFileError, RomFileResult};
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const ZIP_EMPTY_MAGIC: &[u8] = b"PK\x05\x06";
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+const SEVEN_ZIP_MAGIC: &[u8] = &[b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C];
+
+/// A ROM image and where in its file it came from.
+pub struct RomFile {
+    pub data: Vec<u8>,
+    /// The zip entry the ROM was extracted from.
+    pub entry: Option<String>,
+}
+
+/// Read a ROM that may be a plain image, gzipped, or inside a zip. The
+/// format is told from the file's contents, not its name. From a zip the
+/// first `.gb` or `.gbc` entry is used, unless `entry` names another;
+/// naming an entry for any other kind of file is an error.
+pub fn load(path: &Path, entry: Option<&str>) -> RomFileResult<RomFile> {
+    let data = fs::read(path).map_err(|error| RomFileError::Io {
+        path: path.to_path_buf(),
+        error,
+    })?;
+    extract(data, entry)
+}
+
+/// `load` for a file already in memory.
+pub fn extract(data: Vec<u8>, entry: Option<&str>) -> RomFileResult<RomFile> {
+    if data.starts_with(ZIP_MAGIC) || data.starts_with(ZIP_EMPTY_MAGIC) {
+        return extract_zip(data, entry);
+    }
+    if let Some(name) = entry {
+        return Err(RomFileError::NotAnArchive(name.to_string()));
+    }
+    if data.starts_with(GZIP_MAGIC) {
+        let rom = read_limited(GzDecoder::new(&data[..]), RomFileError::Gzip)?;
+        Ok(RomFile {
+            data: rom,
+            entry: None,
+        })
+    } else if data.starts_with(SEVEN_ZIP_MAGIC) {
+        Err(RomFileError::SevenZip)
+    } else {
+        Ok(RomFile { data, entry: None })
+    }
+}
+
+/// Decompress at most `MAX_ROM_SIZE` bytes, so that a small archive can't
+/// unpack to fill memory.
+fn read_limited(
+    reader: impl Read,
+    read_error: impl FnOnce(io::Error) -> RomFileError,
+) -> RomFileResult<Vec<u8>> {
+    let mut rom = Vec::new();
+    reader
+        .take(MAX_ROM_SIZE as u64 + 1)
+        .read_to_end(&mut rom)
+        .map_err(read_error)?;
+    if rom.len() > MAX_ROM_SIZE {
+        return Err(RomFileError::TooLarge);
+    }
+    Ok(rom)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.ends_with(".gb") || name.ends_with(".gbc")
+}
+
+fn extract_zip(data: Vec<u8>, entry: Option<&str>) -> RomFileResult<RomFile> {
+    let mut archive = ZipArchive::new(Cursor::new(data))?;
+    let name = match entry {
+        Some(name) => {
+            if archive.file_names().all(|n| n != name) {
+                return Err(RomFileError::EntryNotFound(name.to_string()));
+            }
+            name.to_string()
+        }
+        // file_names() is in hash order, so go by index for the first one
+        None => (0..archive.len())
+            .map(|i| archive.by_index(i).map(|file| file.name().to_string()))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .find(|name| is_rom_name(name))
+            .ok_or(RomFileError::NoRomInArchive)?,
+    };
+    // The size in the header isn't checked until the entry has been read, so
+    // don't go by it
+    let rom = read_limited(archive.by_name(&name)?, |e| RomFileError::Zip(e.into()))?;
+    Ok(RomFile {
+        data: rom,
+        entry: Some(name),
+    })
+}
+
+/// The path save files and patches are named after: the ROM's own, or the
+/// archive's with a `.gz` dropped so `game.gb.gz` saves to `game.sav`.
+pub fn base_name(path: &Path) -> PathBuf {
+    match path.extension() {
+        Some(ext) if ext.eq_ignore_ascii_case("gz") => path.with_extension(""),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[test]
+fn extract_zip_and_gzip() {
+    use std::io::Write;
+
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let options = zip::write::FileOptions::default();
+    zip.start_file("readme.txt", options).unwrap();
+    zip.write_all(b"hello").unwrap();
+    zip.start_file("Game.GBC", options).unwrap();
+    zip.write_all(&[1, 2, 3]).unwrap();
+    zip.start_file("hack.gb", options).unwrap();
+    zip.write_all(&[4, 5]).unwrap();
+    let zip = zip.finish().unwrap().into_inner();
+
+    let rom = extract(zip.clone(), None).unwrap();
+    assert_eq!((rom.data, rom.entry.as_deref()), (vec![1, 2, 3], Some("Game.GBC")));
+    assert_eq!(extract(zip.clone(), Some("hack.gb")).unwrap().data, vec![4, 5]);
+    assert!(matches!(
+        extract(zip, Some("other.gb")),
+        Err(RomFileError::EntryNotFound(_))
+    ));
+
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(&[6, 7, 8]).unwrap();
+    let gz = gz.finish().unwrap();
+    assert_eq!(extract(gz.clone(), None).unwrap().data, vec![6, 7, 8]);
+    assert!(matches!(
+        extract(gz, Some("game.gb")),
+        Err(RomFileError::NotAnArchive(_))
+    ));
+
+    // Compresses to a few KiB
+    let mut bomb = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    bomb.write_all(&vec![0; MAX_ROM_SIZE + 1]).unwrap();
+    let bomb = bomb.finish().unwrap();
+    assert!(matches!(extract(bomb, None), Err(RomFileError::TooLarge)));
+
+    assert_eq!(extract(vec![9, 9], None).unwrap().data, vec![9, 9]);
+    assert!(matches!(
+        extract(vec![9, 9], Some("game.gb")),
+        Err(RomFileError::NotAnArchive(_))
+    ));
+    assert!(matches!(
+        extract(SEVEN_ZIP_MAGIC.to_vec(), None),
+        Err(RomFileError::SevenZip)
+    ));
+    assert_eq!(base_name(Path::new("roms/game.gb.gz")), Path::new("roms/game.gb"));
+    assert_eq!(base_name(Path::new("roms/game.zip")), Path::new("roms/game.zip"));
+}
+
+#[test]
+fn load_missing_file() {
+    let err = load(Path::new("/nonexistent/game.gb"), None).err().unwrap();
+    assert!(err.to_string().starts_with("Can't read /nonexistent/game.gb"));
+}
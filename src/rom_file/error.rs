@@ -0,0 +1,56 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::hw::controller::MAX_ROM_SIZE;
+
+#[derive(Debug)]
+pub enum RomFileError {
+    Io { path: PathBuf, error: io::Error },
+    Zip(zip::result::ZipError),
+    /// A gzip stream that fails to decompress.
+    Gzip(io::Error),
+    SevenZip,
+    /// A zip archive without a `.gb` or `.gbc` file in it.
+    NoRomInArchive,
+    /// The entry asked for with `--rom-entry` isn't in the archive.
+    EntryNotFound(String),
+    /// `--rom-entry` given for a file that isn't a zip archive.
+    NotAnArchive(String),
+    /// A compressed ROM that unpacks to more than any cartridge holds.
+    TooLarge,
+}
+
+impl fmt::Display for RomFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomFileError::Io { path, error } => {
+                write!(f, "Can't read {}: {}", path.display(), error)
+            }
+            RomFileError::Zip(e) => write!(f, "Bad zip archive: {}", e),
+            RomFileError::Gzip(e) => write!(f, "Bad gzip file: {}", e),
+            RomFileError::SevenZip => {
+                write!(f, "7z archives aren't supported, extract the ROM first")
+            }
+            RomFileError::NoRomInArchive => write!(f, "No .gb or .gbc file in the archive"),
+            RomFileError::EntryNotFound(name) => write!(f, "No {} in the archive", name),
+            RomFileError::TooLarge => {
+                write!(f, "Unpacks to more than {} bytes, too large for a ROM", MAX_ROM_SIZE)
+            }
+            RomFileError::NotAnArchive(name) => {
+                write!(f, "Can't pick {} from a file that isn't a zip archive", name)
+            }
+        }
+    }
+}
+
+impl error::Error for RomFileError {}
+
+impl From<zip::result::ZipError> for RomFileError {
+    fn from(e: zip::result::ZipError) -> RomFileError {
+        RomFileError::Zip(e)
+    }
+}
+
+pub type RomFileResult<T> = Result<T, RomFileError>;
@@ -0,0 +1,165 @@
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+
+use crate::hw::controller::Cartridge;
+use crate::hw::memory::Memory;
+
+/// Frames without a cartridge RAM write before unsaved changes are
+/// flushed, about three seconds.
+const AUTOSAVE_IDLE_FRAMES: u32 = 180;
+
+/// Keeps a battery backed cartridge's `.sav` file in step with its RAM.
+///
+/// Saves are written to a temporary file and renamed into place, so a
+/// crash mid-write never leaves a truncated save. The save as it was when
+/// the session started is kept as `.sav.bak` the first time it's replaced.
+pub struct SaveRam {
+    path: PathBuf,
+    backed_up: bool,
+    /// Frames since the last RAM write, while there are unsaved changes.
+    idle_frames: Option<u32>,
+    /// RAM as of the last change seen. Some mappers put registers in the
+    /// RAM area, so a write there doesn't always mean RAM changed.
+    last_ram: Vec<u8>,
+}
+
+impl SaveRam {
+    /// The save lives next to the ROM unless `save_dir` is given, and is
+    /// named after it.
+    pub fn new(rom_path: &Path, save_dir: Option<&Path>) -> SaveRam {
+        let file_name = rom_path.with_extension("sav");
+        let path = match (save_dir, file_name.file_name()) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => file_name,
+        };
+        SaveRam {
+            path,
+            backed_up: false,
+            idle_frames: None,
+            last_ram: Vec::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load the save into the cartridge. Returns false if there's no save
+    /// yet.
+    pub fn load(&mut self, cartridge: &mut dyn Cartridge) -> io::Result<bool> {
+        self.last_ram = cartridge.ram().to_vec();
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if !cartridge.load_save_data(&data) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} doesn't match the cartridge RAM size", self.path.display()),
+            ));
+        }
+        self.last_ram = cartridge.ram().to_vec();
+        Ok(true)
+    }
+
+    /// Call once per frame. Flushes once RAM writes have stopped for a
+    /// while.
+    pub fn frame(&mut self, memory: &mut Memory) -> io::Result<()> {
+        if self.ram_changed(memory) {
+            self.idle_frames = Some(0);
+            return Ok(());
+        }
+        match self.idle_frames {
+            Some(frames) if frames >= AUTOSAVE_IDLE_FRAMES => self.flush(memory),
+            Some(frames) => {
+                self.idle_frames = Some(frames + 1);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Write the save now, if there's anything unsaved.
+    pub fn flush(&mut self, memory: &mut Memory) -> io::Result<()> {
+        if self.ram_changed(memory) {
+            self.idle_frames = Some(0);
+        }
+        if self.idle_frames.is_none() {
+            return Ok(());
+        }
+        if let Some(data) = memory.save_data() {
+            self.write(&data)?;
+        }
+        self.idle_frames = None;
+        Ok(())
+    }
+
+    fn ram_changed(&mut self, memory: &mut Memory) -> bool {
+        if !memory.take_cartridge_ram_written() {
+            return false;
+        }
+        // Clock register writes don't show up in RAM
+        let cartridge = memory.cartridge();
+        if cartridge.rtc().is_none() && cartridge.ram() == &self.last_ram[..] {
+            return false;
+        }
+        self.last_ram = memory.cartridge().ram().to_vec();
+        true
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("sav.tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(data)?;
+            file.sync_all()?;
+        }
+        if !self.backed_up {
+            if self.path.exists() {
+                fs::copy(&self.path, self.path.with_extension("sav.bak"))?;
+            }
+            self.backed_up = true;
+        }
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+#[test]
+fn save_ram_autosave_and_backup() {
+    use crate::hw::controller::MBC1;
+    use crate::hw::memory::Bus;
+
+    let dir = std::env::temp_dir().join(format!("gbemu-save-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut save = SaveRam::new(Path::new("roms/game.gb"), Some(&dir));
+    assert_eq!(save.path(), dir.join("game.sav"));
+    fs::write(save.path(), vec![0x11u8; 0x2000]).unwrap();
+
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x147] = 0x03;
+    rom[0x149] = 0x02;
+    let mut memory = Memory::new(MBC1::new(rom));
+    assert!(save.load(memory.cartridge_mut()).unwrap());
+    memory.write8(0x0000, 0x0A);
+    assert_eq!(memory.read8(0xA000), 0x11);
+
+    memory.write8(0xA000, 0x22);
+    save.frame(&mut memory).unwrap();
+    for _ in 0..AUTOSAVE_IDLE_FRAMES {
+        save.frame(&mut memory).unwrap();
+    }
+    assert_eq!(fs::read(save.path()).unwrap()[0], 0x11);
+    save.frame(&mut memory).unwrap();
+    assert_eq!(fs::read(save.path()).unwrap()[0], 0x22);
+    assert!(!save.path().with_extension("sav.tmp").exists());
+
+    // The backup keeps the save from before the session
+    memory.write8(0xA000, 0x33);
+    save.flush(&mut memory).unwrap();
+    assert_eq!(fs::read(save.path()).unwrap()[0], 0x33);
+    assert_eq!(fs::read(save.path().with_extension("sav.bak")).unwrap()[0], 0x11);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,185 @@
+use std::error;
+use std::fmt;
+
+const STATE_MAGIC: &[u8; 4] = b"GBST";
+/// Bump whenever the serialized layout changes, so that states from other
+/// builds fail the version check rather than loading as garbage.
+const STATE_VERSION: u8 = 12;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(v) => write!(f, "Unsupported save state version {}", v),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Invalid(what) => write!(f, "Save state has invalid {}", what),
+        }
+    }
+}
+
+impl error::Error for StateError {}
+
+pub type StateResult<T> = Result<T, StateError>;
+
+/// Implemented by every piece of hardware that carries state between
+/// instructions. Fields are written in a fixed order with no tags, so
+/// `load_state` must read back exactly what `save_state` wrote.
+pub trait SaveState {
+    fn save_state(&self, w: &mut StateWriter);
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()>;
+}
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        let mut buf = STATE_MAGIC.to_vec();
+        buf.push(STATE_VERSION);
+        StateWriter { buf }
+    }
+
+    pub fn write_u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn write_bool(&mut self, val: bool) {
+        self.buf.push(val as u8);
+    }
+
+    pub fn write_u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// Length-prefixed byte block.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8]) -> StateResult<StateReader<'a>> {
+        if buf.len() < STATE_MAGIC.len() + 1 || &buf[..STATE_MAGIC.len()] != STATE_MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = buf[STATE_MAGIC.len()];
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        Ok(StateReader {
+            buf,
+            pos: STATE_MAGIC.len() + 1,
+        })
+    }
+
+    fn take(&mut self, len: usize) -> StateResult<&'a [u8]> {
+        let end = self.pos.checked_add(len).ok_or(StateError::Truncated)?;
+        let bytes = self.buf.get(self.pos..end).ok_or(StateError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> StateResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> StateResult<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> StateResult<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> StateResult<u32> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> StateResult<u64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn read_bytes(&mut self) -> StateResult<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    /// Read a length-prefixed block into an existing buffer, which must
+    /// already have the right size.
+    pub fn read_into(&mut self, dest: &mut [u8]) -> StateResult<()> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != dest.len() {
+            return Err(StateError::Invalid("buffer length"));
+        }
+        dest.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Serialize a whole piece of hardware into a standalone state blob.
+pub fn save_to_vec<S: SaveState>(state: &S) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    state.save_state(&mut w);
+    w.into_bytes()
+}
+
+pub fn load_from_slice<S: SaveState>(state: &mut S, bytes: &[u8]) -> StateResult<()> {
+    let mut r = StateReader::new(bytes)?;
+    state.load_state(&mut r)
+}
+
+#[test]
+fn state_round_trip() {
+    let mut w = StateWriter::new();
+    w.write_u8(0x12);
+    w.write_bool(true);
+    w.write_u16(0x3456);
+    w.write_u32(0x789A_BCDE);
+    w.write_u64(0x0123_4567_89AB_CDEF);
+    w.write_bytes(&[1, 2, 3]);
+    let bytes = w.into_bytes();
+
+    let mut r = StateReader::new(&bytes).unwrap();
+    assert_eq!(r.read_u8().unwrap(), 0x12);
+    assert!(r.read_bool().unwrap());
+    assert_eq!(r.read_u16().unwrap(), 0x3456);
+    assert_eq!(r.read_u32().unwrap(), 0x789A_BCDE);
+    assert_eq!(r.read_u64().unwrap(), 0x0123_4567_89AB_CDEF);
+    let mut buf = [0u8; 3];
+    r.read_into(&mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3]);
+    assert_eq!(r.read_u8(), Err(StateError::Truncated));
+}
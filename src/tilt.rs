@@ -0,0 +1,138 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Tilt in g to apply while a tilt key is held.
+pub const KEY_TILT: f32 = 1.0;
+
+/// Tilt in g with a stick pushed all the way over.
+pub const STICK_TILT: f32 = 1.0;
+
+/// Linux joystick API event types. Events sent on open to report the
+/// starting state have `JS_EVENT_INIT` set as well.
+const JS_EVENT_AXIS: u8 = 0x02;
+const JS_EVENT_INIT: u8 = 0x80;
+
+/// Scripted accelerometer input for headless runs. Each line of the file
+/// is `<frame> <x> <y>`, with the tilt in g holding from that frame on.
+/// Blank lines and lines starting with `#` are skipped.
+pub struct TiltScript {
+    events: Vec<(u32, f32, f32)>,
+    next: usize,
+}
+
+impl TiltScript {
+    pub fn load(path: &Path) -> Result<TiltScript, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        TiltScript::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<TiltScript, String> {
+        let mut events = Vec::new();
+        for (num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad_line = || format!("Bad tilt script line {}: {}", num + 1, line);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(bad_line());
+            }
+            let frame = fields[0].parse::<u32>().map_err(|_| bad_line())?;
+            let x = fields[1].parse::<f32>().map_err(|_| bad_line())?;
+            let y = fields[2].parse::<f32>().map_err(|_| bad_line())?;
+            events.push((frame, x, y));
+        }
+        events.sort_by_key(|event| event.0);
+        Ok(TiltScript { events, next: 0 })
+    }
+
+    /// The new tilt if one starts at or before `frame`. Call with
+    /// increasing frame numbers.
+    pub fn at_frame(&mut self, frame: u32) -> Option<(f32, f32)> {
+        let mut tilt = None;
+        while let Some(&(start, x, y)) = self.events.get(self.next) {
+            if start > frame {
+                break;
+            }
+            tilt = Some((x, y));
+            self.next += 1;
+        }
+        tilt
+    }
+}
+
+/// Analog tilt from the first two axes of a Linux joystick device such as
+/// `/dev/input/js0`, read on a thread of its own. Axis 0 tilts along x and
+/// axis 1 along y.
+pub struct JoystickTilt {
+    axes: Arc<Mutex<(f32, f32)>>,
+}
+
+impl JoystickTilt {
+    pub fn open(path: &Path) -> io::Result<JoystickTilt> {
+        let device = File::open(path)?;
+        let axes = Arc::new(Mutex::new((0.0, 0.0)));
+        let thread_axes = axes.clone();
+        thread::spawn(move || read_axes(device, &thread_axes));
+        Ok(JoystickTilt { axes })
+    }
+
+    /// The tilt the stick is at now.
+    pub fn tilt(&self) -> (f32, f32) {
+        *self.axes.lock().unwrap()
+    }
+}
+
+/// Follow the x and y axes until the device goes away. Each event is a u32
+/// timestamp, an i16 value, a type and an axis or button number.
+fn read_axes(mut device: impl Read, axes: &Mutex<(f32, f32)>) {
+    let mut event = [0u8; 8];
+    while device.read_exact(&mut event).is_ok() {
+        if event[6] & !JS_EVENT_INIT != JS_EVENT_AXIS {
+            continue;
+        }
+        let value = i16::from_le_bytes([event[4], event[5]]) as f32 / i16::MAX as f32;
+        let mut axes = axes.lock().unwrap();
+        match event[7] {
+            0 => axes.0 = value.max(-1.0) * STICK_TILT,
+            1 => axes.1 = value.max(-1.0) * STICK_TILT,
+            _ => (),
+        }
+    }
+}
+
+#[test]
+fn joystick_axes() {
+    let event = |value: i16, kind: u8, number: u8| {
+        let mut event = vec![0u8; 4];
+        event.extend_from_slice(&value.to_le_bytes());
+        event.extend_from_slice(&[kind, number]);
+        event
+    };
+    let mut events = event(i16::MAX, JS_EVENT_AXIS | JS_EVENT_INIT, 0);
+    events.extend(event(i16::MIN, JS_EVENT_AXIS, 1));
+    // A button, a third axis, then half an event
+    events.extend(event(1, 0x01, 0));
+    events.extend(event(100, JS_EVENT_AXIS, 2));
+    events.extend(&[0, 0, 0]);
+    let axes = Mutex::new((0.0, 0.0));
+    read_axes(&events[..], &axes);
+    assert_eq!(*axes.lock().unwrap(), (STICK_TILT, -STICK_TILT));
+}
+
+#[test]
+fn tilt_script_parse() {
+    let mut script = TiltScript::parse("# roll right\n10 0.5 0\n\n0 0 0\n30 -1 0.25\n").unwrap();
+    assert_eq!(script.at_frame(0), Some((0.0, 0.0)));
+    assert_eq!(script.at_frame(5), None);
+    assert_eq!(script.at_frame(10), Some((0.5, 0.0)));
+    assert_eq!(script.at_frame(40), Some((-1.0, 0.25)));
+    assert_eq!(script.at_frame(41), None);
+
+    assert!(TiltScript::parse("10 0.5").is_err());
+    assert!(TiltScript::parse("x 0 0").is_err());
+}